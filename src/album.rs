@@ -1,17 +1,88 @@
 use crate::FileType;
 use crate::IMAGE_EXTENSIONS;
 use crate::MUSIC_EXTENSIONS;
-use crate::music_tags::get_track_tags;
+use crate::music_tags::{Sidecar, get_track_tags, read_sidecar, year_from_tags};
 use anyhow::{Context, Result, bail};
 use clap::ValueEnum;
 use counter::Counter;
-use indicatif::ProgressIterator;
+use glob::Pattern;
+use indicatif::{ParallelProgressIterator, ProgressIterator};
 use pathdiff::diff_paths;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::hash_map::Entry;
 use std::fs::read_dir;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::os::windows::fs::FileTypeExt;
 use std::path::{Component, Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
+use unicode_normalization::{UnicodeNormalization, char::is_combining_mark};
+
+/// whether the file name of `cover` without extension is `stem` (ignoring case)
+fn cover_has_stem(cover: &Path, stem: &str) -> bool {
+    cover
+        .file_stem()
+        .is_some_and(|s| s.eq_ignore_ascii_case(stem))
+}
+
+/// album artist used for compilations
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// albums in a directory containing this file (or in one of its subdirectories) are compilations,
+/// the folder structure does not name their artist
+pub const COMPILATION_MARKER: &str = ".various";
+
+/// lowercases `s`, strips diacritics and replaces punctuation by single spaces
+pub fn normalize_key_part(s: &str) -> String {
+    let s: String = s
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .to_lowercase();
+    s.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// edition markers like "(Deluxe Edition)", "[Expanded]", "(2011 Remaster)" or
+/// " - Remastered 2009" that are removed from titles with `--ignore-editions`
+const EDITION_PATTERNS: &[&str] = &[
+    r"\s*[(\[][^)\]]*\b(?:deluxe|remaster(?:ed)?|expanded|edition|anniversary|reissue|bonus tracks?)\b[^)\]]*[)\]]",
+    r"\s+-\s+(?:\d{4}\s+)?(?:digital(?:ly)?\s+)?remaster(?:ed)?(?:\s+\d{4})?(?:\s+version)?\s*$",
+];
+
+static EDITION_REGEXES: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+    EDITION_PATTERNS
+        .iter()
+        .map(|p| Regex::new(&format!("(?i){p}")).expect("edition regex should be valid"))
+        .collect()
+});
+
+/// removes edition markers (see [`EDITION_PATTERNS`]) from `title`. Titles that would become
+/// empty are kept
+pub fn strip_edition(title: &str) -> String {
+    let stripped = EDITION_REGEXES.iter().fold(title.to_string(), |t, re| {
+        re.replace_all(&t, "").to_string()
+    });
+    let stripped = stripped.trim();
+    if stripped.is_empty() {
+        title.to_string()
+    } else {
+        stripped.to_string()
+    }
+}
+
+/// checks whether `artist` is one of the common spellings of "Various Artists"
+pub fn is_various_artists(artist: &str) -> bool {
+    matches!(
+        artist.trim().to_lowercase().as_str(),
+        "various artists" | "various" | "va"
+    )
+}
 
 #[derive(Clone, Debug)]
 pub struct Album {
@@ -22,6 +93,11 @@ pub struct Album {
     pub cover_files: Vec<PathBuf>,
     pub parsed_title: String,
     pub parsed_artist: String,
+    /// metadata pinned in the album directory, see [`AlbumOverride`]
+    pub album_override: Option<AlbumOverride>,
+    /// the distinct non-empty album artist tags of the tracks with the number of tracks that have
+    /// them, most common first. Only filled if the track tags were scanned
+    pub album_artist_counts: Vec<(String, usize)>,
 }
 
 impl Album {
@@ -42,6 +118,8 @@ impl Album {
             cover_files,
             parsed_title,
             parsed_artist,
+            album_override: None,
+            album_artist_counts: vec![],
         }
     }
 
@@ -56,21 +134,62 @@ impl Album {
         )
     }
 
+    /// The cover file to use for the album. Covers named like the first of `preferred_names`
+    /// (without extension) that is present are preferred, ties are broken by the largest
+    /// resolution and then by path
+    pub fn primary_cover(&self, preferred_names: &[String]) -> Option<&PathBuf> {
+        let named: Vec<&PathBuf> = preferred_names
+            .iter()
+            .map(|name| {
+                self.cover_files
+                    .iter()
+                    .filter(|cf| cover_has_stem(cf, name))
+                    .collect::<Vec<_>>()
+            })
+            .find(|covers| !covers.is_empty())
+            .unwrap_or_else(|| self.cover_files.iter().collect());
+        let pixels = |cf: &PathBuf| {
+            image::image_dimensions(cf)
+                .map(|(w, h)| u64::from(w) * u64::from(h))
+                .unwrap_or_default()
+        };
+        named
+            .into_iter()
+            .map(|cf| (pixels(cf), cf))
+            .max_by(|(pa, a), (pb, b)| pa.cmp(pb).then_with(|| b.cmp(a)))
+            .map(|(_, cf)| cf)
+    }
+
+    /// whether one of the cover files is named like one of `names` (without extension)
+    pub fn has_named_cover(&self, names: &[String]) -> bool {
+        self.cover_files
+            .iter()
+            .any(|cf| names.iter().any(|name| cover_has_stem(cf, name)))
+    }
+
+    /// total size of the track and cover files in bytes. Files that cannot be read are ignored
+    pub fn size_bytes(&self) -> u64 {
+        self.tracks
+            .iter()
+            .map(|t| self.dir_path.join(t))
+            .chain(self.cover_files.iter().cloned())
+            .filter_map(|f| std::fs::metadata(f).ok())
+            .map(|m| m.len())
+            .sum()
+    }
+
     pub fn title_without_filetype(&self) -> String {
-        if let Some(ft) = self.file_type()
-            && let Some(ft) = ft.to_possible_value()
-        {
-            let ft = ft.get_name();
-            return self
-                .title
-                .replace(&format!("[{ft}]"), "")
-                .trim()
-                .to_string();
-        }
-        self.title.clone()
+        strip_filetype_suffix(&self.title)
     }
 
-    pub fn album_dir_with_ft(&self, root_dir: PathBuf, ft: &Option<FileType>) -> PathBuf {
+    /// the directory of the album in `root_dir`, i.e. `<artist>/<title> [<ft>]`. Characters that
+    /// are not allowed under `rules` are replaced
+    pub fn album_dir_with_ft(
+        &self,
+        root_dir: PathBuf,
+        ft: &Option<FileType>,
+        rules: FileNameRules,
+    ) -> PathBuf {
         let title = if let Some(ft) = ft {
             format!(
                 "{} [{}]",
@@ -80,36 +199,137 @@ impl Album {
         } else {
             self.parsed_title.to_string()
         };
-        root_dir.join(&self.parsed_artist).join(title)
+        root_dir
+            .join(sanitize_file_name_for(&self.parsed_artist, rules))
+            .join(sanitize_file_name_for(&title, rules))
+    }
+
+    /// Key for matching albums across locations. In contrast to [`Album::key`] it ignores case,
+    /// diacritics, punctuation and whitespace differences.
+    pub fn normalized_key(&self) -> String {
+        self.key_with_title(&self.parsed_title)
+    }
+
+    /// Like [`Album::normalized_key`], but remasters and special editions have the same key as
+    /// the plain album. Used to match destination albums with `--ignore-editions`
+    pub fn edition_key(&self) -> String {
+        self.key_with_title(&strip_edition(&self.parsed_title))
+    }
+
+    fn key_with_title(&self, title: &str) -> String {
+        let artist = if is_various_artists(&self.parsed_artist) {
+            "VA"
+        } else {
+            &self.parsed_artist
+        };
+        format!(
+            "{}###{}",
+            normalize_key_part(artist),
+            normalize_key_part(title)
+        )
+    }
+
+    /// track titles without extension and leading track/disc numbers, normalized like
+    /// [`Album::normalized_key`] and sorted
+    pub fn normalized_track_titles(&self) -> Vec<String> {
+        let mut titles: Vec<String> = self
+            .tracks
+            .iter()
+            .map(|t| {
+                let stem = t.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(t);
+                normalize_key_part(
+                    stem.trim_start_matches(|c: char| c.is_ascii_digit() || " -._".contains(c)),
+                )
+            })
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    /// Why `self` and `other` are considered duplicates, if they are. Only albums with the same
+    /// track files count unless `fuzzy` is set, in which case albums with matching track titles
+    /// and, if `same_root` is set, albums of the same file type with the same key match as well.
+    pub fn duplicate_reason(
+        &self,
+        other: &Album,
+        fuzzy: bool,
+        same_root: bool,
+    ) -> Option<&'static str> {
+        if self.key() != other.key() && self.tracks == other.tracks {
+            Some("same track files")
+        } else if !fuzzy {
+            None
+        } else if same_root
+            && self.normalized_key() == other.normalized_key()
+            && self.file_type() == other.file_type()
+        {
+            Some("same key")
+        } else if self.normalized_key() != other.normalized_key()
+            && self.tracks.len() == other.tracks.len()
+            && self.normalized_track_titles() == other.normalized_track_titles()
+        {
+            Some("same track titles")
+        } else {
+            None
+        }
+    }
+
+    /// cheap fingerprint of the tracks. Albums that [`Album::duplicate_reason`] reports because of
+    /// their tracks have the same fingerprint
+    fn track_fingerprint(&self) -> (usize, u64) {
+        let mut hasher = DefaultHasher::new();
+        self.normalized_track_titles().hash(&mut hasher);
+        (self.tracks.len(), hasher.finish())
+    }
+
+    /// the `.cue` files in the album directory
+    pub fn cue_files(&self) -> Vec<PathBuf> {
+        let Ok(entries) = read_dir(&self.dir_path) else {
+            return vec![];
+        };
+        let mut cue_files: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("cue")))
+            .collect();
+        cue_files.sort();
+        cue_files
+    }
+
+    pub fn is_compilation(&self) -> bool {
+        is_various_artists(&self.artist)
     }
 
     pub fn key(&self) -> String {
         format!("{}###{}", self.parsed_artist, self.parsed_title)
     }
 
+    /// the artist determined by [`Album::finalize`] (e.g. from the album artist tags) if it
+    /// differs from the folder-parsed artist. Compilations keep their parsed artist
+    pub fn tag_artist(&self) -> Option<&str> {
+        (!self.artist.trim().is_empty()
+            && !self.is_compilation()
+            && self.artist != self.parsed_artist)
+            .then_some(self.artist.as_str())
+    }
+
+    /// uses the [`Album::tag_artist`] as parsed artist, so that destination paths and keys use
+    /// the tag spelling instead of the folder name
+    pub fn use_tag_artist(&mut self) {
+        if let Some(artist) = self.tag_artist() {
+            self.parsed_artist = artist.to_string();
+        }
+    }
+
     pub fn file_type(&self) -> Option<FileType> {
+        // extensions of the same file type like aif and aiff are not mixed file types
         let mut file_types = HashSet::new();
         self.tracks.iter().for_each(|t| {
-            if let Some((_, ft)) = t.rsplit_once('.') {
-                file_types.insert(ft.to_string());
+            if let Some((_, ext)) = t.rsplit_once('.') {
+                file_types.insert(FileType::from_extension(ext));
             }
         });
         if file_types.len() == 1 {
-            let ft = file_types.iter().next();
-            if let Some(ft) = ft {
-                FileType::value_variants()
-                    .iter()
-                    .find(|vv| {
-                        if let Some(s) = vv.to_possible_value() {
-                            s.get_name() == ft
-                        } else {
-                            false
-                        }
-                    })
-                    .cloned()
-            } else {
-                None
-            }
+            file_types.into_iter().next().flatten()
         } else {
             None
         }
@@ -150,83 +370,340 @@ impl Album {
         }
     }
 
-    fn finalize(&mut self) {
+    /// sets the parsed title without `filetype_suffix` and determines the album artist. Unless
+    /// `scan_tags` is false, the album artist is taken from the sidecar file or the track tags,
+    /// otherwise the folder-parsed artist is kept
+    fn finalize(&mut self, scan_tags: bool, filetype_suffix: &FiletypeSuffix) {
         let mut artists_counts: Counter<String> = Counter::new();
-        self.tracks.iter().for_each(|t| {
-            let track_path = self.dir_path.join(t);
-            if let Ok(tags) = get_track_tags(&track_path)
-                && let Some(artist) = tags.album_artist()
-            {
-                let artist = artist.to_string();
-                artists_counts[&artist] += 1;
+        let mut track_artists = HashSet::new();
+        let sidecar = match read_sidecar(self) {
+            Ok(sidecar) => sidecar.unwrap_or_default(),
+            Err(e) => {
+                println!("Ignoring the sidecar file in {:?}: {e:?}", self.dir_path);
+                Sidecar::default()
             }
-        });
-        self.parsed_title = self.title_without_filetype();
+        };
+        let sidecar_album = sidecar.album.filter(|_| scan_tags);
+        let from_sidecar = sidecar_album.is_some();
+        if let Some(album) = sidecar_album {
+            artists_counts[&album.artist] += 1;
+        } else if scan_tags {
+            self.tracks.iter().for_each(|t| {
+                let track_path = self.dir_path.join(t);
+                if let Ok(tags) = get_track_tags(&track_path) {
+                    if let Some(artist) = tags.album_artist() {
+                        let artist = artist.to_string();
+                        artists_counts[&artist] += 1;
+                    }
+                    if let Some(artist) = tags.artist() {
+                        track_artists.insert(artist.to_string());
+                    }
+                }
+            });
+        }
+        self.parsed_title = filetype_suffix.strip(&self.title);
 
         let mc = artists_counts.most_common();
         if !mc.is_empty() {
             self.artist = mc[0].0.to_string();
         }
+        if !from_sidecar {
+            self.album_artist_counts = mc
+                .iter()
+                .filter(|(artist, _)| !artist.trim().is_empty())
+                .cloned()
+                .collect();
+        }
+        // without album artist tags, many different track artists indicate a compilation
+        let varied_track_artists =
+            track_artists.len() >= 3 && track_artists.len() * 2 > self.tracks.len();
+        if is_various_artists(&self.artist) || (mc.is_empty() && varied_track_artists) {
+            self.artist = VARIOUS_ARTISTS.to_string();
+        }
+        if self
+            .dir_path
+            .ancestors()
+            .any(|d| d.join(COMPILATION_MARKER).exists())
+        {
+            // compilations are keyed by their title, the tracks are tagged with their own artists
+            self.parsed_artist = "VA".to_string();
+            self.artist = VARIOUS_ARTISTS.to_string();
+        }
+        // the parsed artist and title are kept so that the album still matches in other locations
+        if let Some(album_override) = sidecar.album_override {
+            if let Some(artist) = &album_override.artist {
+                self.artist = artist.clone();
+            }
+            if let Some(title) = &album_override.title {
+                self.title = title.clone();
+            }
+            self.album_override = Some(album_override);
+        }
     }
 }
 
+/// metadata pinned by the user in the `[override]` table of the sidecar file (see
+/// [`crate::music_tags::SIDECAR_FILE`]). It is used instead of the discogs lookup and the values derived from the
+/// folder structure and tags
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct AlbumOverride {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub year: Option<i32>,
+}
+
+/// the pairs of albums (as indices into `albums`, sorted) that may be duplicates according to
+/// [`Album::duplicate_reason`]. Albums are bucketed by their track fingerprint and, if `fuzzy` is
+/// set, by their normalized key, so that only albums within a bucket have to be compared
+pub fn duplicate_candidates(albums: &[&Album], fuzzy: bool) -> BTreeSet<(usize, usize)> {
+    let fingerprints: Vec<(usize, u64)> =
+        albums.par_iter().map(|a| a.track_fingerprint()).collect();
+    let mut buckets: HashMap<String, Vec<usize>> = HashMap::new();
+    albums.iter().enumerate().for_each(|(i, a)| {
+        let (len, hash) = fingerprints[i];
+        buckets
+            .entry(format!("tracks###{len}###{hash}"))
+            .or_default()
+            .push(i);
+        if fuzzy {
+            buckets
+                .entry(format!("key###{}", a.normalized_key()))
+                .or_default()
+                .push(i);
+        }
+    });
+    buckets
+        .values()
+        .flat_map(|bucket| {
+            bucket
+                .iter()
+                .enumerate()
+                .flat_map(|(n, i)| bucket[n + 1..].iter().map(|j| (*i, *j)))
+        })
+        .collect()
+}
+
+/// creates a lookup of all albums in `source_dirs`. If `modified_since` is set, only albums whose
+/// directory was modified after it are included. If several sources contain the same album in the
+/// same file type, the copy with the most tracks is used (the first one on ties) and a warning is
+/// printed. With `artist_from_tags`, the albums use their [`Album::tag_artist`]. The paths are
+/// parsed according to `layout`
 pub fn create_source_album_lookup(
     source_dirs: &[PathBuf],
+    exclude_patterns: &[String],
+    modified_since: Option<SystemTime>,
+    scan_tags: bool,
+    artist_from_tags: bool,
+    layout: &SourceLayout,
 ) -> HashMap<(String, FileType), (Album, PathBuf)> {
-    let mut album_lookup = HashMap::new();
+    let mut album_lookup: HashMap<(String, FileType), (Album, PathBuf)> = HashMap::new();
     source_dirs.iter().for_each(|sd| {
-        let albums = albums_in_dir(sd);
-        albums.into_iter().for_each(|a| {
-            if let Some(ft) = a.file_type() {
-                album_lookup.insert((a.key(), ft), (a.clone(), sd.clone()));
+        let albums =
+            albums_in_dir_modified_since(sd, exclude_patterns, modified_since, scan_tags, layout);
+        albums.into_iter().for_each(|mut a| {
+            let Some(ft) = a.file_type() else {
+                return;
+            };
+            if artist_from_tags {
+                a.use_tag_artist();
+            }
+            match album_lookup.entry((a.normalized_key(), ft)) {
+                Entry::Occupied(mut e) => {
+                    let existing = &e.get().0;
+                    println!(
+                        "WARNING: {} is present in {:?} ({} tracks) and {:?} ({} tracks)",
+                        a.overview(),
+                        existing.dir_path,
+                        existing.tracks.len(),
+                        a.dir_path,
+                        a.tracks.len()
+                    );
+                    if a.tracks.len() > existing.tracks.len() {
+                        println!("Using {:?} as it has more tracks", a.dir_path);
+                        e.insert((a, sd.clone()));
+                    }
+                }
+                Entry::Vacant(e) => {
+                    e.insert((a, sd.clone()));
+                }
             }
         })
     });
     album_lookup
 }
 
-pub fn group_files_into_albums(file_paths: &[PathBuf], root: &Path) -> Vec<Album> {
-    let mut album_lookup: HashMap<PathBuf, Album> = HashMap::new();
+/// groups the files by album directory and parsed album, so that the flat layout can keep several
+/// albums in one directory. The albums are finalized in parallel, see [`Album::finalize`] for
+/// `scan_tags`
+pub fn group_files_into_albums(
+    file_paths: &[PathBuf],
+    root: &Path,
+    scan_tags: bool,
+    layout: &SourceLayout,
+) -> Vec<Album> {
+    let mut album_lookup: HashMap<(PathBuf, String, String), Album> = HashMap::new();
     file_paths.iter().progress().for_each(|mp| {
         if let Some(album_dir) = mp.parent() {
-            let album_dir = album_dir.to_path_buf();
-            let album = path_to_details(mp.into(), root.to_path_buf());
+            let album = path_to_details_with_layout(
+                mp.into(),
+                root.to_path_buf(),
+                layout.dir_layout,
+                &layout.filetype_suffix,
+            );
             if let Ok(album) = album {
-                if let Some(a) = album_lookup.get(&album_dir) {
+                let key = (
+                    album_dir.to_path_buf(),
+                    album.artist.clone(),
+                    album.title.clone(),
+                );
+                if let Some(a) = album_lookup.get(&key) {
                     let merged = album.merge_with(a);
                     if let Ok(merged) = merged {
-                        album_lookup.insert(album_dir, merged);
+                        album_lookup.insert(key, merged);
                     } else {
                         println!("ERROR: {merged:?}");
                     }
                 } else {
-                    album_lookup.insert(album_dir, album);
+                    album_lookup.insert(key, album);
                 };
             }
         }
     });
     println!("Finalizing albums...");
-    album_lookup
-        .into_values()
+    let mut albums: Vec<Album> = album_lookup.into_values().collect();
+    if let Some(singles_dir) = &layout.singles_dir {
+        albums = group_singles(albums, singles_dir);
+    }
+    albums
+        .into_par_iter()
         .progress()
         .map(|mut a| {
-            a.finalize();
+            a.finalize(scan_tags, &layout.filetype_suffix);
             a
         })
         .collect()
 }
 
+/// how artist, album and track are arranged below the scanned directories
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, ValueEnum)]
+pub enum DirLayout {
+    /// guess the layout from the number of path components
+    #[default]
+    Auto,
+    /// `Artist - Album - Track` files, directories above them are ignored
+    Flat,
+    /// `Artist - Album/Track`, directories above the album are ignored
+    ArtistAlbum,
+    /// `Artist/Album/Track`, directories above the artist are ignored
+    ArtistAlbumTrack,
+}
+
+/// how the albums below the source directories are arranged. Destinations are parsed with the
+/// default directory layout, as morg creates them itself, but group their singles as well
+#[derive(Clone, Debug, Default)]
+pub struct SourceLayout {
+    /// the layout used to parse artist, album and track from the paths of scanned files
+    pub dir_layout: DirLayout,
+    /// the name of the directories whose single-track subdirectories are grouped into one album
+    /// per artist, see [`group_singles`]. Disabled with `None`
+    pub singles_dir: Option<String>,
+    /// the file type suffix that is removed from album titles
+    pub filetype_suffix: FiletypeSuffix,
+}
+
+/// merges the albums with a single track whose directory is in a directory named `singles_dir`
+/// into one album per artist, titled like that directory. The tracks are stored relative to it
+fn group_singles(albums: Vec<Album>, singles_dir: &str) -> Vec<Album> {
+    let mut singles: HashMap<(PathBuf, String), Album> = HashMap::new();
+    let mut res = vec![];
+    for a in albums {
+        let parent = a.dir_path.parent().filter(|p| {
+            p.file_name()
+                .is_some_and(|n| n.to_string_lossy().eq_ignore_ascii_case(singles_dir))
+        });
+        let (Some(parent), Some(dir_name), [track]) =
+            (parent, a.dir_path.file_name(), a.tracks.as_slice())
+        else {
+            res.push(a);
+            continue;
+        };
+        // forward slashes also work on windows and for adb destinations
+        let track = format!("{}/{track}", dir_name.to_string_lossy());
+        let title = parent
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let merged = singles
+            .entry((parent.to_path_buf(), a.parsed_artist.clone()))
+            .or_insert_with(|| {
+                Album::new(
+                    title.clone(),
+                    a.artist.clone(),
+                    vec![],
+                    parent.to_path_buf(),
+                    vec![],
+                    title,
+                    a.parsed_artist.clone(),
+                )
+            });
+        merged.tracks.push(track);
+        merged.cover_files.extend(a.cover_files.iter().cloned());
+    }
+    res.extend(singles.into_values().map(|mut a| {
+        a.tracks.sort();
+        a
+    }));
+    res
+}
+
+/// parses the album of the file at `path`, the layout is guessed from the number of path
+/// components
 pub fn path_to_details(path: PathBuf, root_dir: PathBuf) -> Result<Album> {
-    let rel = diff_paths(&path, &root_dir).expect("path must be a child of root_dir!");
-    let parts: Vec<String> = rel
-        .components()
-        .filter_map(|c| match c {
-            Component::Normal(name) => name.to_str().map(|s| s.to_string()),
-            _ => None,
-        })
-        .collect();
+    path_to_details_with_layout(path, root_dir, DirLayout::Auto, &DEFAULT_FILETYPE_SUFFIX)
+}
+
+/// splits the path components below the scan root into artist, album and file name
+fn split_path_parts(parts: &[String], layout: DirLayout) -> Result<(String, String, String)> {
+    let n = parts.len();
+    match layout {
+        DirLayout::Auto => {}
+        DirLayout::Flat => {
+            let Some(file) = parts.last() else {
+                bail!("Expected a file, but got {parts:?}");
+            };
+            let Some((artist, rest)) = file.split_once(" - ") else {
+                bail!("Expected 'Artist - Album - Track' file name, but got {file:?}");
+            };
+            let Some((album, _)) = rest.rsplit_once(" - ") else {
+                bail!("Expected 'Artist - Album - Track' file name, but got {file:?}");
+            };
+            return Ok((artist.to_string(), album.to_string(), file.clone()));
+        }
+        DirLayout::ArtistAlbum => {
+            if n < 2 {
+                bail!("Expected 'Artist - Album/Track', but got {parts:?}");
+            }
+            let Some((artist, album)) = parts[n - 2].split_once(" - ") else {
+                bail!(
+                    "Expected 'Artist - Album' directory, but got {:?}",
+                    parts[n - 2]
+                );
+            };
+            return Ok((artist.to_string(), album.to_string(), parts[n - 1].clone()));
+        }
+        DirLayout::ArtistAlbumTrack => {
+            if n < 3 {
+                bail!("Expected 'Artist/Album/Track', but got {parts:?}");
+            }
+            return Ok((
+                parts[n - 3].clone(),
+                parts[n - 2].clone(),
+                parts[n - 1].clone(),
+            ));
+        }
+    }
 
-    let (artist, album, file) = if parts.len() == 3 {
+    Ok(if n == 3 {
         (parts[0].clone(), parts[1].clone(), parts[2].clone())
     } else if parts.len() > 3 {
         let artist = parts[0].clone();
@@ -247,27 +724,32 @@ pub fn path_to_details(path: PathBuf, root_dir: PathBuf) -> Result<Album> {
             }
         }
     } else {
-        bail!("Could not parse details from {path:?}!");
-    };
+        bail!("Could not parse details from {parts:?}, try setting a directory layout");
+    })
+}
+
+fn path_to_details_with_layout(
+    path: PathBuf,
+    root_dir: PathBuf,
+    layout: DirLayout,
+    filetype_suffix: &FiletypeSuffix,
+) -> Result<Album> {
+    let rel = diff_paths(&path, &root_dir).expect("path must be a child of root_dir!");
+    let parts: Vec<String> = rel
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => name.to_str().map(|s| s.to_string()),
+            _ => None,
+        })
+        .collect();
+    let (artist, album, file) = split_path_parts(&parts, layout)?;
 
     let mut album = album.trim().to_string();
     let tmp = album.trim_start_matches(&format!("{artist} - "));
     if !tmp.is_empty() {
         album = tmp.trim().to_string();
     }
-    if let Some(ext) = MUSIC_EXTENSIONS
-        .iter()
-        .flat_map(|ext| {
-            [ext.to_string(), ext.to_uppercase()].into_iter().chain(
-                FileType::value_variants()
-                    .iter()
-                    .map(|ft| ft.to_possible_value().unwrap().get_name().to_string()),
-            )
-        })
-        .find(|ext| album.ends_with(&format!("[{ext}]")))
-    {
-        album = album.replace(&format!("[{ext}]"), "").trim().to_string();
-    }
+    let album = filetype_suffix.strip(&album);
 
     let cover_files = if is_image(&path) {
         vec![path.clone()]
@@ -293,42 +775,943 @@ pub fn path_to_details(path: PathBuf, root_dir: PathBuf) -> Result<Album> {
     ))
 }
 
+/// brackets that may enclose a file type suffix like " [FLAC]", " (mp3)" or " {Flac}" unless
+/// others are configured
+pub const DEFAULT_FILETYPE_BRACKETS: [(char, char); 3] = [('[', ']'), ('(', ')'), ('{', '}')];
+
+static DEFAULT_FILETYPE_SUFFIX: LazyLock<FiletypeSuffix> =
+    LazyLock::new(|| FiletypeSuffix::new(&DEFAULT_FILETYPE_BRACKETS));
+
+/// a trailing file type suffix like " [FLAC]" in album titles. The format names are compared
+/// case-insensitively
+#[derive(Clone, Debug)]
+pub struct FiletypeSuffix(Regex);
+
+impl Default for FiletypeSuffix {
+    fn default() -> Self {
+        DEFAULT_FILETYPE_SUFFIX.clone()
+    }
+}
+
+impl FiletypeSuffix {
+    /// a suffix enclosed in one of the `brackets`
+    pub fn new(brackets: &[(char, char)]) -> Self {
+        let names: Vec<String> = MUSIC_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .chain(
+                FileType::value_variants()
+                    .iter()
+                    .filter_map(|ft| ft.to_possible_value())
+                    .map(|ft| ft.get_name().to_string()),
+            )
+            .map(|name| regex::escape(&name))
+            .collect();
+        let names = names.join("|");
+        let alternatives: Vec<String> = brackets
+            .iter()
+            .map(|(open, close)| {
+                format!(
+                    r"{}\s*(?:{names})\s*{}",
+                    regex::escape(&open.to_string()),
+                    regex::escape(&close.to_string())
+                )
+            })
+            .collect();
+        let re = Regex::new(&format!(r"(?i)\s*(?:{})\s*$", alternatives.join("|")))
+            .expect("file type suffix regex should be valid");
+        FiletypeSuffix(re)
+    }
+
+    /// removes the suffix from `title`. Titles that would become empty are kept
+    pub fn strip(&self, title: &str) -> String {
+        let stripped = self.0.replace(title, "").trim().to_string();
+        if stripped.is_empty() {
+            title.trim().to_string()
+        } else {
+            stripped
+        }
+    }
+}
+
+/// removes a trailing file type suffix like " [FLAC]" from `title`, see
+/// [`DEFAULT_FILETYPE_BRACKETS`] for the recognized brackets
+pub fn strip_filetype_suffix(title: &str) -> String {
+    DEFAULT_FILETYPE_SUFFIX.strip(title)
+}
+
+/// replaces characters that are not allowed in file names (on any common file system)
+pub fn sanitize_file_name(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // windows does not allow trailing dots and spaces
+    sanitized.trim_end_matches(['.', ' ']).to_string()
+}
+
+static DISC_SUFFIX_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)[\s\-_]*[(\[]?\b(?:cd|disc|disk)\s*(\d{1,2})[)\]]?(\s*\[[^\]]*\])?\s*$")
+        .expect("disc suffix regex should be valid")
+});
+
+/// splits a disc suffix like " - CD1", " (Disc 2)" or " [disk 3]" off `name`. A trailing file type
+/// tag like " [FLAC]" is kept. Returns the name without the suffix and the disc number
+pub fn disc_suffix(name: &str) -> Option<(String, u16)> {
+    let caps = DISC_SUFFIX_RE.captures(name)?;
+    let disc = caps[1].parse().ok()?;
+    let start = caps.get(0)?.start();
+    let ft = caps.get(2).map(|m| m.as_str()).unwrap_or_default();
+    let base = format!("{}{ft}", &name[..start]).trim().to_string();
+    (!base.is_empty()).then_some((base, disc))
+}
+
+/// which characters are allowed in file names of a destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize, ValueEnum)]
+pub enum FileNameRules {
+    /// FAT32, exFAT and NTFS. Android's shared storage has the same restrictions
+    #[default]
+    Fat32,
+    /// ext4 and other unix file systems, only `/` is replaced
+    Ext4,
+}
+
+/// like [`sanitize_file_name`], but only replaces the characters that are not allowed under
+/// `rules`. Sanitized names still have the same [`Album::normalized_key`], so destination albums
+/// are matched to their source albums as before
+pub fn sanitize_file_name_for(name: &str, rules: FileNameRules) -> String {
+    match rules {
+        FileNameRules::Fat32 => sanitize_file_name(name),
+        FileNameRules::Ext4 => name.replace(['/', '\0'], "_"),
+    }
+}
+
+/// a part of a directory name in a layout template
+enum LayoutToken<'a> {
+    Text(&'a str),
+    /// the name of a placeholder like `artist` in `{artist}`
+    Placeholder(&'a str),
+}
+
+/// splits a directory name of a layout template into text and placeholders
+fn layout_tokens(component: &str) -> Vec<LayoutToken<'_>> {
+    let mut tokens = vec![];
+    let mut rest = component;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        if start > 0 {
+            tokens.push(LayoutToken::Text(&rest[..start]));
+        }
+        tokens.push(LayoutToken::Placeholder(&rest[start + 1..start + len]));
+        rest = &rest[start + len + 1..];
+    }
+    if !rest.is_empty() {
+        tokens.push(LayoutToken::Text(rest));
+    }
+    tokens
+}
+
+/// the directory of `album` relative to the destination root according to `template`, e.g.
+/// `{artist}/{year} - {album}`. Directory names are separated by `/`, the placeholders
+/// `{artist}`, `{album}`, `{year}` and `{ft}` are replaced. If the year is unknown, `{year}` and
+/// the text directly after it are left out
+pub fn destination_path(
+    template: &str,
+    album: &Album,
+    ft: Option<&FileType>,
+    rules: FileNameRules,
+) -> PathBuf {
+    let year = album
+        .album_override
+        .as_ref()
+        .and_then(|o| o.year)
+        .or_else(|| year_from_tags(album));
+    template
+        .split('/')
+        .map(|component| {
+            let mut name = String::new();
+            let mut skip_text = false;
+            layout_tokens(component)
+                .iter()
+                .for_each(|token| match token {
+                    LayoutToken::Text(text) => {
+                        if !std::mem::take(&mut skip_text) {
+                            name.push_str(text);
+                        }
+                    }
+                    LayoutToken::Placeholder(placeholder) => {
+                        skip_text = false;
+                        match *placeholder {
+                            "artist" => name.push_str(&album.parsed_artist),
+                            "album" => name.push_str(&album.parsed_title),
+                            "year" => match year {
+                                Some(year) => name.push_str(&year.to_string()),
+                                None => skip_text = true,
+                            },
+                            "ft" => {
+                                if let Some(ft) = ft.and_then(|ft| ft.to_possible_value()) {
+                                    name.push_str(ft.get_name());
+                                }
+                            }
+                            other => name.push_str(&format!("{{{other}}}")),
+                        }
+                    }
+                });
+            sanitize_file_name_for(name.trim(), rules)
+        })
+        .collect()
+}
+
+/// sets the parsed artist and title of the `albums` (destination albums in `root`) from their
+/// directories according to `template`, see [`destination_path`]. Albums whose directory does not
+/// match the template are kept as they are
+pub fn apply_layout(template: &str, albums: &mut [Album], root: &Path) {
+    let mut pattern = String::new();
+    template.split('/').enumerate().for_each(|(i, component)| {
+        if i > 0 {
+            pattern.push('/');
+        }
+        let tokens = layout_tokens(component);
+        let mut tokens = tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            match token {
+                LayoutToken::Text(text) => pattern.push_str(&regex::escape(text)),
+                LayoutToken::Placeholder("artist") => pattern.push_str("(?P<artist>[^/]+?)"),
+                LayoutToken::Placeholder("album") => pattern.push_str("(?P<album>[^/]+?)"),
+                LayoutToken::Placeholder("year") => {
+                    // the text after the year is left out together with it
+                    let text = match tokens.peek() {
+                        Some(LayoutToken::Text(text)) => {
+                            tokens.next();
+                            regex::escape(text)
+                        }
+                        _ => String::new(),
+                    };
+                    pattern.push_str(&format!(r"(?:\d{{4}}{text})?"));
+                }
+                LayoutToken::Placeholder("ft") => pattern.push_str("[^/]*?"),
+                LayoutToken::Placeholder(other) => {
+                    pattern.push_str(&regex::escape(&format!("{{{other}}}")))
+                }
+            }
+        }
+    });
+    let Ok(re) = Regex::new(&format!("^{pattern}$")) else {
+        println!("Invalid layout template {template:?}");
+        return;
+    };
+    albums
+        .iter_mut()
+        .for_each(|album| apply_layout_regex(&re, album, root));
+}
+
+/// see [`apply_layout`], `re` is the regex built from the template
+fn apply_layout_regex(re: &Regex, album: &mut Album, root: &Path) {
+    let Some(rel) = diff_paths(&album.dir_path, root) else {
+        return;
+    };
+    let rel: Vec<String> = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    let rel = rel.join("/");
+    let Some(caps) = re.captures(&rel) else {
+        return;
+    };
+    if let Some(artist) = caps.name("artist") {
+        album.parsed_artist = artist.as_str().to_string();
+    }
+    if let Some(title) = caps.name("album") {
+        album.parsed_title = title.as_str().to_string();
+        if album
+            .album_override
+            .as_ref()
+            .is_none_or(|o| o.title.is_none())
+        {
+            album.title = title.as_str().to_string();
+        }
+    }
+}
+
+/// covers smaller than this (in pixels) in either dimension are reported
+pub const MIN_COVER_SIZE: u32 = 500;
+/// covers whose longer side exceeds their shorter side by more than this factor are reported
+pub const MAX_COVER_ASPECT_RATIO: f64 = 1.1;
+
+/// problems with a cover of the given dimensions, see [`MIN_COVER_SIZE`] and
+/// [`MAX_COVER_ASPECT_RATIO`]
+fn cover_dimension_problems(width: u32, height: u32) -> Vec<String> {
+    let mut problems = vec![];
+    if width < MIN_COVER_SIZE || height < MIN_COVER_SIZE {
+        problems.push(format!(
+            "{width}x{height} is smaller than {MIN_COVER_SIZE}x{MIN_COVER_SIZE}"
+        ));
+    }
+    let (short, long) = (width.min(height), width.max(height));
+    if short == 0 || f64::from(long) / f64::from(short) > MAX_COVER_ASPECT_RATIO {
+        problems.push(format!("{width}x{height} is not roughly square"));
+    }
+    problems
+}
+
+/// reads the dimensions of the cover image `cover` and returns its problems, e.g. a too low
+/// resolution
+pub fn cover_problems(cover: &Path) -> Result<Vec<String>> {
+    let (width, height) = image::image_dimensions(cover)
+        .context(format!("Failed to read the dimensions of {cover:?}"))?;
+    Ok(cover_dimension_problems(width, height))
+}
+
 fn is_image(file: &Path) -> bool {
     let Some(ext) = file.extension() else {
         return false;
     };
-    IMAGE_EXTENSIONS.iter().any(|e| ext == *e)
+    IMAGE_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e))
 }
 
 fn is_music(file: &Path) -> bool {
     let Some(ext) = file.extension() else {
         return false;
     };
-    MUSIC_EXTENSIONS.iter().any(|e| ext == *e)
+    MUSIC_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e))
+}
+
+/// the compiled exclude patterns
+#[derive(Default)]
+struct Excludes {
+    patterns: Vec<Pattern>,
+    /// the directories whose whole content is excluded by a pattern like `Live/**` or `Live/*`
+    dirs: Vec<Pattern>,
+}
+
+/// compiles the exclude patterns, skipping (and reporting) invalid ones
+fn compile_exclude_patterns(exclude_patterns: &[String]) -> Excludes {
+    let patterns: Vec<Pattern> = exclude_patterns
+        .iter()
+        .filter_map(|p| match Pattern::new(p) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                println!("Ignoring invalid exclude pattern {p:?}: {e}");
+                None
+            }
+        })
+        .collect();
+    let dirs = patterns
+        .iter()
+        .filter_map(|p| {
+            let p = p.as_str();
+            let dir = p.strip_suffix("/**").or_else(|| p.strip_suffix("/*"))?;
+            Pattern::new(dir).ok()
+        })
+        .collect();
+    Excludes { patterns, dirs }
 }
 
-fn files_in_dir(root: &Path) -> Vec<PathBuf> {
+/// a path is excluded if its path relative to `root` or its file name matches any pattern. A
+/// directory is also excluded if a pattern matches everything below it, so it is not scanned
+fn is_excluded(path: &Path, root: &Path, excludes: &Excludes, is_dir: bool) -> bool {
+    let rel = diff_paths(path, root).unwrap_or_else(|| path.to_path_buf());
+    let name = path.file_name().map(PathBuf::from).unwrap_or_default();
+    excludes
+        .patterns
+        .iter()
+        .any(|p| p.matches_path(&rel) || p.matches_path(&name))
+        || (is_dir && excludes.dirs.iter().any(|p| p.matches_path(&rel)))
+}
+
+/// directories that could not be read while scanning, see [`take_skipped_dirs`]
+static SKIPPED_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// the directories that were skipped because they could not be read since the last call
+pub fn take_skipped_dirs() -> Vec<PathBuf> {
+    std::mem::take(&mut *SKIPPED_DIRS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Collects the files in `dir` recursively. If `modified_since` is set, the files of directories
+/// that have not been modified since then are skipped (their subdirectories are still visited).
+/// Directories that cannot be read (e.g. `System Volume Information`) are skipped with a warning.
+/// Symlinks are only followed if `follow_links` is set
+fn files_in_dir(
+    root: &Path,
+    dir: &Path,
+    excludes: &Excludes,
+    modified_since: Option<SystemTime>,
+    follow_links: bool,
+) -> Vec<PathBuf> {
     let mut res = vec![];
-    read_dir(root)
-        .unwrap_or_else(|_| panic!("root directory {root:?} does not exist!"))
+    let include_files = match modified_since {
+        Some(since) => std::fs::metadata(dir)
+            .and_then(|m| m.modified())
+            .map(|modified| modified >= since)
+            .unwrap_or(true),
+        None => true,
+    };
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("WARNING: skipping {dir:?}: {e}");
+            SKIPPED_DIRS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(dir.to_path_buf());
+            return res;
+        }
+    };
+    entries
+        .filter_map(|de| {
+            de.map_err(|e| println!("WARNING: skipping an entry of {dir:?}: {e}"))
+                .ok()
+        })
         .for_each(|de| {
-            let de = de.unwrap();
-            if let Ok(ft) = de.file_type() {
-                if ft.is_file() || ft.is_symlink_file() {
+            let Ok(ft) = de.file_type() else {
+                return;
+            };
+            if ft.is_symlink() && !follow_links {
+                return;
+            }
+            let is_dir = ft.is_dir() || ft.is_symlink_dir();
+            if is_excluded(&de.path(), root, excludes, is_dir) {
+                return;
+            }
+            if ft.is_file() || ft.is_symlink_file() {
+                if include_files {
                     res.push(de.path().to_path_buf());
-                } else if ft.is_dir() || ft.is_symlink_dir() {
-                    let mut rec = files_in_dir(&de.path());
-
-                    res.append(&mut rec);
                 }
+            } else if is_dir {
+                let mut rec =
+                    files_in_dir(root, &de.path(), excludes, modified_since, follow_links);
+
+                res.append(&mut rec);
             }
         });
     res
 }
 
-pub fn albums_in_dir(root: &Path) -> Vec<Album> {
-    let files = files_in_dir(root);
+/// finds the `.m3u`/`.m3u8` playlists in `root` (recursively)
+pub fn playlists_in_dir(root: &Path, exclude_patterns: &[String]) -> Vec<PathBuf> {
+    let excludes = compile_exclude_patterns(exclude_patterns);
+    files_in_dir(root, root, &excludes, None, true)
+        .into_iter()
+        .filter(|f| {
+            f.extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("m3u") || e.eq_ignore_ascii_case("m3u8"))
+        })
+        .collect()
+}
+
+/// finds the files in `root` (recursively) that are not excluded by `exclude_patterns`. Symlinks
+/// are skipped, so all returned files are inside `root`
+pub fn own_files_in_dir(root: &Path, exclude_patterns: &[String]) -> Vec<PathBuf> {
+    let excludes = compile_exclude_patterns(exclude_patterns);
+    files_in_dir(root, root, &excludes, None, false)
+}
+
+/// loads the albums in `root`. Files and directories matching one of the `exclude_patterns` (glob
+/// patterns matched against the path relative to `root`) are skipped. The paths are parsed
+/// according to `layout`
+pub fn albums_in_dir(
+    root: &Path,
+    exclude_patterns: &[String],
+    layout: &SourceLayout,
+) -> Vec<Album> {
+    albums_in_dir_modified_since(root, exclude_patterns, None, true, layout)
+}
+
+/// like [`albums_in_dir`], but only loads albums whose directory was modified after
+/// `modified_since`. Track tags are only read if `scan_tags` is set
+pub fn albums_in_dir_modified_since(
+    root: &Path,
+    exclude_patterns: &[String],
+    modified_since: Option<SystemTime>,
+    scan_tags: bool,
+    layout: &SourceLayout,
+) -> Vec<Album> {
+    let excludes = compile_exclude_patterns(exclude_patterns);
+    let files = files_in_dir(root, root, &excludes, modified_since, true);
     println!("Got albums in directory {root:?}");
     println!("Grouping files into albums...");
-    group_files_into_albums(&files, root)
+    group_files_into_albums(&files, root, scan_tags, layout)
+}
+
+#[test]
+fn test_normalized_key() {
+    let album = |artist: &str, title: &str| Album::test(artist, title, "", &[]);
+    assert_eq!(
+        album("Beyoncé", "Lemonade").normalized_key(),
+        album("Beyonce", "Lemonade").normalized_key()
+    );
+    assert_eq!(
+        album("Motörhead", "Ace of Spades").normalized_key(),
+        album("MOTORHEAD", "Ace  of Spades").normalized_key()
+    );
+    assert_eq!(
+        album("Various Artists", "Now, Vol. 1").normalized_key(),
+        album("Various Artists", "Now Vol 1").normalized_key()
+    );
+    assert_eq!(
+        album("AC/DC", "Who Made Who").normalized_key(),
+        "ac dc###who made who"
+    );
+    assert_ne!(
+        album("Poppy", "Choke").normalized_key(),
+        album("Poppy", "Negative Spaces").normalized_key()
+    );
+}
+
+#[test]
+fn test_file_type_uppercase_extensions() {
+    let album = |tracks: &[&str]| Album::test("Poppy", "Choke", "", tracks);
+    assert_eq!(
+        album(&["01 Song.FLAC", "02 Song.flac", "03 Song.Flac"]).file_type(),
+        Some(FileType::Flac)
+    );
+    assert_eq!(album(&["01 Song.Mp3"]).file_type(), Some(FileType::MP3));
+    assert_eq!(album(&["01 Song.MP3", "02 Song.flac"]).file_type(), None);
+    assert_eq!(
+        album(&["01 Song.aif", "02 Song.AIFF"]).file_type(),
+        Some(FileType::Aiff)
+    );
+    assert!(is_music(Path::new("01 Song.AIF")));
+    assert!(is_music(Path::new("01 Song.FLAC")));
+    assert!(is_image(Path::new("cover.JPG")));
+}
+
+#[test]
+fn test_primary_cover() {
+    let album = |cover_files: &[&str]| {
+        let mut album = Album::test("Poppy", "Choke", "/music/Poppy/Choke", &[]);
+        album.cover_files = cover_files
+            .iter()
+            .map(|cf| album.dir_path.join(cf))
+            .collect();
+        album
+    };
+    let names = ["cover".to_string(), "folder".to_string()];
+    let file_name = |a: &Album| {
+        a.primary_cover(&names)
+            .map(|cf| cf.file_name().unwrap().to_str().unwrap().to_string())
+    };
+    assert_eq!(
+        file_name(&album(&["back.jpg", "folder.jpg", "Cover.png"])),
+        Some("Cover.png".to_string())
+    );
+    assert_eq!(
+        file_name(&album(&["back.jpg", "folder.jpg"])),
+        Some("folder.jpg".to_string())
+    );
+    assert_eq!(
+        file_name(&album(&["front.jpg", "back.jpg"])),
+        Some("back.jpg".to_string())
+    );
+    assert_eq!(file_name(&album(&[])), None);
+    let front = album(&["cover.jpg", "front.png"]);
+    assert_eq!(
+        front.primary_cover(&["front".to_string()]),
+        Some(&front.dir_path.join("front.png"))
+    );
+    assert!(front.has_named_cover(&["front".to_string()]));
+    assert!(!front.has_named_cover(&["folder".to_string()]));
+}
+
+#[test]
+fn test_strip_filetype_suffix() {
+    assert_eq!(strip_filetype_suffix("Album (FLAC)"), "Album");
+    assert_eq!(strip_filetype_suffix("Album [Flac]"), "Album");
+    assert_eq!(strip_filetype_suffix("Album {mp3}"), "Album");
+    assert_eq!(strip_filetype_suffix("Album [ MP3 ]"), "Album");
+    assert_eq!(strip_filetype_suffix("Album (Live)"), "Album (Live)");
+    assert_eq!(strip_filetype_suffix("Album [flac)"), "Album [flac)");
+    assert_eq!(strip_filetype_suffix("[flac]"), "[flac]");
+    let square_only = FiletypeSuffix::new(&[('[', ']')]);
+    assert_eq!(square_only.strip("Album [FLAC]"), "Album");
+    assert_eq!(square_only.strip("Album (FLAC)"), "Album (FLAC)");
+    let album = path_to_details(
+        PathBuf::from("/music/Poppy/Choke (FLAC)/01 Choke.flac"),
+        PathBuf::from("/music"),
+    )
+    .unwrap();
+    assert_eq!(album.parsed_title, "Choke");
+}
+
+#[test]
+fn test_path_to_details_with_layout() {
+    let root = PathBuf::from("/music");
+    let album = path_to_details_with_layout(
+        root.join("Poppy - Choke - 01 Choke.flac"),
+        root.clone(),
+        DirLayout::Flat,
+        &FiletypeSuffix::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        (album.artist.as_str(), album.title.as_str()),
+        ("Poppy", "Choke")
+    );
+    assert_eq!(
+        album.tracks,
+        vec!["Poppy - Choke - 01 Choke.flac".to_string()]
+    );
+    assert!(
+        path_to_details_with_layout(
+            root.join("01 Choke.flac"),
+            root.clone(),
+            DirLayout::Auto,
+            &FiletypeSuffix::default(),
+        )
+        .is_err()
+    );
+    // directories above the artist are ignored instead of being joined into the album
+    let path = root.join("Rock/Metal/Poppy/Choke/01 Choke.flac");
+    let album = path_to_details_with_layout(
+        path.clone(),
+        root.clone(),
+        DirLayout::ArtistAlbumTrack,
+        &FiletypeSuffix::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        (album.artist.as_str(), album.title.as_str()),
+        ("Poppy", "Choke")
+    );
+    let album = path_to_details_with_layout(
+        path,
+        root.clone(),
+        DirLayout::Auto,
+        &FiletypeSuffix::default(),
+    )
+    .unwrap();
+    assert_eq!(album.artist, "Rock");
+    let album = path_to_details_with_layout(
+        root.join("Various/Poppy - Choke/01 Choke.flac"),
+        root,
+        DirLayout::ArtistAlbum,
+        &FiletypeSuffix::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        (album.artist.as_str(), album.title.as_str()),
+        ("Poppy", "Choke")
+    );
+}
+
+#[test]
+fn test_group_singles() {
+    let album = |dir: &str, tracks: &[&str]| Album::test("Poppy", "Singles - Choke", dir, tracks);
+    let albums = vec![
+        album("/music/Poppy/Singles/Choke", &["01 Choke.flac"]),
+        album("/music/Poppy/Singles/Concrete", &["01 Concrete.flac"]),
+        album("/music/Poppy/Singles/EP", &["01 A.flac", "02 B.flac"]),
+        album("/music/Poppy/Choke", &["01 Choke.flac"]),
+    ];
+    let mut grouped = group_singles(albums, "singles");
+    grouped.sort_by_key(|a| a.dir_path.clone());
+    assert_eq!(grouped.len(), 3);
+    let singles = &grouped[1];
+    assert_eq!(singles.dir_path, PathBuf::from("/music/Poppy/Singles"));
+    assert_eq!(singles.title, "Singles");
+    assert_eq!(singles.parsed_artist, "Poppy");
+    assert_eq!(
+        singles.tracks,
+        vec![
+            "Choke/01 Choke.flac".to_string(),
+            "Concrete/01 Concrete.flac".to_string()
+        ]
+    );
+    assert_eq!(grouped[2].tracks.len(), 2);
+}
+
+#[test]
+fn test_sanitize_file_name() {
+    assert_eq!(sanitize_file_name("AC/DC"), "AC_DC");
+    assert_eq!(sanitize_file_name("What?: A <Song>*"), "What__ A _Song__");
+    assert_eq!(sanitize_file_name("Ends with dots..."), "Ends with dots");
+    assert_eq!(sanitize_file_name("Tab\there"), "Tab_here");
+    assert_eq!(sanitize_file_name("Beyoncé"), "Beyoncé");
+    assert_eq!(
+        sanitize_file_name_for("What?: A/B", FileNameRules::Ext4),
+        "What?: A_B"
+    );
+    let album = |artist: &str, title: &str| Album::test(artist, title, "", &[]);
+    let dst = album("Weird Al", "What?: A*Song");
+    let sanitized = dst.album_dir_with_ft(PathBuf::new(), &None, FileNameRules::Fat32);
+    assert_eq!(sanitized, PathBuf::from("Weird Al").join("What__ A_Song"));
+    // sanitized destination folders still match their source album
+    let parsed = album(
+        "Weird Al",
+        &sanitized.file_name().unwrap().to_string_lossy(),
+    );
+    assert_eq!(parsed.normalized_key(), dst.normalized_key());
+}
+
+#[test]
+fn test_disc_suffix() {
+    assert_eq!(disc_suffix("Choke - CD1"), Some(("Choke".to_string(), 1)));
+    assert_eq!(
+        disc_suffix("Choke (Disc 2)"),
+        Some(("Choke".to_string(), 2))
+    );
+    assert_eq!(
+        disc_suffix("Poppy - Choke [disk 3] [FLAC]"),
+        Some(("Poppy - Choke [FLAC]".to_string(), 3))
+    );
+    assert_eq!(disc_suffix("Choke CD 10"), Some(("Choke".to_string(), 10)));
+    assert_eq!(disc_suffix("Disco 2000"), None);
+    assert_eq!(disc_suffix("Choke"), None);
+    assert_eq!(disc_suffix("CD1"), None);
+}
+
+#[test]
+fn test_album_override() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("override_test");
+    let dir = root.join("Poppy").join("Choke");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("01 Choke.mp3"), "music").unwrap();
+    std::fs::write(
+        dir.join(crate::music_tags::SIDECAR_FILE),
+        "[override]\ntitle = \"Choke (EP)\"\nyear = 2019\n",
+    )
+    .unwrap();
+    let albums = albums_in_dir(&root, &[], &SourceLayout::default());
+    assert_eq!(albums.len(), 1);
+    let album = &albums[0];
+    assert_eq!(album.title, "Choke (EP)");
+    assert_eq!(album.artist, "Poppy");
+    // the key still matches the album in other locations
+    assert_eq!(album.parsed_title, "Choke");
+    assert_eq!(
+        album.album_override,
+        Some(AlbumOverride {
+            artist: None,
+            title: Some("Choke (EP)".to_string()),
+            year: Some(2019),
+        })
+    );
+}
+
+#[test]
+fn test_duplicate_candidates() {
+    let album = |title: &str, tracks: &[&str]| Album::test("Poppy", title, title, tracks);
+    let albums = [
+        album("Choke", &["01 Choke.mp3", "02 Fill The Crown.mp3"]),
+        album("I Disagree", &["01 Concrete.mp3"]),
+        album("Choke EP", &["01 Choke.flac", "02 Fill the crown.flac"]),
+        album("choke", &["01 Other.mp3"]),
+    ];
+    let albums: Vec<&Album> = albums.iter().collect();
+    assert_eq!(
+        duplicate_candidates(&albums, false),
+        BTreeSet::from([(0, 2)])
+    );
+    assert_eq!(
+        duplicate_candidates(&albums, true),
+        BTreeSet::from([(0, 2), (0, 3)])
+    );
+}
+
+#[test]
+fn test_duplicate_reason() {
+    let album = |title: &str, tracks: &[&str]| {
+        Album::test(
+            "Poppy",
+            title,
+            PathBuf::from("/music/Poppy").join(title),
+            tracks,
+        )
+    };
+    let a1 = album("Choke", &["01 Choke.mp3", "02 Burn.mp3"]);
+    let a2 = album("Choke (EP)", &["1 - choke.mp3", "2 - Burn.mp3"]);
+    assert_eq!(a1.duplicate_reason(&a2, false, true), None);
+    assert_eq!(
+        a1.duplicate_reason(&a2, true, true),
+        Some("same track titles")
+    );
+    let a3 = album("choke", &["Choke.mp3"]);
+    assert_eq!(a1.duplicate_reason(&a3, true, true), Some("same key"));
+    assert_eq!(a1.duplicate_reason(&a3, true, false), None);
+    let a4 = album("Choke [Deluxe]", &["01 Choke.mp3", "02 Burn.mp3"]);
+    assert_eq!(
+        a1.duplicate_reason(&a4, false, false),
+        Some("same track files")
+    );
+}
+
+#[test]
+fn test_compilation_marker() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("various_test");
+    let dir = root.join("Compilations").join("Now 80s");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(root.join("Compilations").join(COMPILATION_MARKER), "").unwrap();
+    std::fs::write(dir.join("01 Take On Me.mp3"), "music").unwrap();
+    let albums = albums_in_dir(&root, &[], &SourceLayout::default());
+    assert_eq!(albums.len(), 1);
+    let album = &albums[0];
+    assert_eq!(album.key(), "VA###Now 80s");
+    assert!(album.is_compilation());
+    let mut other = album.clone();
+    other.parsed_artist = VARIOUS_ARTISTS.to_string();
+    assert_eq!(album.normalized_key(), other.normalized_key());
+}
+
+#[test]
+fn test_primary_cover_preferred_names() {
+    let dir = PathBuf::from("/music/Poppy/Choke");
+    let mut album = Album::test("Poppy", "Choke", dir.clone(), &[]);
+    album.cover_files = ["front.png", "folder.jpg", "Cover.jpg", "cover.png"]
+        .iter()
+        .map(|f| dir.join(f))
+        .collect();
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        album.primary_cover(&names(&["cover", "folder"])),
+        Some(&dir.join("Cover.jpg"))
+    );
+    assert_eq!(
+        album.primary_cover(&names(&["back", "folder"])),
+        Some(&dir.join("folder.jpg"))
+    );
+    assert_eq!(
+        album.primary_cover(&names(&["back"])),
+        Some(&dir.join("Cover.jpg"))
+    );
+}
+
+#[test]
+fn test_source_album_collisions() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("collision_test");
+    let (src1, src2) = (root.join("src1"), root.join("src2"));
+    for (dir, tracks) in [
+        (src1.join("Poppy/Choke"), vec!["01 Choke.flac"]),
+        (
+            src1.join("Poppy/I Disagree"),
+            vec!["01 Concrete.flac", "02 I Disagree.flac"],
+        ),
+        (
+            src2.join("Poppy/Choke"),
+            vec!["01 Choke.flac", "02 Fill The Crown.flac"],
+        ),
+        (src2.join("Poppy/I Disagree"), vec!["01 Concrete.flac"]),
+    ] {
+        std::fs::create_dir_all(&dir).unwrap();
+        tracks
+            .iter()
+            .for_each(|t| std::fs::write(dir.join(t), "").unwrap());
+    }
+    let lookup = create_source_album_lookup(
+        &[src1.clone(), src2.clone()],
+        &[],
+        None,
+        false,
+        false,
+        &SourceLayout::default(),
+    );
+    assert_eq!(lookup.len(), 2);
+    let source = |title: &str| {
+        let key = format!(
+            "{}###{}",
+            normalize_key_part("Poppy"),
+            normalize_key_part(title)
+        );
+        lookup[&(key, FileType::Flac)].1.clone()
+    };
+    assert_eq!(source("Choke"), src2);
+    assert_eq!(source("I Disagree"), src1);
+}
+
+#[test]
+fn test_cover_dimension_problems() {
+    assert!(cover_dimension_problems(600, 600).is_empty());
+    assert!(cover_dimension_problems(1200, 1150).is_empty());
+    assert_eq!(cover_dimension_problems(300, 300).len(), 1);
+    assert_eq!(cover_dimension_problems(1000, 600).len(), 1);
+    assert_eq!(cover_dimension_problems(400, 200).len(), 2);
+}
+
+#[test]
+fn test_skip_unreadable_dirs() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("skip_test");
+    let missing = root.join("System Volume Information");
+    assert!(files_in_dir(&root, &missing, &Excludes::default(), None, true).is_empty());
+    assert!(take_skipped_dirs().contains(&missing));
+}
+
+#[test]
+fn test_excluded_dirs_not_scanned() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("exclude_dirs_test");
+    std::fs::create_dir_all(root.join("Rock/Live/Show")).unwrap();
+    std::fs::write(root.join("Rock/Live/Show/01 - Intro.mp3"), b"").unwrap();
+    std::fs::write(root.join("Rock/01 - Song.mp3"), b"").unwrap();
+    let excludes = compile_exclude_patterns(&["**/Live/**".to_string()]);
+    assert!(is_excluded(&root.join("Rock/Live"), &root, &excludes, true));
+    assert!(!is_excluded(&root.join("Rock"), &root, &excludes, true));
+    // a file named like the directory is not excluded by a pattern for its content
+    assert!(!is_excluded(
+        &root.join("Rock/Live"),
+        &root,
+        &excludes,
+        false
+    ));
+    let files = files_in_dir(&root, &root, &excludes, None, true);
+    assert_eq!(files, vec![root.join("Rock/01 - Song.mp3")]);
+}
+
+#[test]
+fn test_layout_template() {
+    let template = "{artist}/{year} - {album}";
+    let mut album = Album::test("Poppy", "Choke", "/src/Poppy/Choke [FLAC]", &[]);
+    album.album_override = Some(AlbumOverride {
+        year: Some(2019),
+        ..Default::default()
+    });
+    let path = destination_path(template, &album, None, FileNameRules::Fat32);
+    assert_eq!(path, PathBuf::from("Poppy").join("2019 - Choke"));
+    assert_eq!(
+        destination_path(
+            "{artist}/{album} [{ft}]",
+            &album,
+            Some(&FileType::MP3),
+            FileNameRules::Fat32
+        ),
+        PathBuf::from("Poppy").join("Choke [mp3]")
+    );
+    album.album_override = None;
+    let path = destination_path(template, &album, None, FileNameRules::Fat32);
+    assert_eq!(path, PathBuf::from("Poppy").join("Choke"));
+
+    // destination albums are matched by the artist and title in the template
+    let root = PathBuf::from("/dst");
+    let dst_album = |dir: &str| {
+        let mut a = album.clone();
+        a.dir_path = root.join(dir);
+        a.parsed_title = "wrong".to_string();
+        apply_layout(template, std::slice::from_mut(&mut a), &root);
+        a.key()
+    };
+    assert_eq!(dst_album("Poppy/2019 - Choke"), album.key());
+    assert_eq!(dst_album("Poppy/Choke"), album.key());
+    assert_eq!(dst_album("Poppy/Choke/CD1"), "Poppy###wrong");
+}
+
+#[test]
+fn test_strip_edition() {
+    assert_eq!(strip_edition("Album X (Deluxe Edition)"), "Album X");
+    assert_eq!(strip_edition("Album X [Expanded]"), "Album X");
+    assert_eq!(strip_edition("Album X (2011 Remaster)"), "Album X");
+    assert_eq!(
+        strip_edition("Album X (Remastered) [Bonus Tracks]"),
+        "Album X"
+    );
+    assert_eq!(strip_edition("Album X - Remastered 2009"), "Album X");
+    assert_eq!(strip_edition("Live (at Wembley)"), "Live (at Wembley)");
+    assert_eq!(strip_edition("(Deluxe Edition)"), "(Deluxe Edition)");
 }
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{Album, FileType};
+
+/// a converted album that can be reused as long as its source did not change
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+struct ConversionEntry {
+    /// see [`source_fingerprint`]
+    fingerprint: String,
+    dir_path: PathBuf,
+    tracks: Vec<String>,
+}
+
+/// remembers the conversions done by syncs and conversions, so that unchanged albums are not
+/// converted again and destination albums can be recognized as up to date conversions. Stored in
+/// the data dir
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ConversionCache {
+    conversions: HashMap<String, ConversionEntry>,
+    /// where the cache is stored, it is only kept in memory if not set
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+const CACHE_FILE: &str = "conversion_cache.toml";
+
+/// 64 bit FNV-1a hash. Unlike [`std::hash::DefaultHasher`], it does not change between Rust
+/// versions, so stored fingerprints stay valid
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// fingerprint of the tracks of `album`, based on their names, sizes and modification times.
+/// Changing, adding or removing a track changes it
+pub fn source_fingerprint(album: &Album) -> String {
+    let mut tracks: Vec<(String, u64, u64)> = album
+        .tracks
+        .iter()
+        .map(|t| {
+            let metadata = std::fs::metadata(album.dir_path.join(t)).ok();
+            let size = metadata.as_ref().map(|m| m.len()).unwrap_or_default();
+            let modified = metadata
+                .and_then(|m| m.modified().ok())
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or_default();
+            (t.clone(), size, modified)
+        })
+        .collect();
+    tracks.sort();
+    let text: String = tracks
+        .iter()
+        .map(|(name, size, modified)| format!("{name}\0{size}\0{modified}\n"))
+        .collect();
+    format!("{:016x}", fnv1a(text.as_bytes()))
+}
+
+fn cache_key(src_album: &Album, src_ft: &FileType, dest_ft: &FileType) -> String {
+    format!("{}###{src_ft}###{dest_ft}", src_album.normalized_key())
+}
+
+impl ConversionCache {
+    /// loads the cache stored in `state_dir`
+    pub fn load(state_dir: &Path) -> Result<Self> {
+        let cache_file = state_dir.join(CACHE_FILE);
+        let mut cache = if cache_file.exists() {
+            let text = std::fs::read_to_string(&cache_file)
+                .context(format!("Could not read {cache_file:?}"))?
+                .replace("\r\n", "\n");
+            toml::from_str(&text).context(format!("Could not parse {cache_file:?}"))?
+        } else {
+            ConversionCache::default()
+        };
+        cache.path = Some(cache_file);
+        Ok(cache)
+    }
+
+    pub fn store(&self) -> Result<()> {
+        let Some(cache_file) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = cache_file.parent() {
+            std::fs::create_dir_all(dir).context(format!("Failed to create {dir:?}"))?;
+        }
+        let text = toml::to_string(&self)?;
+        std::fs::write(cache_file, text).context(format!("Failed to write {cache_file:?}"))
+    }
+
+    /// the previous conversion of `src_album` to `dest_ft` in `dir` if its source did not change
+    /// since and all converted tracks still exist. Stale entries are removed
+    pub fn get(&mut self, src_album: &Album, dest_ft: &FileType, dir: &Path) -> Option<Album> {
+        let src_ft = src_album.file_type()?;
+        let key = cache_key(src_album, &src_ft, dest_ft);
+        let entry = self.conversions.get(&key)?;
+        if entry.dir_path != dir {
+            return None;
+        }
+        if entry.fingerprint != source_fingerprint(src_album)
+            || !entry.tracks.iter().all(|t| entry.dir_path.join(t).exists())
+        {
+            self.conversions.remove(&key);
+            return None;
+        }
+        let mut converted = src_album.clone();
+        converted.dir_path = entry.dir_path.clone();
+        converted.tracks = entry.tracks.clone();
+        Some(converted)
+    }
+
+    /// whether `album` has the tracks of the last conversion of `src_album` to its file type and
+    /// the source did not change since. The files of `album` are not accessed, so it can be a copy
+    /// of the conversion on any location
+    pub fn is_current(&self, src_album: &Album, album: &Album) -> bool {
+        let (Some(src_ft), Some(dest_ft)) = (src_album.file_type(), album.file_type()) else {
+            return false;
+        };
+        let Some(entry) = self
+            .conversions
+            .get(&cache_key(src_album, &src_ft, &dest_ft))
+        else {
+            return false;
+        };
+        let mut tracks = album.tracks.clone();
+        tracks.sort();
+        let mut converted = entry.tracks.clone();
+        converted.sort();
+        tracks == converted && entry.fingerprint == source_fingerprint(src_album)
+    }
+
+    /// remembers that `converted` is the conversion of `src_album`
+    pub fn insert(&mut self, src_album: &Album, converted: &Album) {
+        let (Some(src_ft), Some(dest_ft)) = (src_album.file_type(), converted.file_type()) else {
+            return;
+        };
+        self.conversions.insert(
+            cache_key(src_album, &src_ft, &dest_ft),
+            ConversionEntry {
+                fingerprint: source_fingerprint(src_album),
+                dir_path: converted.dir_path.clone(),
+                tracks: converted.tracks.clone(),
+            },
+        );
+    }
+
+    /// removes all entries and returns how many there were
+    pub fn clear(&mut self) -> usize {
+        let n = self.conversions.len();
+        self.conversions.clear();
+        n
+    }
+}
+
+#[test]
+fn test_conversion_cache() {
+    use crate::test_util::TestDir;
+    let dir = TestDir::new("test_conversion_cache");
+    let src_dir = dir.join("src/Poppy/Choke");
+    let dst_dir = dir.join("conv/Poppy/Choke [MP3]");
+    std::fs::create_dir_all(&src_dir).unwrap();
+    std::fs::create_dir_all(&dst_dir).unwrap();
+    std::fs::write(src_dir.join("01 Choke.flac"), "flac").unwrap();
+    std::fs::write(dst_dir.join("01 Choke.mp3"), "mp3").unwrap();
+    let album = |dir: &PathBuf, track: &str| Album::test("Poppy", "Choke", dir.clone(), &[track]);
+    let src = album(&src_dir, "01 Choke.flac");
+    let converted = album(&dst_dir, "01 Choke.mp3");
+    let mut cache = ConversionCache::default();
+    assert!(cache.get(&src, &FileType::MP3, &dst_dir).is_none());
+    cache.insert(&src, &converted);
+    let cached = cache.get(&src, &FileType::MP3, &dst_dir).unwrap();
+    assert_eq!(cached.dir_path, dst_dir);
+    assert_eq!(cached.tracks, converted.tracks);
+    assert!(cache.get(&src, &FileType::Opus, &dst_dir).is_none());
+    // the cache is stored in the state dir
+    let mut stored = ConversionCache::load(&dir).unwrap();
+    stored.insert(&src, &converted);
+    stored.store().unwrap();
+    assert!(dir.join(CACHE_FILE).exists());
+    let mut loaded = ConversionCache::load(&dir).unwrap();
+    assert!(loaded.get(&src, &FileType::MP3, &dst_dir).is_some());
+    assert!(cache.get(&src, &FileType::MP3, &dir).is_none());
+    // copies of the conversion on destinations are recognized without accessing them
+    let copy = album(&PathBuf::from("/sdcard/Music/Poppy/Choke"), "01 Choke.mp3");
+    assert!(cache.is_current(&src, &copy));
+    let partial = album(&PathBuf::from("/sdcard/Music/Poppy/Choke"), "02 Other.mp3");
+    assert!(!cache.is_current(&src, &partial));
+    // a changed source track invalidates the entry
+    std::fs::write(src_dir.join("01 Choke.flac"), "remastered flac").unwrap();
+    assert!(!cache.is_current(&src, &copy));
+    assert!(cache.get(&src, &FileType::MP3, &dst_dir).is_none());
+    assert_eq!(cache.clear(), 0);
+}
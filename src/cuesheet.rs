@@ -0,0 +1,157 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{Context, Result, bail};
+
+/// a track of a [`CueSheet`]
+#[derive(Debug, PartialEq)]
+pub struct CueTrack {
+    pub number: u16,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// position of `INDEX 01` in the audio file
+    pub start: Duration,
+}
+
+/// the parts of a cuesheet that describe a single-file album
+#[derive(Debug, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// the audio file the tracks are part of
+    pub file: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+/// the value of a cuesheet command with optional quotes, e.g. `"Choke"` in `TITLE "Choke"`
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.rsplit_once('"').map(|(v, _)| v))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// parses a cuesheet timestamp `mm:ss:ff` (75 frames per second)
+fn parse_timestamp(ts: &str) -> Result<Duration> {
+    let parts: Vec<u64> = ts
+        .trim()
+        .split(':')
+        .map(|p| p.parse())
+        .collect::<Result<_, _>>()
+        .context(format!("Invalid cuesheet timestamp {ts:?}"))?;
+    let [minutes, seconds, frames] = parts[..] else {
+        bail!("Invalid cuesheet timestamp {ts:?}");
+    };
+    Ok(Duration::from_secs(minutes * 60 + seconds) + Duration::from_millis(frames * 1000 / 75))
+}
+
+/// parses the album and track info of a cuesheet. Unknown commands are ignored
+pub fn parse_cue(text: &str) -> Result<CueSheet> {
+    let mut sheet = CueSheet {
+        title: None,
+        performer: None,
+        file: None,
+        tracks: vec![],
+    };
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match (command, sheet.tracks.last_mut()) {
+            ("FILE", _) => {
+                // the file type follows the (possibly quoted) name
+                let name = rest.rsplit_once(' ').map(|(name, _)| name).unwrap_or(rest);
+                sheet.file = Some(unquote(name));
+            }
+            ("TRACK", _) => {
+                let number = rest
+                    .split_whitespace()
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .context(format!("Invalid cuesheet line {line:?}"))?;
+                sheet.tracks.push(CueTrack {
+                    number,
+                    title: None,
+                    performer: None,
+                    start: Duration::ZERO,
+                });
+            }
+            ("TITLE", Some(track)) => track.title = Some(unquote(rest)),
+            ("TITLE", None) => sheet.title = Some(unquote(rest)),
+            ("PERFORMER", Some(track)) => track.performer = Some(unquote(rest)),
+            ("PERFORMER", None) => sheet.performer = Some(unquote(rest)),
+            ("INDEX", Some(track)) => {
+                if let Some(ts) = rest.strip_prefix("01 ") {
+                    track.start = parse_timestamp(ts)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(sheet)
+}
+
+/// reads and parses the cuesheet at `path`
+pub fn read_cue(path: &Path) -> Result<CueSheet> {
+    // cuesheets are often not UTF-8
+    let bytes = std::fs::read(path).context(format!("Could not read {path:?}"))?;
+    parse_cue(&String::from_utf8_lossy(&bytes)).context(format!("Could not parse {path:?}"))
+}
+
+impl CueSheet {
+    /// problems with the track numbering and start times
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.tracks.is_empty() {
+            problems.push("the cuesheet does not contain any tracks".to_string());
+        }
+        self.tracks.iter().enumerate().for_each(|(i, t)| {
+            if t.number as usize != i + 1 {
+                problems.push(format!("track {} has number {}", i + 1, t.number));
+            }
+            if t.title.is_none() {
+                problems.push(format!("track {} has no title", t.number));
+            }
+            if i > 0 && t.start <= self.tracks[i - 1].start {
+                problems.push(format!(
+                    "track {} does not start after the previous track",
+                    t.number
+                ));
+            }
+        });
+        problems
+    }
+}
+
+#[test]
+fn test_parse_cue() {
+    let cue = r#"REM GENRE Rock
+PERFORMER "Poppy"
+TITLE "Choke"
+FILE "Poppy - Choke.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Choke"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Fill The Crown"
+    PERFORMER "Poppy feat. Someone"
+    INDEX 00 03:10:00
+    INDEX 01 03:12:37
+"#;
+    let sheet = parse_cue(cue).unwrap();
+    assert_eq!(sheet.title.as_deref(), Some("Choke"));
+    assert_eq!(sheet.performer.as_deref(), Some("Poppy"));
+    assert_eq!(sheet.file.as_deref(), Some("Poppy - Choke.flac"));
+    assert_eq!(sheet.tracks.len(), 2);
+    assert_eq!(sheet.tracks[1].title.as_deref(), Some("Fill The Crown"));
+    assert_eq!(
+        sheet.tracks[1].performer.as_deref(),
+        Some("Poppy feat. Someone")
+    );
+    assert_eq!(sheet.tracks[1].start, Duration::from_millis(192_493));
+    assert!(sheet.problems().is_empty());
+
+    let broken =
+        parse_cue("TRACK 02 AUDIO\nINDEX 01 00:10:00\nTRACK 03 AUDIO\nINDEX 01 00:05:00").unwrap();
+    assert_eq!(broken.problems().len(), 5);
+}
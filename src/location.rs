@@ -1,18 +1,85 @@
-use std::{fs::File, io::BufWriter, path::PathBuf, str::FromStr};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufWriter, Cursor},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use crate::{
     Album,
-    album::{albums_in_dir, group_files_into_albums},
+    album::{
+        FileNameRules, SourceLayout, albums_in_dir_modified_since, apply_layout, destination_path,
+        group_files_into_albums, sanitize_file_name_for,
+    },
 };
 use adb_client::{ADBDeviceExt, ADBServer, ADBServerDevice};
 use anyhow::{Context, Result, bail};
-use fs_extra::dir::CopyOptions;
+use filetime::FileTime;
+use regex::Regex;
+
+/// a pushed file has a different size on the device than locally, usually because the device ran
+/// out of space
+#[derive(Debug)]
+pub struct SizeMismatch {
+    pub remote: String,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl std::fmt::Display for SizeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} has {} bytes on the device instead of {}, the device may be out of space",
+            self.remote, self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for SizeMismatch {}
+
+/// fails with a [`SizeMismatch`] if the size of a pushed file differs from the local one
+fn check_pushed_size(remote: &str, expected: u64, actual: u64) -> Result<()> {
+    if expected != actual {
+        bail!(SizeMismatch {
+            remote: remote.to_string(),
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
 
 pub trait Location {
     fn albums(&mut self) -> Result<Vec<Album>>;
+    /// the albums with one of the [`Album::key`]s in `keys`. Locations where listing all albums
+    /// is expensive only look at the directories [`Location::copy_full_album`] would use
+    fn albums_by_keys(&mut self, keys: &BTreeSet<String>) -> Result<Vec<Album>> {
+        let albums = self.albums()?;
+        Ok(albums
+            .into_iter()
+            .filter(|a| keys.contains(&a.key()))
+            .collect())
+    }
     fn copy_full_album(&mut self, src_album: &Album) -> Result<()>;
+    /// the directory [`Location::copy_full_album`] copies `src_album` to
+    fn album_dir(&self, src_album: &Album) -> Result<PathBuf>;
     fn del_album(&mut self, album: &Album) -> Result<()>;
-    fn copy_missing_files(&mut self, src_album: &Album, dst_album: &Album);
+    /// deletes the `tracks` of `album`, its directory and other files are kept
+    fn del_tracks(&mut self, album: &Album, tracks: &[String]) -> Result<()>;
+    /// copies the files of `src_album` that are missing in `dst_album`. Returns the number of
+    /// skipped files
+    fn copy_missing_files(&mut self, src_album: &Album, dst_album: &Album) -> usize;
+    /// copies the cover files of `src_album` that are missing in `dst_album` or differ from the
+    /// ones there. Returns the number of copied files
+    fn copy_missing_covers(&mut self, src_album: &Album, dst_album: &Album) -> Result<usize>;
+    /// the directory the albums are stored in
+    fn root(&self) -> PathBuf;
+    /// the number of bytes the files below [`Location::root`] take up
+    fn used_bytes(&mut self) -> Result<u64>;
+    /// writes `content` to `rel_path` (relative to [`Location::root`])
+    fn write_file(&mut self, rel_path: &str, content: &[u8]) -> Result<()>;
 
     fn to_string(&self) -> String;
 }
@@ -20,65 +87,282 @@ pub trait Location {
 #[derive(Debug)]
 pub struct DirLocation {
     dir: PathBuf,
+    exclude_patterns: Vec<String>,
+    /// move deleted albums to the trash instead of removing them permanently
+    use_trash: bool,
+    /// read track tags to determine album artists, see [`Album::finalize`]
+    scan_tags: bool,
+    /// characters that are not allowed in the artist and album directory names are replaced
+    file_name_rules: FileNameRules,
+    /// where albums are stored relative to `dir`, see [`destination_path`]
+    layout_template: Option<String>,
+    /// grouped singles are recognized in the directories with this name, see
+    /// [`SourceLayout::singles_dir`]
+    singles_dir: Option<String>,
 }
 impl DirLocation {
-    pub fn new(dir: PathBuf) -> Self {
-        DirLocation { dir }
+    pub fn new(
+        dir: PathBuf,
+        exclude_patterns: Vec<String>,
+        use_trash: bool,
+        scan_tags: bool,
+        file_name_rules: FileNameRules,
+    ) -> Self {
+        DirLocation {
+            dir,
+            exclude_patterns,
+            use_trash,
+            scan_tags,
+            file_name_rules,
+            layout_template: None,
+            singles_dir: None,
+        }
+    }
+
+    pub fn with_layout_template(mut self, layout_template: Option<String>) -> Self {
+        self.layout_template = layout_template;
+        self
+    }
+
+    pub fn with_singles_dir(mut self, singles_dir: Option<String>) -> Self {
+        self.singles_dir = singles_dir;
+        self
+    }
+
+    /// the layout the albums in the location are parsed with
+    fn layout(&self) -> SourceLayout {
+        SourceLayout {
+            singles_dir: self.singles_dir.clone(),
+            ..SourceLayout::default()
+        }
+    }
+}
+
+/// copies `src` to `dst` and keeps the modification time of `src`
+fn copy_file(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::copy(src, dst).context(format!("Failed to copy {src:?} to {dst:?}"))?;
+    let mtime = FileTime::from_last_modification_time(&std::fs::metadata(src)?);
+    filetime::set_file_mtime(dst, mtime)
+        .context(format!("Failed to set the modification time of {dst:?}"))
+}
+
+/// copies the files in `src` recursively to `dst`. Files that already exist in `dst` with the
+/// same size are skipped. Returns the number of copied and skipped files
+fn copy_dir_missing(src: &Path, dst: &Path) -> Result<(usize, usize)> {
+    std::fs::create_dir_all(dst).context(format!("Failed to create {dst:?}"))?;
+    let mut copied = 0;
+    let mut skipped = 0;
+    for entry in std::fs::read_dir(src).context(format!("Failed to read {src:?}"))? {
+        let entry = entry?;
+        let src_file = entry.path();
+        let dst_file = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            let (c, s) = copy_dir_missing(&src_file, &dst_file)?;
+            copied += c;
+            skipped += s;
+        } else if std::fs::metadata(&dst_file)
+            .is_ok_and(|m| m.len() == entry.metadata().map(|m| m.len()).unwrap_or(u64::MAX))
+        {
+            skipped += 1;
+        } else {
+            copy_file(&src_file, &dst_file)?;
+            copied += 1;
+        }
+    }
+    Ok((copied, skipped))
+}
+
+/// moves the file or directory at `path` to the trash if `use_trash` is set, otherwise it is
+/// deleted
+pub fn remove_path(path: &Path, use_trash: bool) -> Result<()> {
+    if use_trash {
+        trash::delete(path).context(format!("Failed to move {path:?} to the trash"))
+    } else if path.is_dir() {
+        std::fs::remove_dir_all(path).context(format!("Failed to delete {path:?}"))
+    } else {
+        std::fs::remove_file(path).context(format!("Failed to delete {path:?}"))
+    }
+}
+
+/// the total size of the files in `dir` (recursively)
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {dir:?}"))? {
+        let entry = entry?;
+        size += if entry.file_type()?.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            entry.metadata()?.len()
+        };
+    }
+    Ok(size)
+}
+
+/// copies the tracks and cover files of `album` to `dst`, keeping the subdirectories of the
+/// tracks. Files that already exist in `dst` with the same size are skipped. Returns the number of
+/// copied and skipped files
+fn copy_album_files_missing(album: &Album, dst: &Path) -> Result<(usize, usize)> {
+    let covers = album.cover_files.iter().map(|cf| {
+        let rel = cf
+            .strip_prefix(&album.dir_path)
+            .ok()
+            .or_else(|| cf.file_name().map(Path::new))
+            .unwrap_or(cf.as_path());
+        (album.dir_path.join(cf), dst.join(rel))
+    });
+    let tracks = album
+        .tracks
+        .iter()
+        .map(|t| (album.dir_path.join(t), dst.join(t)));
+    let mut copied = 0;
+    let mut skipped = 0;
+    for (src_file, dst_file) in covers.chain(tracks) {
+        let src_len = std::fs::metadata(&src_file)
+            .context(format!("Cannot read metadata of {src_file:?}"))?
+            .len();
+        if std::fs::metadata(&dst_file).is_ok_and(|m| m.len() == src_len) {
+            skipped += 1;
+            continue;
+        }
+        if let Some(parent) = dst_file.parent() {
+            std::fs::create_dir_all(parent).context(format!("Failed to create {parent:?}"))?;
+        }
+        copy_file(&src_file, &dst_file)?;
+        copied += 1;
     }
+    Ok((copied, skipped))
 }
 
 impl Location for DirLocation {
     fn albums(&mut self) -> Result<Vec<Album>> {
-        Ok(albums_in_dir(&self.dir))
+        let mut albums = albums_in_dir_modified_since(
+            &self.dir,
+            &self.exclude_patterns,
+            None,
+            self.scan_tags,
+            &self.layout(),
+        );
+        if let Some(template) = &self.layout_template {
+            apply_layout(template, &mut albums, &self.dir);
+        }
+        Ok(albums)
     }
 
-    fn copy_full_album(&mut self, src_album: &Album) -> Result<()> {
-        let dst_path = self.dir.join(&src_album.parsed_artist);
-        if !dst_path.exists() {
-            std::fs::create_dir_all(&dst_path)?;
+    fn albums_by_keys(&mut self, keys: &BTreeSet<String>) -> Result<Vec<Album>> {
+        if self.layout_template.is_some() {
+            let albums = self.albums()?;
+            return Ok(albums
+                .into_iter()
+                .filter(|a| keys.contains(&a.key()))
+                .collect());
+        }
+        // the directory is listed once. The key does not depend on the tags, so they are only
+        // read for the matching albums
+        let albums: Vec<Album> = albums_in_dir_modified_since(
+            &self.dir,
+            &self.exclude_patterns,
+            None,
+            false,
+            &self.layout(),
+        )
+        .into_iter()
+        .filter(|a| keys.contains(&a.key()))
+        .collect();
+        if !self.scan_tags {
+            return Ok(albums);
         }
-        let copy_options = CopyOptions::new();
+        let files: Vec<PathBuf> = albums
+            .iter()
+            .flat_map(|a| {
+                a.tracks
+                    .iter()
+                    .map(|t| a.dir_path.join(t))
+                    .chain(a.cover_files.iter().cloned())
+            })
+            .collect();
+        Ok(group_files_into_albums(
+            &files,
+            &self.dir,
+            true,
+            &self.layout(),
+        ))
+    }
+
+    fn copy_full_album(&mut self, src_album: &Album) -> Result<()> {
+        let dst_path = self.album_dir(src_album)?;
         println!("Copying {:?} to {dst_path:?}", src_album.dir_path);
-        match fs_extra::copy_items(&[&src_album.dir_path], dst_path, &copy_options) {
-            Ok(_) => Ok(()),
-            Err(e) => bail!("Failed to copy items: {e:?}"),
+        // the directory of grouped singles may contain other albums, so only their own files are
+        // copied
+        let (copied, skipped) = if src_album.tracks.iter().any(|t| t.contains('/')) {
+            copy_album_files_missing(src_album, &dst_path)?
+        } else {
+            copy_dir_missing(&src_album.dir_path, &dst_path)?
+        };
+        println!("Copied {copied} files, skipped {skipped} files that already existed");
+        Ok(())
+    }
+    fn album_dir(&self, src_album: &Album) -> Result<PathBuf> {
+        if let Some(template) = &self.layout_template {
+            return Ok(self.dir.join(destination_path(
+                template,
+                src_album,
+                src_album.file_type().as_ref(),
+                self.file_name_rules,
+            )));
         }
+        let album_dir_name = src_album
+            .dir_path
+            .file_name()
+            .context(format!("{:?} has no directory name", src_album.dir_path))?;
+        Ok(self
+            .dir
+            .join(sanitize_file_name_for(
+                &src_album.parsed_artist,
+                self.file_name_rules,
+            ))
+            .join(sanitize_file_name_for(
+                &album_dir_name.to_string_lossy(),
+                self.file_name_rules,
+            )))
     }
     fn del_album(&mut self, album: &Album) -> Result<()> {
-        std::fs::remove_dir_all(&album.dir_path)
-            .context(format!("Failed to delete {}", album.overview()))
+        remove_path(&album.dir_path, self.use_trash)
+    }
+    fn del_tracks(&mut self, album: &Album, tracks: &[String]) -> Result<()> {
+        tracks
+            .iter()
+            .try_for_each(|t| remove_path(&album.dir_path.join(t), self.use_trash))
     }
-    fn copy_missing_files(&mut self, src_album: &Album, dst_album: &Album) {
+    fn copy_missing_files(&mut self, src_album: &Album, dst_album: &Album) -> usize {
         println!("Copying missing files for {}", src_album.overview());
+        let mut skipped = 0;
         if dst_album.dir_path.exists() {
             src_album.tracks.iter().for_each(|src_track| {
-                if !dst_album.tracks.iter().any(|t| t == src_track) {
+                if dst_album.tracks.iter().any(|t| t == src_track) {
+                    skipped += 1;
+                } else {
                     let dest = dst_album.dir_path.join(src_track);
                     let src_track = src_album.dir_path.join(src_track);
                     if src_track == dest {
                         println!("Did not find better src for {src_track:?}. Skipping.");
+                        skipped += 1;
                     } else {
                         println!("Copying missing track {src_track:?} to {dest:?}");
-                        let succ = std::fs::copy(src_track, dest);
+                        // tracks of grouped singles are in subdirectories
+                        let succ = dest
+                            .parent()
+                            .map_or(Ok(()), std::fs::create_dir_all)
+                            .context(format!("Failed to create the directory of {dest:?}"))
+                            .and_then(|()| copy_file(&src_track, &dest));
                         if succ.is_err() {
                             println!("Something went wrong: {succ:?}");
                         }
                     }
                 }
             });
-            src_album.cover_files.iter().for_each(|src_cover| {
-                if !src_album.cover_files.iter().any(|c| c == src_cover) {
-                    let src_cover = src_album.dir_path.join(src_cover);
-                    println!(
-                        "Copying missing track {src_cover:?} to {:?}",
-                        dst_album.dir_path
-                    );
-                    let succ = std::fs::copy(src_cover, &dst_album.dir_path);
-                    if succ.is_err() {
-                        println!("Something went wrong: {succ:?}");
-                    }
-                }
-            });
+            if let Err(e) = self.copy_missing_covers(src_album, dst_album) {
+                println!("Something went wrong: {e:?}");
+            }
         } else {
             /*println!(
                 "copying {:?} to {:?}!",
@@ -86,6 +370,44 @@ impl Location for DirLocation {
             );*/
             let _ = self.copy_full_album(src_album);
         }
+        skipped
+    }
+
+    fn copy_missing_covers(&mut self, src_album: &Album, dst_album: &Album) -> Result<usize> {
+        let mut copied = 0;
+        for src_cover in &src_album.cover_files {
+            let src_cover = src_album.dir_path.join(src_cover);
+            let name = src_cover
+                .file_name()
+                .context(format!("{src_cover:?} has no file name"))?;
+            let dest = dst_album.dir_path.join(name);
+            let src_len = std::fs::metadata(&src_cover)
+                .context(format!("Cannot read metadata of {src_cover:?}"))?
+                .len();
+            if std::fs::metadata(&dest).is_ok_and(|m| m.len() == src_len) {
+                continue;
+            }
+            println!("Copying cover file {src_cover:?} to {dest:?}");
+            copy_file(&src_cover, &dest)?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    fn root(&self) -> PathBuf {
+        self.dir.clone()
+    }
+
+    fn used_bytes(&mut self) -> Result<u64> {
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+        dir_size(&self.dir)
+    }
+
+    fn write_file(&mut self, rel_path: &str, content: &[u8]) -> Result<()> {
+        let path = self.dir.join(rel_path);
+        std::fs::write(&path, content).context(format!("Failed to write {path:?}"))
     }
 
     fn to_string(&self) -> String {
@@ -93,12 +415,42 @@ impl Location for DirLocation {
     }
 }
 
+/// music directories on ADB devices, the first one that exists is used. SD cards are tried
+/// afterwards, see [`sd_card_music_dirs`]
+const ADB_MUSIC_DIR_CANDIDATES: [&str; 3] = [
+    "/storage/emulated/0/Music",
+    "/sdcard/Music",
+    "/storage/self/primary/Music",
+];
+
+/// the music directories of the SD cards in the output of `ls /storage`. SD cards are mounted by
+/// their volume id, e.g. `/storage/1A2B-3C4D`
+fn sd_card_music_dirs(storage_listing: &str) -> Vec<String> {
+    let volume_id = Regex::new(r"^[0-9A-Fa-f]{4}-[0-9A-Fa-f]{4}$").expect("valid regex");
+    storage_listing
+        .split_whitespace()
+        .filter(|name| volume_id.is_match(name))
+        .map(|name| format!("/storage/{name}/Music"))
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct AdbLocation {
     device: ADBServerDevice,
+    /// how often a failed push is retried
+    push_retries: u32,
+    /// the directory albums are stored in
+    music_dir: String,
+    /// where albums are stored relative to the music directory, see [`destination_path`]
+    layout_template: Option<String>,
+    /// grouped singles are recognized in the directories with this name, see
+    /// [`SourceLayout::singles_dir`]
+    singles_dir: Option<String>,
 }
 impl AdbLocation {
-    pub fn new() -> Result<Self> {
+    /// connects to the ADB device. Albums are stored in `music_dir` if it is set, otherwise the
+    /// first existing directory of [`ADB_MUSIC_DIR_CANDIDATES`] or an SD card is used
+    pub fn new(push_retries: u32, music_dir: Option<String>) -> Result<Self> {
         let mut server = ADBServer::default();
         let devices = server.devices()?;
         if devices.len() != 1 {
@@ -112,8 +464,148 @@ impl AdbLocation {
         let Ok(device) = server.get_device() else {
             bail!("Failed to get ADB device!");
         };
-        Ok(AdbLocation { device })
+        let mut location = AdbLocation {
+            device,
+            push_retries,
+            music_dir: String::new(),
+            layout_template: None,
+            singles_dir: None,
+        };
+        location.music_dir = match music_dir {
+            Some(music_dir) => {
+                if !location.dir_exists_on_adb_device(&music_dir) {
+                    bail!("The configured ADB music directory {music_dir} does not exist");
+                }
+                music_dir
+            }
+            None => {
+                let mut buf = BufWriter::new(Vec::new());
+                let _ = location.device.shell_command(&["ls", "/storage"], &mut buf);
+                let storage_listing = String::from_utf8_lossy(&buf.into_inner()?).to_string();
+                let candidates: Vec<String> = ADB_MUSIC_DIR_CANDIDATES
+                    .iter()
+                    .map(|d| d.to_string())
+                    .chain(sd_card_music_dirs(&storage_listing))
+                    .collect();
+                let Some(music_dir) = candidates
+                    .iter()
+                    .find(|d| location.dir_exists_on_adb_device(d))
+                else {
+                    bail!(
+                        "None of the music directories {candidates:?} exist on the ADB device, set one with `config set-adb-music-dir`"
+                    );
+                };
+                music_dir.clone()
+            }
+        };
+        println!("Using ADB music directory {}", location.music_dir);
+        Ok(location)
+    }
+
+    pub fn with_layout_template(mut self, layout_template: Option<String>) -> Self {
+        self.layout_template = layout_template;
+        self
+    }
+
+    pub fn with_singles_dir(mut self, singles_dir: Option<String>) -> Self {
+        self.singles_dir = singles_dir;
+        self
+    }
+
+    /// the layout the albums in the location are parsed with
+    fn layout(&self) -> SourceLayout {
+        SourceLayout {
+            singles_dir: self.singles_dir.clone(),
+            ..SourceLayout::default()
+        }
+    }
+
+    /// pushes `local` to `remote` on the device. Files that are already present on the device with
+    /// the same size are skipped. Failed pushes and pushed files whose size on the device differs
+    /// (see [`SizeMismatch`]) are retried up to `push_retries` times. Returns whether the file was
+    /// pushed
+    fn push_file(&mut self, local: &Path, remote: &str) -> Result<bool> {
+        let local_size = std::fs::metadata(local)
+            .context(format!("Cannot read metadata of {local:?}"))?
+            .len();
+        // missing files are reported with all fields set to 0
+        if let Ok(stat) = self.device.stat(remote)
+            && stat.file_perm != 0
+            && u64::from(stat.file_size) == local_size
+        {
+            println!("Skipping {remote}: already present on device");
+            return Ok(false);
+        }
+        let mut attempt = 0;
+        loop {
+            let res = File::open(local)
+                .context(format!("Cannot open file {local:?}"))
+                .and_then(|mut input| {
+                    self.device
+                        .push(&mut input, &remote)
+                        .context(format!("Failed to push {local:?} to {remote}"))
+                })
+                .and_then(|()| {
+                    let stat = self
+                        .device
+                        .stat(remote)
+                        .context(format!("Failed to check the size of {remote}"))?;
+                    check_pushed_size(remote, local_size, u64::from(stat.file_size))
+                });
+            match res {
+                Ok(()) => return Ok(true),
+                Err(e) if attempt < self.push_retries => {
+                    attempt += 1;
+                    println!("{e:?}\nRetrying ({attempt}/{})...", self.push_retries);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// the album with [`Album::key`] `key` in the directory [`Location::copy_full_album`] would
+    /// use without a layout template
+    fn album_by_key(&mut self, key: &str) -> Result<Option<Album>> {
+        let Some((artist, title)) = key.split_once("###") else {
+            bail!("Invalid album key {key:?}");
+        };
+        let album_dir = format!(
+            "{}/{}/{}",
+            self.music_dir,
+            sanitize_file_name_for(artist, FileNameRules::Fat32),
+            sanitize_file_name_for(title, FileNameRules::Fat32)
+        );
+        if !self.dir_exists_on_adb_device(&album_dir) {
+            return Ok(None);
+        }
+        // the tracks of grouped singles are in the subdirectories of the album
+        let is_singles = self
+            .singles_dir
+            .as_ref()
+            .is_some_and(|name| title.eq_ignore_ascii_case(name));
+        let depth = if is_singles { "2" } else { "1" };
+        let mut buf = BufWriter::new(Vec::new());
+        let album_dir_s = format!("\"{album_dir}\"");
+        let command = vec!["find", &album_dir_s, "-maxdepth", depth, "-type", "f"];
+        self.device
+            .shell_command(&command, &mut buf)
+            .context(format!("Failed to list the files in {album_dir}"))?;
+        let bytes = buf.into_inner()?;
+        let out = String::from_utf8_lossy(&bytes).to_string();
+        let music_paths: Vec<PathBuf> = out
+            .lines()
+            .filter(|l| l.starts_with(&album_dir))
+            .map(PathBuf::from)
+            .collect();
+        // sanitizing may change the title, so the album is not compared by key
+        let albums = group_files_into_albums(
+            &music_paths,
+            Path::new(&self.music_dir),
+            false,
+            &self.layout(),
+        );
+        Ok(albums.into_iter().next())
     }
+
     fn dir_exists_on_adb_device(&mut self, path: &str) -> bool {
         let mut path = path.to_string();
         if !(path.starts_with('\"')) {
@@ -135,66 +627,142 @@ impl AdbLocation {
 impl Location for AdbLocation {
     fn albums(&mut self) -> Result<Vec<Album>> {
         let mut buf = BufWriter::new(Vec::new());
-        let command = vec!["find", "/storage/emulated/0/Music", "-type", "f"];
-        let _ = self.device.shell_command(&command, &mut buf);
+        let command = vec!["find", &self.music_dir, "-type", "f"];
+        self.device
+            .shell_command(&command, &mut buf)
+            .context(format!("Failed to list the files in {}", self.music_dir))?;
         let bytes = buf.into_inner()?;
         let out = String::from_utf8_lossy(&bytes).to_string();
+        let music_dir = format!("{}/", self.music_dir);
+        // warnings of find and paths that are not valid UTF-8 cannot be handled, they are skipped
         let music_paths: Vec<PathBuf> = out
             .lines()
-            .map(|l| PathBuf::from_str(l).expect("each line should be a valid path!"))
+            .filter(|l| {
+                let valid = l.starts_with(&music_dir) && !l.contains(char::REPLACEMENT_CHARACTER);
+                if !valid {
+                    println!("WARNING: skipping unexpected output of find: {l:?}");
+                }
+                valid
+            })
+            .map(PathBuf::from)
             .collect();
-        let pb: PathBuf = PathBuf::from_str("/storage/emulated/0/Music")?;
-        let albums = group_files_into_albums(&music_paths, pb.as_path());
+        let pb: PathBuf = PathBuf::from_str(&self.music_dir)?;
+        // the tracks are not accessible locally, so their tags cannot be read
+        let mut albums = group_files_into_albums(&music_paths, pb.as_path(), false, &self.layout());
+        if let Some(template) = &self.layout_template {
+            apply_layout(template, &mut albums, &pb);
+        }
         Ok(albums)
     }
 
+    fn albums_by_keys(&mut self, keys: &BTreeSet<String>) -> Result<Vec<Album>> {
+        if self.layout_template.is_some() {
+            let albums = self.albums()?;
+            return Ok(albums
+                .into_iter()
+                .filter(|a| keys.contains(&a.key()))
+                .collect());
+        }
+        keys.iter()
+            .filter_map(|key| self.album_by_key(key).transpose())
+            .collect()
+    }
+
     fn copy_full_album(&mut self, src_album: &Album) -> Result<()> {
-        let adb_artist_dir = format!("/storage/emulated/0/Music/{}", &src_album.parsed_artist);
-        if !self.dir_exists_on_adb_device(&adb_artist_dir) {
-            let mut buf = BufWriter::new(Vec::new());
-            let adb_dir_s = format!("\"{adb_artist_dir}\"");
-            let command = vec!["mkdir", &adb_dir_s];
-            let _ = self.device.shell_command(&command, &mut buf);
+        if self.layout_template.is_none() {
+            let adb_artist_dir = format!(
+                "{}/{}",
+                self.music_dir,
+                sanitize_file_name_for(&src_album.parsed_artist, FileNameRules::Fat32)
+            );
+            if !self.dir_exists_on_adb_device(&adb_artist_dir) {
+                let mut buf = BufWriter::new(Vec::new());
+                let adb_dir_s = format!("\"{adb_artist_dir}\"");
+                let command = vec!["mkdir", &adb_dir_s];
+                let _ = self.device.shell_command(&command, &mut buf);
+            }
         }
-        let adb_album_dir =
-            src_album.album_dir_with_ft(PathBuf::from("/storage/emulated/0/Music"), &None);
+        let adb_album_dir = self.album_dir(src_album)?;
         let adb_album_dir = adb_album_dir.to_str().unwrap();
         let adb_album_dir = adb_album_dir.replace("\\", "/");
         let adb_album_dir_s = format!("\"{adb_album_dir}\"");
         if !self.dir_exists_on_adb_device(&adb_album_dir_s) {
             let mut buf = BufWriter::new(Vec::new());
             // TODO: only replace unescaped double backslash
-            let command = vec!["mkdir", &adb_album_dir_s];
+            // templates may contain several levels of directories
+            let command = vec!["mkdir", "-p", &adb_album_dir_s];
             let success = self.device.shell_command(&command, &mut buf);
             if success.is_err() {
                 println!("{success:?}");
             }
         }
-        src_album.cover_files.iter().for_each(|cf| {
-            let mut input = File::open(cf).expect("Cannot open file {cf:?}");
+        let mut failed = vec![];
+        let covers = src_album.cover_files.iter().map(|cf| {
             let name = cf
                 .file_name()
                 .expect("Cover files must have a file name!")
                 .to_str()
                 .expect("Cover file name must be convertible to str")
                 .replace(".jpeg", ".jpg");
-            let full_cover_dst = format!("{adb_album_dir}/{name}");
-            let _ = self.device.push(&mut input, &full_cover_dst);
+            (cf.clone(), format!("{adb_album_dir}/{name}"))
         });
-        src_album.tracks.iter().for_each(|tf| {
-            let full_track_file = src_album.dir_path.join(tf);
-            let input = File::open(&full_track_file);
-            match input {
-                Ok(mut input) => {
-                    let full_track_dst = format!("{adb_album_dir}/{tf}");
-                    let success = self.device.push(&mut input, &full_track_dst);
-                    if success.is_err() {
-                        println!("{success:?}");
-                    }
+        let tracks = src_album
+            .tracks
+            .iter()
+            .map(|tf| (src_album.dir_path.join(tf), format!("{adb_album_dir}/{tf}")));
+        let files: Vec<(PathBuf, String)> = covers.chain(tracks).collect();
+        for (local, remote) in files {
+            match self.push_file(&local, &remote) {
+                Ok(_) => {}
+                // the following files would be truncated as well
+                Err(e) if e.is::<SizeMismatch>() => {
+                    return Err(e.context(format!("Stopped pushing {}", src_album.overview())));
+                }
+                Err(e) => {
+                    println!("{e:?}");
+                    failed.push(local);
                 }
-                Err(e) => println!("Cannot open track file {full_track_file:?}: {e:?}"),
             }
-        });
+        }
+        let total = src_album.cover_files.len() + src_album.tracks.len();
+        if failed.is_empty() {
+            println!("Pushed {} ({total} files)", src_album.overview());
+            Ok(())
+        } else {
+            bail!(
+                "Failed to push {} of {total} files of {}: {failed:?}",
+                failed.len(),
+                src_album.overview()
+            )
+        }
+    }
+
+    fn album_dir(&self, src_album: &Album) -> Result<PathBuf> {
+        Ok(match &self.layout_template {
+            Some(template) => PathBuf::from(&self.music_dir).join(destination_path(
+                template,
+                src_album,
+                src_album.file_type().as_ref(),
+                FileNameRules::Fat32,
+            )),
+            None => src_album.album_dir_with_ft(
+                PathBuf::from(&self.music_dir),
+                &None,
+                FileNameRules::Fat32,
+            ),
+        })
+    }
+
+    fn del_tracks(&mut self, album: &Album, tracks: &[String]) -> Result<()> {
+        let dir = album.dir_path.to_string_lossy().replace('\\', "/");
+        for t in tracks {
+            let path = format!("\"{dir}/{t}\"");
+            println!("Deleting {path}");
+            let mut buf = BufWriter::new(Vec::new());
+            self.device
+                .shell_command(&["rm", "-f", &path], &mut buf)
+                .context(format!("Failed to delete {path}"))?;
+        }
         Ok(())
     }
 
@@ -214,50 +782,30 @@ impl Location for AdbLocation {
         Ok(())
     }
 
-    fn copy_missing_files(&mut self, src_album: &Album, dst_album: &Album) {
+    fn copy_missing_files(&mut self, src_album: &Album, dst_album: &Album) -> usize {
         let dst_dir = dst_album.dir_path.to_str().unwrap();
+        let mut skipped = 0;
         if self.dir_exists_on_adb_device(dst_dir) {
             src_album.tracks.iter().for_each(|src_track| {
-                if !dst_album.tracks.iter().any(|t| t == src_track) {
+                if dst_album.tracks.iter().any(|t| t == src_track) {
+                    skipped += 1;
+                } else {
+                    let full_track_dst = format!("{dst_dir}/{src_track}");
                     let src_track = src_album.dir_path.join(src_track);
                     println!(
                         "Copying missing track {src_track:?} to {:?}",
                         dst_album.dir_path
                     );
-                    let mut input = File::open(&src_track).expect("Cannot open file");
-                    let name = src_track
-                        .file_name()
-                        .expect("Track files must have a file name!")
-                        .to_str()
-                        .expect("Cover file name must be convertible to str");
-                    let full_track_dst = format!("{dst_dir}/{name}");
                     println!("PUSH {src_track:?} -> {full_track_dst}");
-                    let success = self.device.push(&mut input, &full_track_dst);
+                    let success = self.push_file(&src_track, &full_track_dst);
                     if success.is_err() {
                         println!("{success:?}");
                     }
                 }
             });
-            src_album.cover_files.iter().for_each(|src_cover| {
-                if !src_album.cover_files.iter().any(|c| c == src_cover) {
-                    let src_cover = src_album.dir_path.join(src_cover);
-                    println!(
-                        "Copying missing cover file {src_cover:?} to {:?}",
-                        dst_album.dir_path
-                    );
-                    let mut input = File::open(&src_cover)
-                        .unwrap_or_else(|e| panic!("Cannot open file {src_cover:?}: {e}"));
-
-                    let name = src_cover
-                        .file_name()
-                        .expect("Cover files must have a file name!")
-                        .to_str()
-                        .expect("Cover file name must be convertible to str")
-                        .replace(".jpeg", ".jpg");
-                    let full_cover_dst = format!("{dst_dir}/{name}");
-                    let _ = self.device.push(&mut input, &full_cover_dst);
-                }
-            });
+            if let Err(e) = self.copy_missing_covers(src_album, dst_album) {
+                println!("{e:?}");
+            }
         } else {
             println!(
                 "{:?} does not exist on device. Copying everything from {:?}!",
@@ -265,8 +813,160 @@ impl Location for AdbLocation {
             );
             let _ = self.copy_full_album(src_album);
         }
+        skipped
+    }
+    fn copy_missing_covers(&mut self, src_album: &Album, dst_album: &Album) -> Result<usize> {
+        let dst_dir = dst_album.dir_path.to_string_lossy().replace('\\', "/");
+        let mut copied = 0;
+        for src_cover in &src_album.cover_files {
+            let src_cover = src_album.dir_path.join(src_cover);
+            let name = src_cover
+                .file_name()
+                .context(format!("{src_cover:?} has no file name"))?
+                .to_string_lossy()
+                .replace(".jpeg", ".jpg");
+            let full_cover_dst = format!("{dst_dir}/{name}");
+            if self.push_file(&src_cover, &full_cover_dst)? {
+                copied += 1;
+            }
+        }
+        Ok(copied)
     }
+
+    fn root(&self) -> PathBuf {
+        PathBuf::from(&self.music_dir)
+    }
+
+    fn used_bytes(&mut self) -> Result<u64> {
+        let mut buf = BufWriter::new(Vec::new());
+        let music_dir_s = format!("\"{}\"", self.music_dir);
+        self.device
+            .shell_command(&["du", "-sk", &music_dir_s], &mut buf)
+            .context(format!(
+                "Failed to determine the size of {}",
+                self.music_dir
+            ))?;
+        let out = String::from_utf8_lossy(&buf.into_inner()?).to_string();
+        let kib: u64 = out
+            .split_whitespace()
+            .next()
+            .and_then(|kib| kib.parse().ok())
+            .context(format!("Unexpected output of du: {out:?}"))?;
+        Ok(kib * 1024)
+    }
+
+    fn write_file(&mut self, rel_path: &str, content: &[u8]) -> Result<()> {
+        let remote = format!("{}/{rel_path}", self.music_dir);
+        self.device
+            .push(&mut Cursor::new(content), &remote)
+            .context(format!("Failed to push {remote}"))
+    }
+
     fn to_string(&self) -> String {
-        "AdbLocation".to_string()
+        format!("AdbLocation({})", self.music_dir)
+    }
+}
+
+#[test]
+fn test_copy_keeps_modification_time() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("copy_test");
+    let src = root.join("src");
+    let dst = root.join("dst");
+    std::fs::create_dir_all(src.join("CD1")).unwrap();
+    std::fs::write(src.join("CD1").join("01 Choke.mp3"), "music").unwrap();
+    let mtime = FileTime::from_unix_time(1_500_000_000, 0);
+    filetime::set_file_mtime(src.join("CD1").join("01 Choke.mp3"), mtime).unwrap();
+
+    assert_eq!(copy_dir_missing(&src, &dst).unwrap(), (1, 0));
+    let copied = std::fs::metadata(dst.join("CD1").join("01 Choke.mp3")).unwrap();
+    assert_eq!(FileTime::from_last_modification_time(&copied), mtime);
+    // a second copy skips the existing file
+    assert_eq!(copy_dir_missing(&src, &dst).unwrap(), (0, 1));
+}
+
+#[test]
+fn test_copy_missing_covers() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("cover_test");
+    let src = root.join("src");
+    let dst = root.join("dst");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::create_dir_all(&dst).unwrap();
+    std::fs::write(src.join("cover.jpg"), "new cover").unwrap();
+    std::fs::write(src.join("back.jpg"), "back").unwrap();
+    std::fs::write(dst.join("back.jpg"), "back").unwrap();
+    std::fs::write(dst.join("cover.jpg"), "old").unwrap();
+    let album = |dir: &Path| {
+        let mut album = Album::test("Poppy", "Choke", dir, &[]);
+        album.cover_files = vec![dir.join("cover.jpg"), dir.join("back.jpg")];
+        album
+    };
+    let mut loc = DirLocation::new(dst.clone(), vec![], false, false, FileNameRules::Fat32);
+    // only the differing cover is copied
+    assert_eq!(
+        loc.copy_missing_covers(&album(&src), &album(&dst)).unwrap(),
+        1
+    );
+    assert_eq!(
+        std::fs::read_to_string(dst.join("cover.jpg")).unwrap(),
+        "new cover"
+    );
+    assert_eq!(
+        loc.copy_missing_covers(&album(&src), &album(&dst)).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_copy_grouped_singles() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("singles_copy_test");
+    let singles = root.join("src/Poppy/Singles");
+    let dst = root.join("dst");
+    for file in [
+        "Choke/01 Choke.flac",
+        "Concrete/01 Concrete.flac",
+        "EP/01 A.flac",
+    ] {
+        std::fs::create_dir_all(singles.join(file).parent().unwrap()).unwrap();
+        std::fs::write(singles.join(file), "flac").unwrap();
     }
+    let album = Album::test(
+        "Poppy",
+        "Singles",
+        singles.clone(),
+        &["Choke/01 Choke.flac", "Concrete/01 Concrete.flac"],
+    );
+    let mut loc = DirLocation::new(dst.clone(), vec![], false, false, FileNameRules::Fat32)
+        .with_singles_dir(Some("Singles".to_string()));
+    loc.copy_full_album(&album).unwrap();
+    assert!(dst.join("Poppy/Singles/Choke/01 Choke.flac").exists());
+    assert!(dst.join("Poppy/Singles/Concrete/01 Concrete.flac").exists());
+    // the EP in the singles directory is a separate album
+    assert!(!dst.join("Poppy/Singles/EP").exists());
+    let albums = loc.albums().unwrap();
+    assert_eq!(albums.len(), 1);
+    assert_eq!(albums[0].key(), "Poppy###Singles");
+    assert_eq!(albums[0].tracks, album.tracks);
+}
+
+#[test]
+fn test_sd_card_music_dirs() {
+    let listing = "1A2B-3C4D\nemulated\nself\nABCD-EF01\n";
+    assert_eq!(
+        sd_card_music_dirs(listing),
+        vec!["/storage/1A2B-3C4D/Music", "/storage/ABCD-EF01/Music"]
+    );
+    assert!(sd_card_music_dirs("emulated self").is_empty());
+}
+
+#[test]
+fn test_check_pushed_size() {
+    assert!(check_pushed_size("/sdcard/Music/a.flac", 100, 100).is_ok());
+    let err = check_pushed_size("/sdcard/Music/a.flac", 100, 40)
+        .unwrap_err()
+        .context("Stopped pushing album");
+    assert!(err.is::<SizeMismatch>());
+    assert!(format!("{err:#}").contains("out of space"));
 }
@@ -1,33 +1,67 @@
 use anyhow::{Context, Result, bail};
 use directories::ProjectDirs;
+use distance::levenshtein;
 use fs_extra::dir::CopyOptions;
-use indicatif::ProgressIterator;
+use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
 use music_info::MusicInfoCache;
 use music_tags::set_tags;
+use notify::{RecursiveMode, Watcher};
+use pathdiff::diff_paths;
+use playlist::{playlist_tracks, rewrite_playlist};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Display,
     fs::read_dir,
+    io::{BufRead, BufReader, Read},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, ExitStatus, Stdio},
     str::FromStr,
-    time,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 mod album;
+mod conversion_cache;
+mod cuesheet;
 mod location;
 mod music_info;
 mod music_tags;
+mod playlist;
+mod sync_checkpoint;
+#[cfg(test)]
+mod test_util;
+mod vorbis_tag;
 use crate::{
-    album::{Album, path_to_details},
-    location::{AdbLocation, DirLocation, Location},
-    music_info::AlbumInfo,
-    music_tags::parse_track_info,
+    album::{
+        Album, FileNameRules, cover_problems, disc_suffix, path_to_details, sanitize_file_name,
+    },
+    conversion_cache::ConversionCache,
+    cuesheet::read_cue,
+    location::{AdbLocation, DirLocation, Location, SizeMismatch, remove_path},
+    music_info::{AlbumInfo, DiscogsFilters, Keys, LyricsClient, fetch_cover_bytes, test_discogs},
+    music_tags::{
+        Id3Version, TagField, TrackInfo, copy_tags_to_mp3, detect_file_type,
+        fix_track_number_titles, get_audio_properties, get_duration, get_track_tags,
+        parse_track_info, read_lyrics, repair_tags, strip_tags, strip_track_number, write_lyrics,
+        write_sidecar, year_from_tags,
+    },
+    sync_checkpoint::SyncCheckpoint,
 };
 use crate::{
-    album::{albums_in_dir, create_source_album_lookup},
-    music_tags::set_missing_tags,
+    album::{
+        DEFAULT_FILETYPE_BRACKETS, DirLayout, FiletypeSuffix, SourceLayout, albums_in_dir,
+        albums_in_dir_modified_since, create_source_album_lookup, duplicate_candidates,
+        is_various_artists, normalize_key_part, playlists_in_dir,
+    },
+    music_tags::{
+        embed_cover, embed_cover_bytes, embedded_cover_dimensions, image_dimensions,
+        set_missing_tags,
+    },
 };
 
 use clap::{Parser, Subcommand, ValueEnum};
@@ -35,13 +69,24 @@ use clap::{Parser, Subcommand, ValueEnum};
 use crate::music_info::download_cover_file;
 
 const IMAGE_EXTENSIONS: [&str; 3] = ["jpeg", "jpg", "png"];
-const MUSIC_EXTENSIONS: [&str; 4] = ["mp3", "flac", "wav", "m4a"];
+const MUSIC_EXTENSIONS: [&str; 9] = [
+    "mp3", "flac", "wav", "m4a", "ogg", "opus", "aiff", "aif", "wma",
+];
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Exit codes: 0 on success, 1 on errors, 2 if a command like check or diff found issues"
+)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// never access the network: discogs info is only taken from the cache (albums that are not
+    /// cached fall back to the info parsed from their folders) and nothing is downloaded
+    #[arg(long, global = true)]
+    offline: bool,
 }
 
 #[derive(Subcommand)]
@@ -52,29 +97,296 @@ enum Commands {
         subcommand: ConfigCommands,
     },
     /// check your configured directories for issues like duplicate albums, albums that are nested
-    /// too deeply and many more
-    Check,
+    /// too deeply and many more. Exits with code 2 if issues were found
+    Check {
+        /// also read the audio properties of every track to find albums with mixed sample rates or
+        /// bit depths and unreadable files. This is considerably slower
+        #[arg(short, long)]
+        deep: bool,
+        /// also report albums as duplicates if their track titles match although the file names
+        /// differ, or if they have the same key and file type in the same directory
+        #[arg(short, long)]
+        fuzzy: bool,
+        /// detect the format of every track from its file header and report tracks whose
+        /// extension does not match it
+        #[arg(long)]
+        verify_format: bool,
+        /// read the dimensions of every cover file and report covers that are smaller than
+        /// 500x500 or not roughly square, and albums without a cover named like one of the
+        /// configured cover file names
+        #[arg(long)]
+        check_covers: bool,
+        /// do not read track tags to determine album artists, the artist parsed from the folder
+        /// structure is used instead. Considerably faster for large libraries
+        #[arg(long)]
+        no_tag_scan: bool,
+    },
     /// sync files in the sources to the destination directories. If a suitable ADB connection can
     /// be established, the files are also synced to the first ADB device
-    Sync,
+    Sync {
+        /// only consider source albums whose directory changed since then. Accepts a duration
+        /// (e.g. `30m`, `12h`, `7d`, `2w`), a date (`YYYY-MM-DD`) or a unix timestamp
+        #[arg(long, value_parser = parse_since)]
+        since: Option<SystemTime>,
+        /// never fall back to a different file type (ignores allow_any) and report the albums
+        /// that are not available in the desired file type
+        #[arg(long)]
+        strict: bool,
+        /// store converted albums in this directory instead of the configured conversion dir or
+        /// next to their source album
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+        /// do not read track tags to determine album artists, the artist parsed from the folder
+        /// structure is used instead. Considerably faster for large libraries
+        #[arg(long)]
+        no_tag_scan: bool,
+        /// abort on the first album that fails to sync instead of continuing with the others
+        #[arg(long)]
+        fail_fast: bool,
+        /// ask before deleting an album in the wrong file type from a destination. It is only
+        /// deleted once its replacement was copied
+        #[arg(long)]
+        confirm_deletes: bool,
+        /// only copy the albums in this file: either a playlist (`.m3u`/`.m3u8`) whose tracks
+        /// select their albums, or a list with one album key (`artist###title`) per line
+        #[arg(long, value_parser = parse_album_selection)]
+        only: Option<AlbumSelection>,
+        /// treat remasters and special editions like "(Deluxe Edition)" as the plain album, so
+        /// they are not copied if the destination already has it
+        #[arg(long)]
+        ignore_editions: bool,
+    },
+    /// copies missing or changed cover files of the source albums to the albums already present in
+    /// the destinations. Tracks are not touched
+    SyncCovers,
+    /// reports for every destination how many source albums are missing, are in the wrong file
+    /// type or are not in the sources. Nothing is modified. Exits with code 2 if a destination
+    /// does not reflect the sources
+    Status {
+        /// list the albums instead of only counting them
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// watches the source directories and syncs changes to all destinations. Stop with Ctrl-C
+    Watch {
+        /// seconds without further changes before a sync is started
+        #[arg(short, long, default_value_t = 30)]
+        debounce: u64,
+    },
     /// Uses discogs to set music tags (metadata)
     CleanUpTags {
         dir: PathBuf,
         #[arg(short, long)]
         no_cache: bool,
+        /// also write artist and album artist sort tags (e.g. "Beatles, The")
+        #[arg(short, long)]
+        sort_tags: bool,
+        /// only process albums whose key, artist or title contains this (case-insensitive)
+        #[arg(short, long)]
+        album: Option<String>,
+        /// only process the first n albums
+        #[arg(short, long)]
+        limit: Option<usize>,
+        /// write the tags to a sidecar file (`morg.toml`) in the album directory instead of the
+        /// tracks. Other commands prefer the sidecar over the track tags. Metadata pinned in its
+        /// `[override]` table is kept
+        #[arg(long)]
+        sidecar: bool,
+        /// abort on the first album that fails instead of continuing with the others
+        #[arg(long)]
+        fail_fast: bool,
+        /// only print how the tags of every track would change, nothing is written
+        #[arg(long, conflicts_with = "sidecar")]
+        preview: bool,
     },
+    /// Sets the tags of the tracks in dir from their file names (e.g. `01 Title.flac`) and the
+    /// artist and album parsed from the folders, without looking anything up. Existing tags are
+    /// replaced. Reports the files whose name has no track number or title
+    RepairTags { dir: PathBuf },
     /// Uses discogs to download cover files. The cover files will be stored in the album directory
     FillInCoverFiles {
         dir: PathBuf,
         #[arg(short, long)]
         overwrite: bool,
+        /// only process albums whose key, artist or title contains this (case-insensitive)
+        #[arg(short, long)]
+        album: Option<String>,
+        /// only process the first n albums
+        #[arg(short, long)]
+        limit: Option<usize>,
+        /// abort on the first album that fails instead of continuing with the others
+        #[arg(long)]
+        fail_fast: bool,
+    },
+    /// Embeds the existing cover file of each album in dir into its tracks. If an album has several,
+    /// the one DedupeCovers would keep is used
+    EmbedCovers {
+        dir: PathBuf,
+        /// also replace covers that are already embedded
+        #[arg(short, long)]
+        overwrite: bool,
+    },
+    /// Replaces the embedded covers of the albums in dir whose shorter side is below min_size pixels
+    /// by the cover of their discogs release. Albums with large enough covers are skipped
+    UpgradeCovers {
+        dir: PathBuf,
+        #[arg(long, default_value_t = album::MIN_COVER_SIZE)]
+        min_size: u32,
+    },
+    /// Keeps one cover file per album in dir and deletes the others. Covers named like the
+    /// configured cover file names are preferred, then the one with the largest resolution
+    DedupeCovers {
+        dir: PathBuf,
+        /// only print what would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// embed the kept cover into tracks without an embedded cover
+        #[arg(long)]
+        embed: bool,
+    },
+    /// Removes junk files like `Thumbs.db` or `.DS_Store` from dir (recursively), see
+    /// `config set-junk-patterns`. Audio, image and auxiliary files are never removed
+    CleanJunk {
+        dir: PathBuf,
+        /// only print what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Looks up the lyrics of each track in dir on lrclib.net using its artist and title tags and
+    /// writes them to the track's tag. Synced lyrics are preferred over plain ones
+    FetchLyrics {
+        dir: PathBuf,
+        /// also replace lyrics that are already present
+        #[arg(short, long)]
+        overwrite: bool,
+    },
+    /// WIP: fixes some issues in the file setup. Currently replaces symlinks in the source
+    /// directories with copies of their targets
+    Fix {
+        /// only print what would be done
+        #[arg(short, long)]
+        dry_run: bool,
     },
-    /// WIP: fixes some issues in the file setup
-    Fix,
     /// Just for internal testing purposes
     Test,
-    /// Lists the albums found in src that are missing in dst
-    Diff { src: PathBuf, dst: PathBuf },
+    /// Lists the albums found in src that are missing in dst. Exits with code 2 if albums are
+    /// missing
+    Diff {
+        src: PathBuf,
+        dst: PathBuf,
+        /// list the albums found in dst that are missing in src instead, e.g. to review orphaned
+        /// albums before deleting them
+        #[arg(short, long)]
+        reverse: bool,
+        /// treat remasters and special editions like "(Deluxe Edition)" as the plain album
+        #[arg(long)]
+        ignore_editions: bool,
+    },
+    /// checks that the external tools needed for your configured destinations (ffmpeg, adb) are
+    /// available and that the config, keys file and data directory are usable
+    Doctor,
+    /// makes a single request to discogs to check that the keys are valid and prints how many
+    /// requests remain in the current rate limit window
+    DiscogsTest,
+    /// reports albums whose album artist or album title tags differ from the artist and title
+    /// derived from their folder. Exits with code 2 if mismatches were found
+    Audit {
+        dir: PathBuf,
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// lists the albums in dir that are only available in lossy formats (e.g. MP3 or M4A), i.e.
+    /// neither dir nor a source directory contains a lossless (FLAC, WAV, AIFF) copy
+    LossyOnly {
+        dir: PathBuf,
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Deletes the albums in the configured conversion dir and forgets the conversions remembered
+    /// by previous syncs
+    ClearConversionCache,
+    /// Removes the cached discogs info of the album with the given key (`<artist>###<title>`) or,
+    /// if no key is given, clears the whole cache
+    CacheClear { key: Option<String> },
+    /// Removes the track number from the start of title tags, e.g. "01 - Song" becomes "Song".
+    /// File names are not changed
+    FixTitles {
+        dir: PathBuf,
+        /// only print what would be changed
+        #[arg(short, long)]
+        dry_run: bool,
+    },
+    /// Lists the tag items of the tracks in dir and removes all items except the kept fields
+    StripTags {
+        dir: PathBuf,
+        /// tag fields to keep
+        #[arg(
+            short,
+            long,
+            value_delimiter = ',',
+            default_values = ["title", "artist", "album", "albumartist", "track", "disc", "year", "genre"]
+        )]
+        keep: Vec<TagField>,
+        /// also remove embedded pictures
+        #[arg(short, long)]
+        strip_cover: bool,
+        /// only list the tag items
+        #[arg(short, long)]
+        dry_run: bool,
+    },
+    /// Renames the track files in dir to `NN Title.ext` (`D-NN Title.ext` for albums with
+    /// multiple discs) based on their tags
+    RenameTracks {
+        dir: PathBuf,
+        /// only print what would be renamed
+        #[arg(short, long)]
+        dry_run: bool,
+    },
+    /// Moves albums whose album artist tags differ from their artist folder to a folder named
+    /// after the tag artist, e.g. `Beyonce/Lemonade` to `Beyoncé/Lemonade`. Compilations are not
+    /// moved
+    RenameArtistDirs {
+        dir: PathBuf,
+        /// only print what would be moved
+        #[arg(short, long)]
+        dry_run: bool,
+    },
+    /// Merges albums that were split by disc (e.g. "Album - CD1" and "Album - CD2") into one album
+    /// directory. The tracks are renamed to `D-NN Title.ext` and get disc number tags
+    MergeDiscs {
+        dir: PathBuf,
+        /// only print what would be merged
+        #[arg(short, long)]
+        dry_run: bool,
+        /// merge without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Splits single-file albums with a cuesheet into one file per track (`NN Title.ext`) with
+    /// ffmpeg. The original file is renamed to `<name>.orig` afterwards
+    SplitCue {
+        dir: PathBuf,
+        /// only print what would be split
+        #[arg(short, long)]
+        dry_run: bool,
+    },
+    /// Converts the albums in src to dest_ft without syncing. The converted albums are stored in
+    /// out_dir as `<artist>/<title> [<dest_ft>]`. Use Transcode to convert albums in place
+    Convert {
+        src: PathBuf,
+        dest_ft: FileType,
+        out_dir: PathBuf,
+    },
+    /// Converts the albums in dir to dest_ft next to the originals, e.g. to free space by
+    /// replacing FLAC with MP3. Converting a lossy format to a lossless one is refused
+    Transcode {
+        dir: PathBuf,
+        dest_ft: FileType,
+        /// delete the original albums once all of their tracks were converted and the converted
+        /// tracks decode without errors
+        #[arg(short, long)]
+        replace: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -98,6 +410,200 @@ enum ConfigCommands {
         #[clap(default_value_t = false)]
         allow_any: bool,
     },
+    /// store the albums of a destination directory in subdirectories named after their file
+    /// type, e.g. `FLAC/` and `MP3/`. Add the directory once per file type with `add-dest`
+    SetFileTypeSubdirs {
+        #[arg()]
+        directory: PathBuf,
+        #[clap(default_value_t = true)]
+        enabled: bool,
+    },
+    /// set whether the artist from the album artist tags is used instead of the artist folder
+    /// name for destination paths and album matching. Albums already synced under the folder
+    /// spelling are copied again under the tag spelling
+    SetArtistFromTags {
+        #[arg(action = clap::ArgAction::Set)]
+        enabled: bool,
+    },
+    /// add a glob pattern for files and directories that should be skipped during scanning.
+    /// Patterns are matched against the path relative to the scanned root and the file name
+    AddExclude {
+        #[arg()]
+        pattern: String,
+    },
+    /// set how often a failed push to an ADB device is retried
+    SetAdbPushRetries {
+        #[arg()]
+        retries: u32,
+    },
+    /// set the music directory on ADB devices, e.g. `/sdcard/Music`. Call without a path to use
+    /// the first existing one of the common locations
+    SetAdbMusicDir {
+        #[arg()]
+        path: Option<String>,
+    },
+    /// set the ffmpeg executable used for conversions
+    SetFfmpegPath {
+        #[arg()]
+        path: PathBuf,
+    },
+    /// set additional arguments that are passed to ffmpeg for every conversion. Call without
+    /// arguments to remove them
+    SetFfmpegExtraArgs {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// set after how many days cached discogs info is fetched again. Call without a value to keep
+    /// cached info forever
+    SetCacheTtl {
+        #[arg()]
+        days: Option<u64>,
+    },
+    /// set the order in which source file types are tried when an album has to be converted.
+    /// File types that are not given are tried afterwards in the default order (flac, wav, aiff,
+    /// mp3, m4a, ogg, opus, wma)
+    SetSourcePriority {
+        #[arg(value_parser = parse_source_file_type)]
+        file_types: Vec<FileType>,
+    },
+    /// set the order in which file types are preferred when an album is not available in the
+    /// desired file type and allow_any is set for the destination. Call without file types to copy
+    /// the album the sync found as it is
+    SetFallbackPriority {
+        #[arg()]
+        file_types: Vec<FileType>,
+    },
+    /// set whether albums are converted to mp3 as a whole with `lame --nogap`, so that gapless
+    /// albums stay gapless. Otherwise every track is converted with ffmpeg on its own
+    SetGaplessMp3 {
+        #[arg(action = clap::ArgAction::Set)]
+        enabled: bool,
+    },
+    /// set the lame executable used for gapless mp3 conversions
+    SetLamePath {
+        #[arg()]
+        path: PathBuf,
+    },
+    /// set the quality of albums converted to mp3. Defaults to 320k CBR if neither option is given
+    SetMp3Quality {
+        /// constant bitrate in kbit/s, e.g. 192
+        #[arg(short, long, conflicts_with = "vbr")]
+        bitrate: Option<u32>,
+        /// VBR quality level from 0 (best) to 9 (smallest)
+        #[arg(short, long, value_parser = clap::value_parser!(u8).range(0..=9))]
+        vbr: Option<u8>,
+    },
+    /// set the ID3v2 version of MP3 tags written by conversions and clean-up-tags. v23 (the
+    /// default) is read by almost every player but stores text as UTF-16 and joins multiple values
+    /// with `/`. v24 supports UTF-8 and multi-valued frames, but older players and Windows
+    /// Explorer may not read it
+    SetId3Version {
+        #[arg()]
+        version: Id3Version,
+    },
+    /// set how artist, album and track are arranged in the scanned directories. `auto` guesses
+    /// the layout from the depth of every file
+    SetDirLayout {
+        #[arg()]
+        layout: DirLayout,
+    },
+    /// group the single-track directories in directories with this name (e.g. `Singles` for
+    /// `Artist/Singles/Title`) into one album per artist. Call without a name to disable it
+    SetSinglesDir {
+        #[arg()]
+        name: Option<String>,
+    },
+    /// set the brackets that enclose file type suffixes in album titles, e.g. `[]` `()` for
+    /// " [FLAC]" and " (mp3)". Call without brackets to use `[]` `()` `{}`
+    SetFiletypeBrackets {
+        #[arg()]
+        brackets: Vec<String>,
+    },
+    /// set the maximum number of bytes the files on a destination may take up. Syncs skip and
+    /// report the albums that do not fit anymore. Call without a size to remove the limit
+    SetMaxSize {
+        /// the destination directory
+        #[arg(long, required_unless_present = "adb", conflicts_with = "adb")]
+        dir: Option<PathBuf>,
+        /// set the limit for the ADB device
+        #[arg(long)]
+        adb: bool,
+        max_size_bytes: Option<u64>,
+    },
+    /// set which albums are copied first to destinations with a maximum size
+    SetQuotaOrder {
+        #[arg()]
+        order: QuotaOrder,
+    },
+    /// set the directory converted albums are stored in during syncs. They are reused by later
+    /// syncs. Call without a path to store them next to their source album again
+    SetConversionDir {
+        #[arg()]
+        path: Option<PathBuf>,
+    },
+    /// set whether albums deleted from destination directories are moved to the trash instead of
+    /// being removed permanently
+    SetUseTrash {
+        #[arg(action = clap::ArgAction::Set)]
+        use_trash: bool,
+    },
+    /// set the proxy used for discogs and cover requests, e.g. `http://proxy:8080`. Call without a
+    /// url to use the HTTP_PROXY/HTTPS_PROXY environment variables again
+    SetProxy {
+        #[arg()]
+        url: Option<String>,
+    },
+    /// set which file system's file name rules are used for the artist and album directories
+    /// created in destination directories. ADB devices always use fat32
+    SetFileNameRules {
+        #[arg()]
+        rules: FileNameRules,
+    },
+    /// set the discogs filters used to narrow searches with many results. Omitted filters are not
+    /// applied, e.g. call without arguments to never filter
+    SetDiscogsFilters {
+        /// e.g. `album`, `ep` or `single`
+        #[arg(long)]
+        format: Option<String>,
+        /// e.g. `Official Release`
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// set the names (without extension) downloaded covers are stored as, e.g. `folder` or
+    /// `AlbumArt`. The cover is written once for each name. Existing covers with these names are
+    /// preferred (in this order) when embedding or deduplicating covers
+    SetCoverFileNames {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// set the extensions of the files (besides covers) that are copied to converted albums, e.g.
+    /// `cue log nfo pdf`. Call without extensions to copy none
+    SetAuxFileExtensions {
+        #[arg()]
+        extensions: Vec<String>,
+    },
+    /// set where albums are stored in destinations, e.g. `{artist}/{year} - {album}`. The
+    /// placeholders `{artist}`, `{album}`, `{year}` and `{ft}` are supported. Call without a
+    /// template to use the default `artist/album` layout
+    SetLayoutTemplate {
+        #[arg()]
+        template: Option<String>,
+    },
+    /// set the regular expressions whose matches are removed from the track titles parsed from
+    /// file names, e.g. `"(?i)\s*\[hd\]"`. Call without patterns to remove none
+    SetTitleCleanupPatterns {
+        #[arg()]
+        patterns: Vec<String>,
+    },
+    /// set the file name glob patterns of junk files removed by clean-junk, e.g. `Thumbs.db *.txt`.
+    /// Call without patterns to remove none
+    SetJunkPatterns {
+        #[arg()]
+        patterns: Vec<String>,
+    },
+    /// Checks that the configured directories exist and are readable, that there are no duplicate
+    /// entries and that the discogs keys file is present
+    Validate,
     /// Prints the config file location
     PrintFile,
 }
@@ -108,12 +614,46 @@ pub enum FileType {
     MP3,
     Wav,
     Flac,
+    Ogg,
+    Opus,
+    /// only supported as conversion source
+    Aiff,
+    /// only supported as conversion source
+    Wma,
 }
 
 impl FileType {
+    /// all file types, including those that can only be converted from
+    const ALL: [FileType; 8] = [
+        FileType::M4A,
+        FileType::MP3,
+        FileType::Wav,
+        FileType::Flac,
+        FileType::Ogg,
+        FileType::Opus,
+        FileType::Aiff,
+        FileType::Wma,
+    ];
+
     fn is_lossless(&self) -> bool {
         use FileType::*;
-        matches!(self, Wav | Flac)
+        matches!(self, Wav | Flac | Aiff)
+    }
+
+    /// the file type of music files with the extension `ext` (case-insensitive)
+    pub fn from_extension(ext: &str) -> Option<FileType> {
+        if ext.eq_ignore_ascii_case("aif") {
+            return Some(FileType::Aiff);
+        }
+        FileType::ALL
+            .iter()
+            .find(|ft| ft.to_string().eq_ignore_ascii_case(ext))
+            .cloned()
+    }
+
+    /// converting a lossy format to a lossless one is prohibited
+    fn can_convert_to(&self, dest_ft: &FileType) -> bool {
+        self.is_lossless() || !dest_ft.is_lossless()
     }
 }
 
@@ -126,13 +666,18 @@ impl ValueEnum for FileType {
                 MP3 => "mp3",
                 Wav => "wav",
                 Flac => "flac",
+                Ogg => "ogg",
+                Opus => "opus",
+                Aiff => "aiff",
+                Wma => "wma",
             }
             .into(),
         )
     }
+    /// aiff and wma are not listed as they cannot be used as destination file types
     fn value_variants<'a>() -> &'a [Self] {
         use FileType::*;
-        &[M4A, MP3, Wav, Flac]
+        &[M4A, MP3, Wav, Flac, Ogg, Opus]
     }
 }
 
@@ -150,6 +695,258 @@ struct DirConfig {
     source_directories: Vec<PathBuf>,
     /// dest, ft, allow_any (fallback option if ft is not available)
     destinations: Vec<(Destination, FileType, bool)>,
+    /// glob patterns of files and directories that are skipped during scanning
+    #[serde(default)]
+    exclude_patterns: Vec<String>,
+    /// how often a failed push to an ADB device is retried
+    #[serde(default = "default_adb_push_retries")]
+    adb_push_retries: u32,
+    /// music directory on ADB devices. Common locations are probed if not set
+    #[serde(default)]
+    adb_music_dir: Option<String>,
+    /// ffmpeg executable to use, defaults to `ffmpeg` on the PATH
+    #[serde(default)]
+    ffmpeg_path: Option<PathBuf>,
+    /// additional arguments passed to ffmpeg for every conversion
+    #[serde(default)]
+    ffmpeg_extra_args: Vec<String>,
+    /// cached discogs info older than this is fetched again. Never expires if not set
+    #[serde(default)]
+    cache_ttl_days: Option<u64>,
+    /// order in which source file types are tried when an album has to be converted
+    #[serde(
+        default = "default_conversion_source_priority",
+        deserialize_with = "deserialize_file_types"
+    )]
+    conversion_source_priority: Vec<FileType>,
+    /// order in which file types are copied to destinations with allow_any if the desired one is
+    /// not available. The source album of the sync is copied as it is if empty
+    #[serde(default, deserialize_with = "deserialize_file_types")]
+    fallback_priority: Vec<FileType>,
+    /// quality of albums converted to mp3, 320k CBR by default
+    #[serde(default)]
+    mp3_quality: Mp3Quality,
+    /// convert whole albums to mp3 with `lame --nogap` instead of converting every track with
+    /// ffmpeg, so that they play without gaps
+    #[serde(default)]
+    gapless_mp3: bool,
+    /// lame executable to use for gapless conversions, defaults to `lame` on the PATH
+    #[serde(default)]
+    lame_path: Option<PathBuf>,
+    /// ID3v2 version of MP3 tags written during conversions and tagging
+    #[serde(default)]
+    id3_version: Id3Version,
+    /// how artist, album and track are arranged in the scanned directories
+    #[serde(default)]
+    dir_layout: DirLayout,
+    /// single-track directories in directories with this name are grouped into one album per
+    /// artist
+    #[serde(default)]
+    singles_dir: Option<String>,
+    /// opening and closing brackets (e.g. `[]`) that enclose the file type suffixes removed from
+    /// album titles, like " [FLAC]"
+    #[serde(default = "default_filetype_brackets")]
+    filetype_brackets: Vec<String>,
+    /// maximum number of bytes the files on a destination may take up
+    #[serde(default)]
+    destination_max_sizes: Vec<(Destination, u64)>,
+    /// which albums are copied first if a destination has a maximum size
+    #[serde(default)]
+    quota_order: QuotaOrder,
+    /// converted albums are stored here instead of next to their source album
+    #[serde(default)]
+    conversion_dir: Option<PathBuf>,
+    /// albums deleted from destination directories are moved to the trash instead of being
+    /// removed permanently
+    #[serde(default)]
+    use_trash: bool,
+    /// proxy url for discogs and cover requests. `HTTP_PROXY`/`HTTPS_PROXY` are used if not set
+    #[serde(default)]
+    proxy: Option<String>,
+    /// characters that are not allowed under these rules are replaced in the artist and album
+    /// directories created in destination directories
+    #[serde(default)]
+    file_name_rules: FileNameRules,
+    /// filters used to narrow discogs searches with many results
+    #[serde(default)]
+    discogs_filters: DiscogsFilters,
+    /// names (without extension) downloaded covers are stored as and that are preferred when
+    /// choosing an album's cover
+    #[serde(default = "default_cover_file_names")]
+    cover_file_names: Vec<String>,
+    /// extensions of files like logs and booklets that are copied to converted albums
+    #[serde(default = "default_aux_file_extensions")]
+    aux_file_extensions: Vec<String>,
+    /// file name glob patterns of files removed by `CleanJunk`
+    #[serde(default = "default_junk_patterns")]
+    junk_patterns: Vec<String>,
+    /// regular expressions whose matches are removed from track titles parsed from file names,
+    /// see [`music_tags::clean_title`]
+    #[serde(default = "default_title_cleanup_patterns")]
+    title_cleanup_patterns: Vec<String>,
+    /// where albums are stored in destinations, see [`album::destination_path`]. Albums are
+    /// stored as `artist/album` if not set
+    #[serde(default)]
+    layout_template: Option<String>,
+    /// destination directories whose albums are stored in a subdirectory per file type
+    #[serde(default)]
+    file_type_subdir_destinations: Vec<PathBuf>,
+    /// use the artist from the album artist tags instead of the artist folder name for
+    /// destination paths and album matching
+    #[serde(default)]
+    artist_from_tags: bool,
+}
+
+/// the switches of a single sync that are given on the command line instead of being stored in
+/// the config
+#[derive(Clone, Debug, Default)]
+struct SyncOptions {
+    /// keep the folder-parsed album artists instead of reading the track tags
+    skip_tag_scan: bool,
+    /// stop syncing after the first error
+    fail_fast: bool,
+    /// ask before deleting albums from destinations
+    confirm_deletes: bool,
+    /// the source albums the sync is restricted to
+    only: Option<AlbumSelection>,
+    /// do not copy source albums whose plain album or other edition is in the destination
+    ignore_editions: bool,
+    /// where the conversion cache and the progress of the sync are stored (see [`data_dir`]).
+    /// They are only kept in memory if not set
+    state_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize, ValueEnum)]
+enum QuotaOrder {
+    /// albums whose directory was modified most recently come first
+    RecentlyAdded,
+    /// albums are copied in alphabetical order of artist and title
+    #[default]
+    Alphabetical,
+}
+
+/// encoder setting for mp3 conversions
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+enum Mp3Quality {
+    /// constant bitrate in kbit/s
+    Cbr(u32),
+    /// lame VBR quality level, 0 (best) to 9 (smallest)
+    Vbr(u8),
+}
+
+impl Default for Mp3Quality {
+    fn default() -> Self {
+        Mp3Quality::Cbr(320)
+    }
+}
+
+impl Mp3Quality {
+    fn ffmpeg_args(&self) -> Vec<String> {
+        match self {
+            Mp3Quality::Cbr(kbps) => vec!["-b:a".to_string(), format!("{kbps}k")],
+            Mp3Quality::Vbr(level) => vec!["-q:a".to_string(), level.to_string()],
+        }
+    }
+
+    fn lame_args(&self) -> Vec<String> {
+        match self {
+            Mp3Quality::Cbr(kbps) => vec!["--cbr".to_string(), "-b".to_string(), kbps.to_string()],
+            Mp3Quality::Vbr(level) => vec!["-V".to_string(), level.to_string()],
+        }
+    }
+}
+
+fn default_adb_push_retries() -> u32 {
+    3
+}
+
+fn default_cover_file_names() -> Vec<String> {
+    vec!["cover".to_string()]
+}
+
+fn default_aux_file_extensions() -> Vec<String> {
+    ["cue", "log", "nfo", "pdf"]
+        .iter()
+        .map(|ext| ext.to_string())
+        .collect()
+}
+
+fn default_junk_patterns() -> Vec<String> {
+    ["Thumbs.db", ".DS_Store", "desktop.ini", "*.txt"]
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+fn default_title_cleanup_patterns() -> Vec<String> {
+    [
+        // "(Official Audio)", "[Official Music Video]"
+        r"(?i)\s*[(\[]official\s+(?:audio|video|music\s+video|lyric\s+video|visualizer)[)\]]",
+        // "[HD]", "(HQ)", "(4K)"
+        r"(?i)\s*[(\[](?:hd|hq|4k|1080p|720p)[)\]]",
+        // a separator between the track number and the title like in "04 . Title". Titles like
+        // "...Baby One More Time" are kept, the separator has to be followed by whitespace
+        r"^[.\-_]\s+",
+    ]
+    .iter()
+    .map(|p| p.to_string())
+    .collect()
+}
+
+fn default_filetype_brackets() -> Vec<String> {
+    DEFAULT_FILETYPE_BRACKETS
+        .iter()
+        .map(|(open, close)| format!("{open}{close}"))
+        .collect()
+}
+
+/// splits `brackets` like `[]` into the opening and closing bracket
+fn bracket_pair(brackets: &str) -> Option<(char, char)> {
+    let mut chars = brackets.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(open), Some(close), None) => Some((open, close)),
+        _ => None,
+    }
+}
+
+fn default_conversion_source_priority() -> Vec<FileType> {
+    vec![
+        FileType::Flac,
+        FileType::Wav,
+        FileType::Aiff,
+        FileType::MP3,
+        FileType::M4A,
+        FileType::Ogg,
+        FileType::Opus,
+        FileType::Wma,
+    ]
+}
+
+/// skips unknown file types instead of failing to parse the whole config
+fn deserialize_file_types<'de, D>(deserializer: D) -> std::result::Result<Vec<FileType>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let names: Vec<String> = Deserialize::deserialize(deserializer)?;
+    Ok(names
+        .into_iter()
+        .filter_map(|name| {
+            let ft = FileType::ALL
+                .iter()
+                .find(|ft| format!("{ft:?}") == name || ft.to_string() == name.to_lowercase())
+                .cloned();
+            if ft.is_none() {
+                println!("WARNING: Ignoring unknown file type {name:?} in the config");
+            }
+            ft
+        })
+        .collect())
 }
 
 impl DirConfig {
@@ -163,6 +960,35 @@ impl DirConfig {
             Ok(DirConfig {
                 source_directories: vec![],
                 destinations: vec![],
+                exclude_patterns: vec![],
+                adb_push_retries: default_adb_push_retries(),
+                adb_music_dir: None,
+                ffmpeg_path: None,
+                ffmpeg_extra_args: vec![],
+                cache_ttl_days: None,
+                conversion_source_priority: default_conversion_source_priority(),
+                fallback_priority: vec![],
+                mp3_quality: Mp3Quality::default(),
+                gapless_mp3: false,
+                lame_path: None,
+                id3_version: Id3Version::default(),
+                dir_layout: DirLayout::default(),
+                singles_dir: None,
+                filetype_brackets: default_filetype_brackets(),
+                destination_max_sizes: vec![],
+                quota_order: QuotaOrder::default(),
+                conversion_dir: None,
+                use_trash: false,
+                proxy: None,
+                file_name_rules: FileNameRules::default(),
+                discogs_filters: DiscogsFilters::default(),
+                cover_file_names: default_cover_file_names(),
+                aux_file_extensions: default_aux_file_extensions(),
+                junk_patterns: default_junk_patterns(),
+                title_cleanup_patterns: default_title_cleanup_patterns(),
+                layout_template: None,
+                file_type_subdir_destinations: vec![],
+                artist_from_tags: false,
             })
         }
     }
@@ -173,6 +999,162 @@ impl DirConfig {
         Ok(())
     }
 
+    /// how the albums in the source directories are arranged
+    fn source_layout(&self) -> SourceLayout {
+        SourceLayout {
+            dir_layout: self.dir_layout,
+            singles_dir: self.singles_dir.clone(),
+            filetype_suffix: FiletypeSuffix::new(&self.filetype_brackets()),
+        }
+    }
+
+    /// the configured filetype brackets as pairs of opening and closing bracket. Invalid entries
+    /// are skipped
+    fn filetype_brackets(&self) -> Vec<(char, char)> {
+        self.filetype_brackets
+            .iter()
+            .filter_map(|b| {
+                let pair = bracket_pair(b);
+                if pair.is_none() {
+                    println!("WARNING: Ignoring invalid file type brackets {b:?} in the config");
+                }
+                pair
+            })
+            .collect()
+    }
+
+    /// how the albums in the destinations are arranged, they only share the singles directories
+    /// with the sources
+    fn destination_layout(&self) -> SourceLayout {
+        SourceLayout {
+            singles_dir: self.singles_dir.clone(),
+            ..SourceLayout::default()
+        }
+    }
+
+    fn cache_ttl(&self) -> Option<Duration> {
+        self.cache_ttl_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    /// loads the discogs cache with the configured ttl, proxy and search filters. With `offline`,
+    /// only cached info is used
+    fn load_music_info(&self, refresh: bool, offline: bool) -> Result<MusicInfoCache> {
+        Ok(MusicInfoCache::load(refresh, self.cache_ttl())?
+            .with_proxy(self.proxy.clone())
+            .with_filters(self.discogs_filters.clone())
+            .with_offline(offline))
+    }
+
+    /// the compiled [`DirConfig::title_cleanup_patterns`]
+    fn title_rules(&self) -> Result<Vec<Regex>> {
+        self.title_cleanup_patterns
+            .iter()
+            .map(|p| Regex::new(p).context(format!("Invalid title cleanup pattern {p:?}")))
+            .collect()
+    }
+
+    /// the directory albums of file type `ft` are stored in for the destination directory `dir`
+    fn destination_dir(&self, dir: &Path, ft: &FileType) -> PathBuf {
+        if self.file_type_subdir_destinations.iter().any(|d| d == dir) {
+            dir.join(ft.to_string().to_uppercase())
+        } else {
+            dir.to_path_buf()
+        }
+    }
+
+    /// the albums in the destination directory `dir`, parsed the same way syncs do
+    fn destination_albums(&self, dir: &Path) -> Vec<Album> {
+        let roots: Vec<PathBuf> = if self.file_type_subdir_destinations.iter().any(|d| d == dir) {
+            FileType::ALL
+                .iter()
+                .map(|ft| self.destination_dir(dir, ft))
+                .filter(|d| d.is_dir())
+                .collect()
+        } else {
+            vec![dir.to_path_buf()]
+        };
+        roots
+            .iter()
+            .flat_map(|root| {
+                let mut albums =
+                    albums_in_dir(root, &self.exclude_patterns, &self.destination_layout());
+                if let Some(template) = &self.layout_template {
+                    album::apply_layout(template, &mut albums, root);
+                }
+                albums
+            })
+            .collect()
+    }
+
+    fn max_size(&self, dest: &Destination) -> Option<u64> {
+        self.destination_max_sizes
+            .iter()
+            .find(|(d, _)| d == dest)
+            .map(|(_, size)| *size)
+    }
+
+    /// the configured conversion source priority, completed with the remaining file types in
+    /// their default order
+    fn source_priority(&self) -> Vec<FileType> {
+        let mut res: Vec<FileType> = vec![];
+        for ft in self
+            .conversion_source_priority
+            .iter()
+            .chain(default_conversion_source_priority().iter())
+        {
+            if !res.contains(ft) {
+                res.push(ft.clone());
+            }
+        }
+        res
+    }
+
+    /// checks the config and prints a report. Returns whether all checks passed
+    fn validate(&self) -> bool {
+        let mut valid = true;
+        let dirs = self.source_directories.iter().map(|d| ("Source", d)).chain(
+            self.destinations.iter().filter_map(|(d, _, _)| match d {
+                Destination::PathDest(p) => Some(("Destination", p)),
+                Destination::ADBDest => None,
+            }),
+        );
+        for (kind, dir) in dirs {
+            match read_dir(dir) {
+                Ok(_) => println!("[ OK ] {kind} {dir:?} exists and is readable"),
+                Err(e) => {
+                    println!("[FAIL] {kind} {dir:?} is not readable: {e}");
+                    valid = false;
+                }
+            }
+        }
+        for (i, dir) in self.source_directories.iter().enumerate() {
+            if self.source_directories[..i].contains(dir) {
+                println!("[FAIL] Source {dir:?} is configured more than once");
+                valid = false;
+            }
+        }
+        for (i, (dest, _, _)) in self.destinations.iter().enumerate() {
+            if self.destinations[..i].iter().any(|(d, _, _)| d == dest) {
+                println!("[FAIL] Destination {dest} is configured more than once");
+                valid = false;
+            }
+        }
+        for pattern in &self.exclude_patterns {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                println!("[FAIL] Invalid exclude pattern {pattern:?}: {e}");
+                valid = false;
+            }
+        }
+        match Keys::parse() {
+            Ok(_) => println!("[ OK ] Discogs keys file is present"),
+            Err(e) => println!(
+                "[WARN] Discogs keys are not available, CleanUpTags and FillInCoverFiles will not work: {e}"
+            ),
+        }
+        valid
+    }
+
     fn config_file() -> PathBuf {
         let pd = ProjectDirs::from("TF", "TF", "morg").expect("The project dir should be valid!");
         let cfg_dir = pd.config_dir();
@@ -186,16 +1168,155 @@ impl DirConfig {
     }
 }
 
-#[derive(Clone, Deserialize, Serialize)]
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
 enum Destination {
     PathDest(PathBuf),
     ADBDest,
 }
 
+impl Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Destination::PathDest(p) => write!(f, "{p:?}"),
+            Destination::ADBDest => f.write_str("ADB device"),
+        }
+    }
+}
+
+/// counts of what happened during a sync
+#[derive(Clone, Debug, Default)]
+struct SyncSummary {
+    albums_copied: usize,
+    albums_converted: usize,
+    albums_deleted: usize,
+    files_skipped: usize,
+    errors: usize,
+    /// albums that could not be provided in the desired file type
+    unavailable_albums: Vec<String>,
+    /// albums that were skipped because the destination quota was reached
+    quota_skipped_albums: Vec<String>,
+    /// albums whose files were truncated on the destination, see [`SizeMismatch`]
+    truncated_albums: Vec<String>,
+    /// albums copied in another file type because of allow_any, with the chosen file type
+    fallback_albums: Vec<String>,
+}
+
+impl SyncSummary {
+    fn add(&mut self, other: &SyncSummary) {
+        self.albums_copied += other.albums_copied;
+        self.albums_converted += other.albums_converted;
+        self.albums_deleted += other.albums_deleted;
+        self.files_skipped += other.files_skipped;
+        self.errors += other.errors;
+        self.unavailable_albums
+            .extend(other.unavailable_albums.iter().cloned());
+        self.quota_skipped_albums
+            .extend(other.quota_skipped_albums.iter().cloned());
+        self.truncated_albums
+            .extend(other.truncated_albums.iter().cloned());
+        self.fallback_albums
+            .extend(other.fallback_albums.iter().cloned());
+    }
+
+    fn print(&self, title: &str) {
+        println!("===== {title} =====");
+        println!("Albums copied:    {}", self.albums_copied);
+        println!("Albums converted: {}", self.albums_converted);
+        println!("Albums deleted:   {}", self.albums_deleted);
+        println!("Files skipped:    {}", self.files_skipped);
+        println!("Errors:           {}", self.errors);
+        if !self.quota_skipped_albums.is_empty() {
+            println!(
+                "Destination quota reached, skipped {} albums:",
+                self.quota_skipped_albums.len()
+            );
+            self.quota_skipped_albums
+                .iter()
+                .for_each(|a| println!("  {a}"));
+        }
+        if !self.truncated_albums.is_empty() {
+            println!(
+                "Files of {} albums were truncated, the destination may be out of space:",
+                self.truncated_albums.len()
+            );
+            self.truncated_albums.iter().for_each(|a| println!("  {a}"));
+        }
+        if !self.fallback_albums.is_empty() {
+            println!(
+                "{} albums were copied in a fallback file type:",
+                self.fallback_albums.len()
+            );
+            self.fallback_albums.iter().for_each(|a| println!("  {a}"));
+        }
+    }
+}
+
+/// exit code for errors
+const EXIT_ERROR: i32 = 1;
+/// exit code of commands like Check and Diff that ran successfully, but found issues
+const EXIT_ISSUES_FOUND: i32 = 2;
+
+/// returned by commands that report issues, e.g. Check. Results in [`EXIT_ISSUES_FOUND`]
+#[derive(Debug)]
+struct IssuesFound(usize);
+
+impl Display for IssuesFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Found {} issues", self.0)
+    }
+}
+
+impl std::error::Error for IssuesFound {}
+
+/// set on Ctrl-C, see [`install_cancel_handler`]
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// whether the user pressed Ctrl-C. Long running commands stop before the next album or track
+fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// makes Ctrl-C stop the current command after it removed its partial output instead of
+/// killing morg. A second Ctrl-C exits immediately
+fn install_cancel_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_ERROR);
+        }
+        println!("Interrupted, stopping after cleaning up. Press Ctrl-C again to exit immediately");
+    })
+    .context("Failed to set Ctrl-C handler")
+}
+
+/// returned when an operation stopped because of Ctrl-C, see [`cancelled`]
+#[derive(Debug)]
+struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Interrupted by Ctrl-C")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
 fn main() {
     let res = run();
-    if res.is_err() {
-        println!("ERROR: {res:?}");
+    let skipped = album::take_skipped_dirs();
+    if !skipped.is_empty() {
+        println!(
+            "WARNING: {} directories could not be read and were skipped:",
+            skipped.len()
+        );
+        skipped.iter().for_each(|d| println!("  {d:?}"));
+    }
+    if let Err(e) = res {
+        if let Some(issues) = e.downcast_ref::<IssuesFound>() {
+            println!("{issues}");
+            std::process::exit(EXIT_ISSUES_FOUND);
+        }
+        println!("ERROR: {e:?}");
+        std::process::exit(EXIT_ERROR);
     }
 }
 
@@ -228,12 +1349,224 @@ fn run() -> Result<()> {
                         .push((Destination::ADBDest, ft, allow_any));
                     config.write()?;
                 }
-                PrintFile => {
-                    println!("{:?}", DirConfig::config_file())
-                }
-            }
-            Ok(())
-        }
+                SetFileTypeSubdirs { directory, enabled } => {
+                    let mut config = DirConfig::read()?;
+                    if !config
+                        .destinations
+                        .iter()
+                        .any(|(d, _, _)| *d == Destination::PathDest(directory.clone()))
+                    {
+                        println!("WARNING: {directory:?} is not a configured destination");
+                    }
+                    config
+                        .file_type_subdir_destinations
+                        .retain(|d| *d != directory);
+                    if enabled {
+                        config.file_type_subdir_destinations.push(directory);
+                    }
+                    config.write()?;
+                }
+                SetArtistFromTags { enabled } => {
+                    let mut config = DirConfig::read()?;
+                    config.artist_from_tags = enabled;
+                    config.write()?;
+                }
+                AddExclude { pattern } => {
+                    if let Err(e) = glob::Pattern::new(&pattern) {
+                        bail!("Invalid exclude pattern {pattern:?}: {e}");
+                    }
+                    let mut config = DirConfig::read()?;
+                    if !config.exclude_patterns.contains(&pattern) {
+                        config.exclude_patterns.push(pattern);
+                    }
+                    config.write()?;
+                }
+                SetAdbPushRetries { retries } => {
+                    let mut config = DirConfig::read()?;
+                    config.adb_push_retries = retries;
+                    config.write()?;
+                }
+                SetAdbMusicDir { path } => {
+                    let mut config = DirConfig::read()?;
+                    config.adb_music_dir = path;
+                    config.write()?;
+                }
+                SetFfmpegPath { path } => {
+                    let mut config = DirConfig::read()?;
+                    config.ffmpeg_path = Some(path);
+                    config.write()?;
+                }
+                SetFfmpegExtraArgs { args } => {
+                    let mut config = DirConfig::read()?;
+                    config.ffmpeg_extra_args = args;
+                    config.write()?;
+                }
+                SetCacheTtl { days } => {
+                    let mut config = DirConfig::read()?;
+                    config.cache_ttl_days = days;
+                    config.write()?;
+                }
+                SetSourcePriority { file_types } => {
+                    let mut config = DirConfig::read()?;
+                    config.conversion_source_priority = file_types;
+                    config.write()?;
+                }
+                SetFallbackPriority { file_types } => {
+                    let mut config = DirConfig::read()?;
+                    config.fallback_priority = file_types;
+                    config.write()?;
+                }
+                SetMp3Quality { bitrate, vbr } => {
+                    let mut config = DirConfig::read()?;
+                    config.mp3_quality = match (bitrate, vbr) {
+                        (Some(kbps), _) => Mp3Quality::Cbr(kbps),
+                        (None, Some(level)) => Mp3Quality::Vbr(level),
+                        (None, None) => Mp3Quality::default(),
+                    };
+                    config.write()?;
+                }
+                SetGaplessMp3 { enabled } => {
+                    let mut config = DirConfig::read()?;
+                    config.gapless_mp3 = enabled;
+                    config.write()?;
+                }
+                SetLamePath { path } => {
+                    let mut config = DirConfig::read()?;
+                    config.lame_path = Some(path);
+                    config.write()?;
+                }
+                SetId3Version { version } => {
+                    let mut config = DirConfig::read()?;
+                    config.id3_version = version;
+                    config.write()?;
+                }
+                SetDirLayout { layout } => {
+                    let mut config = DirConfig::read()?;
+                    config.dir_layout = layout;
+                    config.write()?;
+                }
+                SetSinglesDir { name } => {
+                    let mut config = DirConfig::read()?;
+                    config.singles_dir = name;
+                    config.write()?;
+                }
+                SetFiletypeBrackets { brackets } => {
+                    if let Some(b) = brackets.iter().find(|b| bracket_pair(b).is_none()) {
+                        bail!("{b:?} is not an opening and a closing bracket like `[]`");
+                    }
+                    let mut config = DirConfig::read()?;
+                    config.filetype_brackets = if brackets.is_empty() {
+                        default_filetype_brackets()
+                    } else {
+                        brackets
+                    };
+                    config.write()?;
+                }
+                SetMaxSize {
+                    dir,
+                    adb,
+                    max_size_bytes,
+                } => {
+                    let dest = match dir {
+                        Some(dir) if !adb => Destination::PathDest(dir),
+                        _ => Destination::ADBDest,
+                    };
+                    let mut config = DirConfig::read()?;
+                    if !config.destinations.iter().any(|(d, _, _)| *d == dest) {
+                        println!("WARNING: {dest} is not a configured destination");
+                    }
+                    config.destination_max_sizes.retain(|(d, _)| *d != dest);
+                    if let Some(size) = max_size_bytes {
+                        config.destination_max_sizes.push((dest, size));
+                    }
+                    config.write()?;
+                }
+                SetQuotaOrder { order } => {
+                    let mut config = DirConfig::read()?;
+                    config.quota_order = order;
+                    config.write()?;
+                }
+                SetConversionDir { path } => {
+                    let mut config = DirConfig::read()?;
+                    config.conversion_dir = path;
+                    config.write()?;
+                }
+                SetUseTrash { use_trash } => {
+                    let mut config = DirConfig::read()?;
+                    config.use_trash = use_trash;
+                    config.write()?;
+                }
+                SetProxy { url } => {
+                    let mut config = DirConfig::read()?;
+                    config.proxy = url;
+                    config.write()?;
+                }
+                SetFileNameRules { rules } => {
+                    let mut config = DirConfig::read()?;
+                    config.file_name_rules = rules;
+                    config.write()?;
+                }
+                SetCoverFileNames { names } => {
+                    let mut config = DirConfig::read()?;
+                    config.cover_file_names = names;
+                    config.write()?;
+                }
+                SetAuxFileExtensions { extensions } => {
+                    let mut config = DirConfig::read()?;
+                    config.aux_file_extensions = extensions;
+                    config.write()?;
+                }
+                SetJunkPatterns { patterns } => {
+                    if let Some((p, e)) = patterns
+                        .iter()
+                        .find_map(|p| glob::Pattern::new(p).err().map(|e| (p, e)))
+                    {
+                        bail!("Invalid junk pattern {p:?}: {e}");
+                    }
+                    let mut config = DirConfig::read()?;
+                    config.junk_patterns = patterns;
+                    config.write()?;
+                }
+                SetTitleCleanupPatterns { patterns } => {
+                    if let Some((p, e)) = patterns
+                        .iter()
+                        .find_map(|p| Regex::new(p).err().map(|e| (p, e)))
+                    {
+                        bail!("Invalid title cleanup pattern {p:?}: {e}");
+                    }
+                    let mut config = DirConfig::read()?;
+                    config.title_cleanup_patterns = patterns;
+                    config.write()?;
+                }
+                SetLayoutTemplate { template } => {
+                    let mut config = DirConfig::read()?;
+                    config.layout_template = template;
+                    config.write()?;
+                }
+                SetDiscogsFilters {
+                    format,
+                    description,
+                } => {
+                    let mut config = DirConfig::read()?;
+                    config.discogs_filters = DiscogsFilters {
+                        format,
+                        description,
+                    };
+                    config.write()?;
+                }
+                Validate => {
+                    let config = DirConfig::read()?;
+                    if !config.validate() {
+                        bail!("Config validation failed!");
+                    }
+                    println!("Config is valid.");
+                }
+                PrintFile => {
+                    println!("{:?}", DirConfig::config_file())
+                }
+            }
+            Ok(())
+        }
         Commands::Test => {
             /*let mut album = Album::new(
                 "Who Made Who".to_string(),
@@ -244,7 +1577,7 @@ fn run() -> Result<()> {
                 "Who Made Who".to_string(),
                 "AC/DC".to_string(),
             );
-            download_cover_file(&mut album)?;
+            download_cover_file(&album, &mut MusicInfoCache::load(false, None)?)?;
             //set_music_info(&album)?;*/
             let res = path_to_details(
                 PathBuf::from_str(
@@ -255,43 +1588,75 @@ fn run() -> Result<()> {
             println!("{res:?}");
             Ok(())
         }
-        Commands::Sync => {
+        Commands::Sync {
+            since,
+            strict,
+            output_dir,
+            no_tag_scan,
+            fail_fast,
+            confirm_deletes,
+            only,
+            ignore_editions,
+        } => {
+            install_cancel_handler()?;
+            let mut config = DirConfig::read()?;
+            if output_dir.is_some() {
+                config.conversion_dir = output_dir;
+            }
+            let options = SyncOptions {
+                skip_tag_scan: no_tag_scan,
+                fail_fast,
+                confirm_deletes,
+                only,
+                ignore_editions,
+                state_dir: Some(data_dir()?),
+            };
+            let total = sync_all(&config, &options, since, strict);
+            total.print("Total");
+            if strict && !total.unavailable_albums.is_empty() {
+                println!("===== Albums not available in the desired file type =====");
+                total
+                    .unavailable_albums
+                    .iter()
+                    .for_each(|a| println!("{a}"));
+            }
+            if cancelled() {
+                bail!("The sync was interrupted, run it again to resume");
+            }
+            if total.errors > 0 {
+                bail!("Sync finished with {} errors", total.errors);
+            }
+            Ok(())
+        }
+        Commands::Status { verbose } => {
             let config = DirConfig::read()?;
-            let mut destinations = config.destinations.clone();
-            // sync to sources first
-            destinations.sort_by_key(|d| match &d.0 {
-                Destination::PathDest(p) => {
-                    if config.source_directories.contains(p) {
-                        0
-                    } else {
-                        1
-                    }
-                }
-                Destination::ADBDest => 1,
-            });
-
-            destinations
-                .iter()
-                .for_each(|(dest, ft, allow_any)| match dest {
-                    Destination::PathDest(p) => {
-                        println!("===== Syncing to dir {p:?} =====");
-                        let mut loc = DirLocation::new(p.to_path_buf());
-                        sync_to_loc(&mut loc, ft, &config, *allow_any);
-                    }
-                    Destination::ADBDest => {
-                        println!("===== Syncing to ADB devce =====");
-                        let loc = AdbLocation::new();
-                        if let Ok(mut loc) = loc {
-                            sync_to_loc(&mut loc, ft, &config, *allow_any);
-                        } else {
-                            println!("{loc:?}\nSkipping this location.");
-                        }
-                    }
-                });
+            let total = destinations_status(&config, verbose)?;
+            if total.issues() > 0 {
+                return Err(IssuesFound(total.issues()).into());
+            }
             Ok(())
         }
-        Commands::Check => {
+        Commands::SyncCovers => {
             let config = DirConfig::read()?;
+            let errors = sync_covers(&config);
+            if errors > 0 {
+                bail!("Syncing the cover files finished with {errors} errors");
+            }
+            Ok(())
+        }
+        Commands::Watch { debounce } => {
+            let config = DirConfig::read()?;
+            watch_sources(&config, Duration::from_secs(debounce))
+        }
+        Commands::Check {
+            deep,
+            fuzzy,
+            verify_format,
+            check_covers,
+            no_tag_scan,
+        } => {
+            let config = DirConfig::read()?;
+            let title_rules = config.title_rules()?;
             let dirs_to_handle: HashSet<PathBuf> = config
                 .source_directories
                 .iter()
@@ -304,31 +1669,37 @@ fn run() -> Result<()> {
                 }))
                 .cloned()
                 .collect();
+            let mut issues = 0;
             let mut all_albums = Vec::new();
             let mut albums_by_root = HashMap::new();
+            // one cache for all albums, so that the rate limit is tracked across them
+            let mut cache = config.load_music_info(false, args.offline)?;
             // check whether an album path is contained in another one
             dirs_to_handle.iter().for_each(|dir| {
-                let albums = albums_in_dir(dir);
+                let albums =
+                    albums_in_dir_modified_since(
+                    dir,
+                    &config.exclude_patterns,
+                    None,
+                    !no_tag_scan,
+                    &config.source_layout(),
+                );
                 albums_by_root.insert(dir.clone(), albums.clone());
                 albums.iter().enumerate().for_each(|(i, a)| {
-                    all_albums.push(a.clone());
-
-                    let mut cache = MusicInfoCache::load(false).unwrap();
+                    all_albums.push((dir.clone(), a.clone()));
 
                     if let Ok(album_info) = cache.get_album_info(a) {
                         a.tracks.iter().for_each(|t| {
-                            let track_info = parse_track_info(t, a, &album_info);
-                            if let Some(tn) = track_info.track_number {
-                                let tn = tn.to_string();
-                                if track_info.title.starts_with(&tn)
-                                    || track_info.title.starts_with(&format!("0{tn}"))
-                                {
-                                    println!(
-                                        "Track '{}' (file '{t}') of album '{}' starts with its track number '{tn}'",
-                                        track_info.title,
-                                        a.overview()
-                                    )
-                                }
+                            let track_info = parse_track_info(t, a, &album_info, &title_rules);
+                            if let Some(tn) = track_info.track_number
+                                && strip_track_number(&track_info.title, tn).is_some()
+                            {
+                                issues += 1;
+                                println!(
+                                    "Track '{}' (file '{t}') of album '{}' starts with its track number '{tn}'",
+                                    track_info.title,
+                                    a.overview()
+                                )
                             }
                         });
                     }
@@ -337,15 +1708,134 @@ fn run() -> Result<()> {
                         .enumerate()
                         .find(|(j, a2)| i != *j && a.dir_path.starts_with(&a2.dir_path))
                     {
+                        issues += 1;
                         println!(
                             "Album {} is in a subdir of album {}",
                             a.overview(),
                             a2.overview()
                         );
                     }
+                    if deep {
+                        let mut properties = HashSet::new();
+                        a.tracks.iter().for_each(|t| {
+                            match get_audio_properties(&a.dir_path.join(t)) {
+                                Ok(p) => {
+                                    properties.insert(p);
+                                }
+                                Err(e) => {
+                                    issues += 1;
+                                    println!(
+                                        "Track '{t}' of album {} could not be read (corrupt file?): {e:?}",
+                                        a.overview()
+                                    );
+                                }
+                            }
+                        });
+                        if properties.len() > 1 {
+                            issues += 1;
+                            println!(
+                                "Album {} contains tracks with different sample rates or bit depths: {properties:?}",
+                                a.overview()
+                            );
+                        }
+                    }
+                    if verify_format {
+                        a.tracks.iter().for_each(|t| {
+                            let ext_ft = Path::new(t)
+                                .extension()
+                                .and_then(|ext| FileType::from_extension(&ext.to_string_lossy()));
+                            match detect_file_type(&a.dir_path.join(t)) {
+                                Ok(Some(ft)) if Some(&ft) != ext_ft.as_ref() => {
+                                    issues += 1;
+                                    println!(
+                                        "Track '{t}' of album {} is a {ft} file, but has a different extension",
+                                        a.overview()
+                                    );
+                                }
+                                Ok(_) => {}
+                                Err(e) => {
+                                    issues += 1;
+                                    println!(
+                                        "Failed to detect the format of track '{t}' of album {}: {e:?}",
+                                        a.overview()
+                                    );
+                                }
+                            }
+                        });
+                    }
+                    if let Some(artists) = inconsistent_album_artists(a) {
+                        issues += 1;
+                        println!(
+                            "Album {} has tracks with different album artist tags: {artists} (using {:?})",
+                            a.overview(),
+                            a.artist
+                        );
+                    }
+                    if a.cover_files.len() > 1 {
+                        issues += 1;
+                        println!(
+                            "Album {} has multiple cover candidates: {:?} (using {:?})",
+                            a.overview(),
+                            a.cover_files,
+                            a.primary_cover(&config.cover_file_names)
+                        );
+                    }
+                    if check_covers {
+                        if !a.has_named_cover(&config.cover_file_names) {
+                            issues += 1;
+                            println!(
+                                "Album {} has no cover file named like one of {:?}",
+                                a.overview(),
+                                config.cover_file_names
+                            );
+                        }
+                        a.cover_files.iter().for_each(|cf| match cover_problems(cf) {
+                            Ok(problems) => problems.iter().for_each(|p| {
+                                issues += 1;
+                                println!(
+                                    "Cover {cf:?} of album {}: {p} (replace it with fill-in-cover-files --overwrite)",
+                                    a.overview()
+                                );
+                            }),
+                            Err(e) => {
+                                issues += 1;
+                                println!("Cover {cf:?} of album {}: {e:?}", a.overview());
+                            }
+                        });
+                    }
+                    if let [track] = &a.tracks[..]
+                        && let Some(cue_file) = a.cue_files().first()
+                    {
+                        match read_cue(cue_file) {
+                            Ok(sheet) => {
+                                println!(
+                                    "Album {} is a single file with {} tracks in {cue_file:?}",
+                                    a.overview(),
+                                    sheet.tracks.len()
+                                );
+                                if sheet.file.as_ref().is_some_and(|f| f != track) {
+                                    issues += 1;
+                                    println!(
+                                        "  The cuesheet refers to {:?} instead of {track:?}",
+                                        sheet.file
+                                    );
+                                }
+                                sheet.problems().iter().for_each(|p| {
+                                    issues += 1;
+                                    println!("  Cuesheet problem: {p}");
+                                });
+                            }
+                            Err(e) => {
+                                issues += 1;
+                                println!("Failed to read cuesheet of {}: {e:?}", a.overview());
+                            }
+                        }
+                    }
                     if a.tracks.is_empty() {
+                        issues += 1;
                         println!("Album {} does not contain any tracks!", a.overview());
                     } else if a.file_type().is_none() {
+                        issues += 1;
                         println!(
                             "Album {} contains tracks with multiple filetypes",
                             a.overview()
@@ -355,23 +1845,25 @@ fn run() -> Result<()> {
             });
 
             // check for albums with the same contents, but different key
-            all_albums
+            let with_tracks: Vec<&(PathBuf, Album)> = all_albums
                 .iter()
-                .filter(|a| !a.tracks.is_empty())
-                .enumerate()
-                .for_each(|(i, a1)| {
-                    all_albums[i + 1..]
-                        .iter()
-                        .filter(|a2| a1.key() != a2.key() && a1.tracks == a2.tracks)
-                        .for_each(|a2| {
-                            println!(
-                                "Found duplicate albums: {} ({}) and {} ({})",
-                                a1.overview(),
-                                a1.key(),
-                                a2.overview(),
-                                a2.key()
-                            )
-                        });
+                .filter(|(_, a)| !a.tracks.is_empty())
+                .collect();
+            let albums: Vec<&Album> = with_tracks.iter().map(|(_, a)| a).collect();
+            duplicate_candidates(&albums, fuzzy)
+                .into_iter()
+                .for_each(|(i, j)| {
+                    let ((root1, a1), (root2, a2)) = (with_tracks[i], with_tracks[j]);
+                    if let Some(reason) = a1.duplicate_reason(a2, fuzzy, root1 == root2) {
+                        issues += 1;
+                        println!(
+                            "Found duplicate albums ({reason}): {} ({}) and {} ({})",
+                            a1.overview(),
+                            a1.key(),
+                            a2.overview(),
+                            a2.key()
+                        )
+                    }
                 });
 
             // check for symlinks in source directories
@@ -385,6 +1877,7 @@ fn run() -> Result<()> {
                             && let Ok(ft) = child.file_type()
                         {
                             if ft.is_symlink() {
+                                issues += 1;
                                 println!("{child:?} is a symlink")
                             } else if ft.is_dir() {
                                 dirs_to_handle.push(child.path().to_path_buf());
@@ -407,6 +1900,7 @@ fn run() -> Result<()> {
                             .map(|c| c.as_os_str().to_string_lossy().to_string())
                             .collect();
                         if comps.len() > 2 {
+                            issues += 1;
                             println!(
                                 "The directory of album {} is nested too deeply.",
                                 a.overview()
@@ -416,343 +1910,3447 @@ fn run() -> Result<()> {
                 })
             });
 
+            if issues > 0 {
+                return Err(IssuesFound(issues).into());
+            }
             Ok(())
         }
-        Commands::CleanUpTags { dir, no_cache } => {
+        Commands::CleanUpTags {
+            dir,
+            no_cache,
+            sort_tags,
+            album,
+            limit,
+            sidecar,
+            fail_fast,
+            preview,
+        } => {
+            let config = DirConfig::read()?;
+            // cached info can be used without keys
+            if !args.offline {
+                require_keys()?;
+            }
             println!("Loading albums...");
-            let albums = albums_in_dir(&dir);
+            let mut albums = filter_albums(
+                albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout()),
+                &album,
+            );
+            albums.truncate(limit.unwrap_or(usize::MAX));
             println!("Loading cache...");
-            let mut cache = MusicInfoCache::load(no_cache)?;
+            let mut cache = config.load_music_info(no_cache, args.offline)?;
+            let title_rules = config.title_rules()?;
             println!("Setting tags...");
-            albums.iter().progress().for_each(|a| {
+            let mut failed = 0;
+            for a in albums.iter().progress() {
                 let info = cache.get_album_info(a);
-                if let Ok(info) = info {
-                    let success = set_tags(a, &info);
-                    if success.is_err() {
-                        println!("Failed to set album tags for {}: {success:?}", a.overview());
+                let success = if let Ok(info) = info {
+                    if sidecar {
+                        write_sidecar(a, &info, &title_rules)
+                    } else {
+                        set_tags(
+                            a,
+                            &info,
+                            sort_tags,
+                            config.id3_version,
+                            &title_rules,
+                            preview,
+                        )
                     }
                 } else {
                     println!("Failed to get album info: {info:?}; Falling back to album...");
+                    // keep the year that is already present in the tags
                     let album_info = AlbumInfo {
                         artist: a.artist.clone(),
                         title: a.title.clone(),
-                        year: None,
+                        year: year_from_tags(a),
                     };
-                    let success = set_missing_tags(a, &album_info);
-                    if success.is_err() {
-                        println!("Failed to set album tags for {}: {success:?}", a.overview());
+                    if sidecar {
+                        write_sidecar(a, &album_info, &title_rules)
+                    } else {
+                        set_missing_tags(
+                            a,
+                            &album_info,
+                            sort_tags,
+                            config.id3_version,
+                            &title_rules,
+                            preview,
+                        )
+                    }
+                };
+                if let Err(e) = success {
+                    if fail_fast {
+                        return Err(
+                            e.context(format!("Failed to set album tags for {}", a.overview()))
+                        );
                     }
+                    println!("Failed to set album tags for {}: {e:?}", a.overview());
+                    failed += 1;
                 }
-            });
+            }
+            if failed > 0 {
+                bail!("Failed to set album tags for {failed} albums");
+            }
             Ok(())
         }
-        Commands::FillInCoverFiles { dir, overwrite } => {
-            let mut albums = albums_in_dir(&dir);
-            albums
-                .iter_mut()
+        Commands::RepairTags { dir } => {
+            let config = DirConfig::read()?;
+            let title_rules = config.title_rules()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mut unparsed = vec![];
+            let mut failed = 0;
+            for a in albums.iter().progress() {
+                let album_info = AlbumInfo {
+                    artist: a.artist.clone(),
+                    title: a.title.clone(),
+                    year: year_from_tags(a),
+                };
+                match repair_tags(a, &album_info, config.id3_version, &title_rules) {
+                    Ok(tracks) => {
+                        unparsed.extend(tracks.iter().map(|t| a.dir_path.join(t)));
+                    }
+                    Err(e) => {
+                        println!("Failed to repair the tags of {}: {e:?}", a.overview());
+                        failed += 1;
+                    }
+                }
+            }
+            if !unparsed.is_empty() {
+                println!("===== Files without a track number or title in their name =====");
+                unparsed.iter().for_each(|f| println!("{f:?}"));
+            }
+            if failed > 0 {
+                bail!("Failed to repair the tags of {failed} albums");
+            }
+            Ok(())
+        }
+        Commands::FillInCoverFiles {
+            dir,
+            overwrite,
+            album,
+            limit,
+            fail_fast,
+        } => {
+            if args.offline {
+                println!("Skipping cover downloads in offline mode");
+                return Ok(());
+            }
+            let config = DirConfig::read()?;
+            require_keys()?;
+            let albums = filter_albums(
+                albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout()),
+                &album,
+            );
+            let mut cache = config.load_music_info(false, args.offline)?;
+            let mut location = DirLocation::new(
+                dir.clone(),
+                config.exclude_patterns.clone(),
+                config.use_trash,
+                true,
+                config.file_name_rules,
+            );
+            let mut failed = 0;
+            for a in albums
+                .iter()
                 .filter(|a| overwrite || a.cover_files.is_empty())
-                .for_each(|a| {
-                    let res = download_cover_file(a);
-                    if let Ok(limit) = res {
-                        println!("Downloaded cover file for {}", a.overview());
-                        if limit <= 1 {
-                            println!("Waiting 60s to avoid rate limit...");
-
-                            std::thread::sleep(time::Duration::from_secs(60));
-                        }
-                    } else {
-                        println!("Failed to download cover file: {res:?}");
+                .take(limit.unwrap_or(usize::MAX))
+            {
+                match download_cover_file(&mut location, a, &mut cache, &config.cover_file_names) {
+                    Ok(()) => println!("Downloaded cover file for {}", a.overview()),
+                    Err(e) if fail_fast => return Err(e),
+                    Err(e) => {
+                        println!("Failed to download cover file: {e:?}");
+                        failed += 1;
                     }
-                });
+                }
+            }
+            if failed > 0 {
+                bail!("Failed to download cover files for {failed} albums");
+            }
             Ok(())
         }
-        Commands::Fix => {
-            let config = DirConfig::read().unwrap();
-            // check for symlinks in source directories
-            let mut pos = 0;
-            let mut dirs_to_handle: Vec<PathBuf> = config.source_directories.clone();
-            while pos < dirs_to_handle.len() {
-                let dir = &dirs_to_handle[pos];
-                if let Ok(items) = read_dir(dir) {
-                    for child in items {
-                        if let Ok(child) = child
-                            && let Ok(ft) = child.file_type()
-                        {
-                            if ft.is_symlink() {
-                                println!("{child:?} is a symlink");
-                                if let Ok(sl) = child.path().read_link() {
-                                    println!("Deleting symlink {:?}", child.path());
-                                    let _ = std::fs::remove_dir_all(child.path()).context("");
-                                    let copy_options = CopyOptions::new();
-
-                                    fs_extra::copy_items(&[&sl], child.path(), &copy_options)?;
-                                }
-                            } else if ft.is_dir() {
-                                dirs_to_handle.push(child.path().to_path_buf());
-                            }
+        Commands::DedupeCovers {
+            dir,
+            dry_run,
+            embed,
+        } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mut failed = 0;
+            for a in albums.iter().filter(|a| a.cover_files.len() > 1) {
+                let Some(keep) = a.primary_cover(&config.cover_file_names).cloned() else {
+                    continue;
+                };
+                let remove: Vec<&PathBuf> =
+                    a.cover_files.iter().filter(|cf| **cf != keep).collect();
+                println!("{}: keeping {keep:?}, removing {remove:?}", a.overview());
+                if dry_run {
+                    continue;
+                }
+                for cf in remove {
+                    if let Err(e) = remove_path(cf, config.use_trash) {
+                        println!("{e:?}");
+                        failed += 1;
+                    }
+                }
+                if embed {
+                    let mut kept = a.clone();
+                    kept.cover_files = vec![keep];
+                    match embed_cover(&kept, &config.cover_file_names, false) {
+                        Ok(n) => println!("Embedded cover into {n} tracks of {}", a.overview()),
+                        Err(e) => {
+                            println!("Failed to embed cover for {}: {e:?}", a.overview());
+                            failed += 1;
                         }
                     }
                 }
-                pos += 1;
             }
-
+            if failed > 0 {
+                bail!("{failed} cover files could not be removed or embedded");
+            }
             Ok(())
         }
-        Commands::Diff { src, dst } => {
-            let src_albums = albums_in_dir(&src);
-            let dst_albums: HashMap<String, Album> = albums_in_dir(&dst)
-                .into_iter()
-                .map(|a| (a.key(), a))
-                .collect();
-            let mut missing_keys = HashSet::new();
+        Commands::CleanJunk { dir, dry_run } => {
+            let config = DirConfig::read()?;
+            let patterns = config
+                .junk_patterns
+                .iter()
+                .map(|p| glob::Pattern::new(p).context(format!("Invalid junk pattern {p:?}")))
+                .collect::<Result<Vec<_>>>()?;
+            let files = junk_files(
+                &dir,
+                &config.exclude_patterns,
+                &patterns,
+                &config.aux_file_extensions,
+            );
+            let mut failed = 0;
+            for f in &files {
+                if dry_run {
+                    println!("Would remove {f:?}");
+                    continue;
+                }
+                match remove_path(f, config.use_trash) {
+                    Ok(()) => println!("Removed {f:?}"),
+                    Err(e) => {
+                        println!("{e:?}");
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                bail!("Failed to remove {failed} junk files");
+            }
+            println!("Found {} junk files", files.len());
+            Ok(())
+        }
+        Commands::EmbedCovers { dir, overwrite } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mut without_cover = vec![];
+            albums.iter().for_each(|a| {
+                if a.cover_files.is_empty() {
+                    without_cover.push(a.overview());
+                    return;
+                }
+                match embed_cover(a, &config.cover_file_names, overwrite) {
+                    Ok(n) => println!("Embedded cover into {n} tracks of {}", a.overview()),
+                    Err(e) => println!("Failed to embed cover for {}: {e:?}", a.overview()),
+                }
+            });
+            if !without_cover.is_empty() {
+                println!("===== Albums without a cover file =====");
+                without_cover.iter().for_each(|a| println!("{a}"));
+            }
+            Ok(())
+        }
+        Commands::UpgradeCovers { dir, min_size } => {
+            if args.offline {
+                bail!("Covers cannot be downloaded in offline mode");
+            }
+            let config = DirConfig::read()?;
+            require_keys()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mut cache = config.load_music_info(false, args.offline)?;
+            let mut upgraded = 0;
+            let mut failed = 0;
+            for a in &albums {
+                let Some(current) = a
+                    .tracks
+                    .first()
+                    .and_then(|t| embedded_cover_dimensions(&a.dir_path.join(t)))
+                else {
+                    println!("Skipping {}: no readable embedded cover", a.overview());
+                    continue;
+                };
+                if !cover_below_min_size(current, min_size) {
+                    continue;
+                }
+                let res = fetch_cover_bytes(a, &mut cache).and_then(|(name, bytes)| {
+                    let new = image_dimensions(&bytes)
+                        .context(format!("Failed to read the dimensions of {name}"))?;
+                    if new.0.min(new.1) <= current.0.min(current.1) {
+                        println!(
+                            "Keeping the {}x{} cover of {}, the discogs cover is {}x{}",
+                            current.0,
+                            current.1,
+                            a.overview(),
+                            new.0,
+                            new.1
+                        );
+                        return Ok(false);
+                    }
+                    let n = embed_cover_bytes(a, &name, &bytes)?;
+                    println!(
+                        "Replaced the {}x{} cover of {} by a {}x{} one in {n} tracks",
+                        current.0,
+                        current.1,
+                        a.overview(),
+                        new.0,
+                        new.1
+                    );
+                    Ok(true)
+                });
+                match res {
+                    Ok(true) => upgraded += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        println!("Failed to upgrade the cover of {}: {e:?}", a.overview());
+                        failed += 1;
+                    }
+                }
+            }
+            println!("Upgraded the covers of {upgraded} albums");
+            if failed > 0 {
+                bail!("Failed to upgrade the covers of {failed} albums");
+            }
+            Ok(())
+        }
+        Commands::FetchLyrics { dir, overwrite } => {
+            if args.offline {
+                bail!("Lyrics cannot be fetched in offline mode");
+            }
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mut client = LyricsClient::new(config.proxy.as_deref())?;
+            let mut written = 0;
+            let mut not_found = vec![];
+            for a in &albums {
+                for t in &a.tracks {
+                    let track_path = a.dir_path.join(t);
+                    if !overwrite && read_lyrics(&track_path).is_some() {
+                        continue;
+                    }
+                    let tags = match get_track_tags(&track_path) {
+                        Ok(tags) => tags,
+                        Err(e) => {
+                            println!("Skipping {track_path:?}: {e:?}");
+                            continue;
+                        }
+                    };
+                    let (Some(artist), Some(title)) = (tags.artist(), tags.title()) else {
+                        println!("Skipping {track_path:?}: it has no artist or title tag");
+                        continue;
+                    };
+                    let lyrics =
+                        client.fetch(artist, title, tags.album_title(), get_duration(&track_path));
+                    match lyrics {
+                        Ok(Some(lyrics)) => match write_lyrics(&track_path, &lyrics) {
+                            Ok(()) => written += 1,
+                            Err(e) => println!("{e:?}"),
+                        },
+                        Ok(None) => not_found.push(track_path),
+                        Err(e) => println!("Failed to fetch the lyrics of {track_path:?}: {e:?}"),
+                    }
+                }
+            }
+            println!("Wrote lyrics to {written} tracks");
+            if !not_found.is_empty() {
+                println!("===== Tracks without lyrics on lrclib =====");
+                not_found.iter().for_each(|t| println!("{t:?}"));
+            }
+            Ok(())
+        }
+        Commands::Fix { dry_run } => {
+            let config = DirConfig::read()?;
+            // check for symlinks in source directories
+            let mut failed = 0;
+            let mut pos = 0;
+            let mut dirs_to_handle: Vec<PathBuf> = config.source_directories.clone();
+            while pos < dirs_to_handle.len() {
+                let dir = &dirs_to_handle[pos];
+                if let Ok(items) = read_dir(dir) {
+                    for child in items {
+                        if let Ok(child) = child
+                            && let Ok(ft) = child.file_type()
+                        {
+                            if ft.is_symlink() {
+                                println!("{child:?} is a symlink");
+                                let res = replace_symlink_with_copy(&child.path(), dry_run);
+                                if let Err(e) = res {
+                                    println!("Failed to replace symlink {:?}: {e:?}", child.path());
+                                    failed += 1;
+                                }
+                            } else if ft.is_dir() {
+                                dirs_to_handle.push(child.path().to_path_buf());
+                            }
+                        }
+                    }
+                }
+                pos += 1;
+            }
+            if failed > 0 {
+                bail!("Failed to replace {failed} symlinks");
+            }
+            Ok(())
+        }
+        Commands::Diff {
+            src,
+            dst,
+            reverse,
+            ignore_editions,
+        } => {
+            let config = DirConfig::read()?;
+            let mut src_albums =
+                albums_in_dir(&src, &config.exclude_patterns, &config.source_layout());
+            if config.artist_from_tags {
+                src_albums.iter_mut().for_each(Album::use_tag_artist);
+            }
+            // destination albums are matched the same way sync does
+            let dst_albums = config.destination_albums(&dst);
+            let (src_albums, dst_albums, msg) = if reverse {
+                (dst_albums, src_albums, "Album not in source")
+            } else {
+                (src_albums, dst_albums, "Album missing")
+            };
+            let album_key = if ignore_editions {
+                Album::edition_key
+            } else {
+                Album::normalized_key
+            };
+            let dst_albums: HashMap<String, Album> =
+                dst_albums.into_iter().map(|a| (album_key(&a), a)).collect();
+            let mut missing_keys = HashSet::new();
             src_albums.iter().for_each(|a| {
-                let key = a.key();
+                let key = album_key(a);
                 if !dst_albums.contains_key(&key) && !missing_keys.contains(&key) {
-                    println!("Album missing: {}", a.overview());
+                    println!("{msg}: {}", a.overview());
                     missing_keys.insert(key);
                 }
             });
+            if !missing_keys.is_empty() {
+                return Err(IssuesFound(missing_keys.len()).into());
+            }
+            Ok(())
+        }
+        Commands::Audit { dir, format } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mismatches: Vec<TagMismatch> = albums.iter().flat_map(tag_mismatches).collect();
+            match format {
+                OutputFormat::Text => mismatches.iter().for_each(|m| {
+                    println!(
+                        "{}: folder {} {:?}, tags {:?}",
+                        m.album, m.field, m.folder, m.tags
+                    )
+                }),
+                OutputFormat::Json => {
+                    let entries: Vec<json::JsonValue> = mismatches
+                        .iter()
+                        .map(|m| {
+                            json::object! {
+                                "album": m.album.clone(),
+                                "dir": m.dir.to_string_lossy().to_string(),
+                                "field": m.field,
+                                "folder": m.folder.clone(),
+                                "tags": m.tags.clone(),
+                            }
+                        })
+                        .collect();
+                    println!("{}", json::stringify_pretty(entries, 2));
+                }
+            }
+            if !mismatches.is_empty() {
+                return Err(IssuesFound(mismatches.len()).into());
+            }
+            Ok(())
+        }
+        Commands::LossyOnly { dir, format } => {
+            let config = DirConfig::read()?;
+            let mut albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let lossless_keys: HashSet<String> = config
+                .source_directories
+                .iter()
+                .filter(|sd| **sd != dir)
+                .flat_map(|sd| albums_in_dir(sd, &config.exclude_patterns, &config.source_layout()))
+                .chain(albums.iter().cloned())
+                .filter(|a| a.file_type().is_some_and(|ft| ft.is_lossless()))
+                .map(|a| a.normalized_key())
+                .collect();
+            albums.retain(|a| !lossless_keys.contains(&a.normalized_key()));
+            let lossy_only = lossy_only_albums(&albums);
+            match format {
+                OutputFormat::Text => {
+                    lossy_only.iter().for_each(|(a, formats)| {
+                        println!("{} ({})", a.overview(), formats.join(", "))
+                    });
+                    println!(
+                        "{} albums are only available in lossy formats",
+                        lossy_only.len()
+                    );
+                }
+                OutputFormat::Json => {
+                    let entries: Vec<json::JsonValue> = lossy_only
+                        .iter()
+                        .map(|(a, formats)| {
+                            json::object! {
+                                "artist": a.parsed_artist.clone(),
+                                "title": a.parsed_title.clone(),
+                                "dir": a.dir_path.to_string_lossy().to_string(),
+                                "formats": formats.clone(),
+                            }
+                        })
+                        .collect();
+                    println!("{}", json::stringify_pretty(entries, 2));
+                }
+            }
+            Ok(())
+        }
+        Commands::Doctor => {
+            if !doctor() {
+                bail!("Some requirements are missing!");
+            }
+            println!("Everything required is available.");
+            Ok(())
+        }
+        Commands::DiscogsTest => {
+            if args.offline {
+                bail!("Discogs cannot be tested in offline mode");
+            }
+            let config = DirConfig::read()?;
+            require_keys()?;
+            match test_discogs(config.proxy.as_deref())? {
+                Some(remaining) => println!(
+                    "The discogs keys are valid, {remaining} requests remain in the current minute"
+                ),
+                None => println!("The discogs keys are valid"),
+            }
+            Ok(())
+        }
+        Commands::ClearConversionCache => {
+            let config = DirConfig::read()?;
+            let Some(dir) = config.conversion_dir else {
+                bail!("No conversion dir is configured, see `config set-conversion-dir`");
+            };
+            let mut conversions = ConversionCache::load(&data_dir()?)?;
+            conversions.clear();
+            conversions.store()?;
+            if dir.exists() {
+                std::fs::remove_dir_all(&dir).context(format!("Failed to delete {dir:?}"))?;
+            }
+            println!("Cleared conversion cache {dir:?}");
+            Ok(())
+        }
+        Commands::CacheClear { key } => {
+            let mut cache = MusicInfoCache::load(false, None)?;
+            if let Some(key) = key {
+                if !cache.remove(&key) {
+                    bail!("No cached info for {key:?}");
+                }
+                println!("Removed cached info for {key:?}");
+            } else {
+                let n = cache.clear();
+                println!("Removed {n} cached entries");
+            }
+            cache.store()
+        }
+        Commands::FixTitles { dir, dry_run } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            albums.iter().for_each(|a| {
+                if let Err(e) = fix_track_number_titles(a, dry_run) {
+                    println!("Failed to fix titles of {}: {e:?}", a.overview());
+                }
+            });
+            Ok(())
+        }
+        Commands::StripTags {
+            dir,
+            keep,
+            strip_cover,
+            dry_run,
+        } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mut saved = 0;
+            albums.iter().for_each(|a| {
+                a.tracks.iter().for_each(|t| {
+                    match strip_tags(&a.dir_path.join(t), &keep, strip_cover, dry_run) {
+                        Ok(s) => saved += s,
+                        Err(e) => {
+                            println!("Failed to strip tags of {t:?} in {}: {e:?}", a.overview())
+                        }
+                    }
+                })
+            });
+            if !dry_run {
+                println!("Saved {saved} bytes");
+            }
+            Ok(())
+        }
+        Commands::RenameTracks { dir, dry_run } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            albums.iter().for_each(|a| rename_tracks(a, dry_run));
+            Ok(())
+        }
+        Commands::RenameArtistDirs { dir, dry_run } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            for (src, dst) in artist_dir_moves(&albums) {
+                if dst.exists() {
+                    println!("Skipping {src:?}: {dst:?} already exists");
+                    continue;
+                }
+                println!("MOVE: {src:?} -> {dst:?}");
+                if dry_run {
+                    continue;
+                }
+                if let Some(artist_dir) = dst.parent() {
+                    std::fs::create_dir_all(artist_dir)
+                        .context(format!("Failed to create {artist_dir:?}"))?;
+                }
+                std::fs::rename(&src, &dst)
+                    .context(format!("Failed to move {src:?} to {dst:?}"))?;
+                // the old artist directory is only removed once all of its albums were moved
+                if let Some(old_artist_dir) = src.parent() {
+                    let _ = std::fs::remove_dir(old_artist_dir);
+                }
+            }
+            Ok(())
+        }
+        Commands::MergeDiscs { dir, dry_run, yes } => {
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let keys: HashSet<String> = albums.iter().map(|a| a.normalized_key()).collect();
+            // discs of the same album are next to each other
+            let mut groups: HashMap<(PathBuf, String), Vec<(u16, &Album)>> = HashMap::new();
+            albums.iter().for_each(|a| {
+                if let Some((base, disc)) = disc_suffix(&a.parsed_title) {
+                    let parent = a.dir_path.parent().unwrap_or(&a.dir_path).to_path_buf();
+                    let base_album = Album::new(
+                        base.clone(),
+                        a.artist.clone(),
+                        vec![],
+                        parent.clone(),
+                        vec![],
+                        base,
+                        a.parsed_artist.clone(),
+                    );
+                    groups
+                        .entry((parent, base_album.normalized_key()))
+                        .or_default()
+                        .push((disc, a));
+                }
+            });
+            let mut groups: Vec<_> = groups
+                .into_iter()
+                .filter(|(_, discs)| discs.len() > 1)
+                .collect();
+            groups.sort_by(|a, b| a.0.1.cmp(&b.0.1));
+            for ((_, key), mut discs) in groups {
+                discs.sort_by_key(|(disc, _)| *disc);
+                let overview = discs
+                    .iter()
+                    .map(|(_, a)| format!("{:?}", a.dir_path))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                let target = match merge_target(&discs) {
+                    Ok(target) if keys.contains(&key) => {
+                        println!("Skipping {overview}: there already is an album {target:?}");
+                        continue;
+                    }
+                    Ok(target) => target,
+                    Err(e) => {
+                        println!("Skipping {overview}: {e}");
+                        continue;
+                    }
+                };
+                println!("Merging {overview} into {target:?}");
+                if !dry_run && !yes && !confirm("Merge these albums?") {
+                    continue;
+                }
+                if let Err(e) = merge_discs(&discs, &target, dry_run) {
+                    println!("Failed to merge {overview}: {e:?}");
+                }
+            }
+            Ok(())
+        }
+        Commands::SplitCue { dir, dry_run } => {
+            install_cancel_handler()?;
+            let config = DirConfig::read()?;
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            albums.iter().take_while(|_| !cancelled()).for_each(|a| {
+                let cue_files = a.cue_files();
+                let (Some(cue_file), [_]) = (cue_files.first(), &a.tracks[..]) else {
+                    return;
+                };
+                if let Err(e) = split_cue(a, cue_file, &config, dry_run) {
+                    println!("Failed to split {}: {e:?}", a.overview());
+                }
+            });
+            if cancelled() {
+                bail!(Cancelled);
+            }
+            Ok(())
+        }
+        Commands::Convert {
+            src,
+            dest_ft,
+            out_dir,
+        } => {
+            install_cancel_handler()?;
+            let config = DirConfig::read()?;
+            let mut conversions = load_conversion_cache(Some(&data_dir()?));
+            let albums = albums_in_dir(&src, &config.exclude_patterns, &config.source_layout());
+            let mut lossless_skipped = vec![];
+            albums
+                .iter()
+                .take_while(|_| !cancelled())
+                .for_each(|a| match a.file_type() {
+                    None => println!(
+                        "Skipping {}: failed to determine its file type",
+                        a.overview()
+                    ),
+                    Some(ft) if ft == dest_ft => {
+                        println!("Skipping {}: it already is {dest_ft}", a.overview())
+                    }
+                    Some(ft) if !ft.can_convert_to(&dest_ft) => lossless_skipped.push(a.overview()),
+                    Some(_) => {
+                        match convert_src_album(&out_dir, a, &dest_ft, &config, &mut conversions) {
+                            Ok(converted) => println!("Converted album: {}", converted.overview()),
+                            Err(e) => println!("Failed to convert {}: {e:?}", a.overview()),
+                        }
+                    }
+                });
+            if !lossless_skipped.is_empty() {
+                println!(
+                    "Skipped {} albums because converting a lossy format to {dest_ft} is prohibited:",
+                    lossless_skipped.len()
+                );
+                lossless_skipped.iter().for_each(|o| println!("  {o}"));
+            }
+            if cancelled() {
+                bail!(Cancelled);
+            }
+            Ok(())
+        }
+        Commands::Transcode {
+            dir,
+            dest_ft,
+            replace,
+        } => {
+            install_cancel_handler()?;
+            let config = DirConfig::read()?;
+            let mut conversions = load_conversion_cache(Some(&data_dir()?));
+            let albums = albums_in_dir(&dir, &config.exclude_patterns, &config.source_layout());
+            let mut failed = 0;
+            albums
+                .iter()
+                .take_while(|_| !cancelled())
+                .for_each(|a| match a.file_type() {
+                    None => println!(
+                        "Skipping {}: failed to determine its file type",
+                        a.overview()
+                    ),
+                    Some(ft) if ft == dest_ft => {}
+                    // convert_src_album refuses to convert lossy formats to lossless ones
+                    Some(_) => {
+                        if let Err(e) =
+                            transcode_album(&dir, a, &dest_ft, replace, &config, &mut conversions)
+                        {
+                            println!("Failed to transcode {}: {e:?}", a.overview());
+                            failed += 1;
+                        }
+                    }
+                });
+            if cancelled() {
+                bail!(Cancelled);
+            }
+            if failed > 0 {
+                bail!("Failed to transcode {failed} albums");
+            }
             Ok(())
         }
     }
 }
 
-/// tries to obtain a copy of album with file type `dest_ft`
-fn get_ft_src_album(
-    album: &Album,
-    dest_ft: &FileType,
-    album_lookup: &HashMap<(String, FileType), (Album, PathBuf)>,
-) -> Option<Album> {
-    if let Some((src_album, _src)) = album_lookup.get(&(album.key(), dest_ft.clone())) {
-        return Some(src_album.clone());
-    } else {
-        // this is the order in which src_ft are tried for conversion
-        let src_ft_order = [FileType::Flac, FileType::Wav, FileType::MP3, FileType::M4A];
-        for ft in src_ft_order {
-            if let Some((src_album, src)) = album_lookup.get(&(album.key(), ft.clone())) {
+/// converts `album` to `dest_ft` next to it. With `replace`, the original album is deleted once
+/// every converted track decodes without errors
+fn transcode_album(
+    root: &Path,
+    album: &Album,
+    dest_ft: &FileType,
+    replace: bool,
+    config: &DirConfig,
+    conversions: &mut ConversionCache,
+) -> Result<()> {
+    // fails if any track could not be converted
+    let converted = convert_src_album(root, album, dest_ft, config, conversions)?;
+    println!("Converted album: {}", converted.overview());
+    if !replace {
+        return Ok(());
+    }
+    if converted.dir_path.starts_with(&album.dir_path) {
+        bail!(
+            "The converted album is stored inside {:?}, keeping the original",
+            album.dir_path
+        );
+    }
+    if converted.tracks.len() != album.tracks.len() {
+        bail!(
+            "Only {} of {} tracks were converted, keeping the original",
+            converted.tracks.len(),
+            album.tracks.len()
+        );
+    }
+    let ffmpeg = config
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("ffmpeg"));
+    for t in &converted.tracks {
+        check_decodes(&ffmpeg, &converted.dir_path.join(t))
+            .context("Keeping the original album")?;
+    }
+    remove_path(&album.dir_path, config.use_trash)?;
+    println!(
+        "Replaced {:?} with {:?}",
+        album.dir_path, converted.dir_path
+    );
+    Ok(())
+}
+
+/// decodes `track` with ffmpeg and fails if that reports any error
+fn check_decodes(ffmpeg: &Path, track: &Path) -> Result<()> {
+    let output = Command::new(ffmpeg)
+        .args(["-v", "error", "-i"])
+        .arg(track)
+        .args(["-f", "null", "-"])
+        .output()
+        .context(format!("Failed to run {ffmpeg:?}"))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || !stderr.trim().is_empty() {
+        bail!("{track:?} does not decode cleanly:\n{}", stderr.trim());
+    }
+    Ok(())
+}
+
+/// groups the lossy `albums` by their normalized key. Returns the first album of each group with
+/// the file types it is available in, sorted by key
+fn lossy_only_albums(albums: &[Album]) -> Vec<(&Album, Vec<String>)> {
+    let mut groups: BTreeMap<String, (&Album, BTreeSet<String>)> = BTreeMap::new();
+    albums.iter().for_each(|a| {
+        let Some(ft) = a.file_type() else {
+            return;
+        };
+        if ft.is_lossless() {
+            return;
+        }
+        groups
+            .entry(a.normalized_key())
+            .or_insert_with(|| (a, BTreeSet::new()))
+            .1
+            .insert(ft.to_string());
+    });
+    groups
+        .into_values()
+        .map(|(a, formats)| (a, formats.into_iter().collect()))
+        .collect()
+}
+
+/// the album artist or title of an album according to its folder and its tags
+#[derive(Debug, PartialEq)]
+struct TagMismatch {
+    album: String,
+    dir: PathBuf,
+    /// "artist" or "title"
+    field: &'static str,
+    folder: String,
+    /// the distinct values in the track tags
+    tags: Vec<String>,
+}
+
+/// compares the artist and title parsed from the folder of `album` with its album artist and
+/// album title tags. Differences in case and punctuation are ignored
+fn tag_mismatches(album: &Album) -> Vec<TagMismatch> {
+    let mut artists = BTreeSet::new();
+    let mut track_artists = BTreeSet::new();
+    let mut titles = BTreeSet::new();
+    album.tracks.iter().for_each(|t| {
+        if let Ok(tags) = get_track_tags(&album.dir_path.join(t)) {
+            artists.extend(tags.album_artist().map(|a| a.to_string()));
+            track_artists.extend(tags.artist().map(|a| a.to_string()));
+            titles.extend(tags.album_title().map(|a| a.to_string()));
+        }
+    });
+    // without album artist tags, the track artist is used if all tracks agree on it
+    if artists.is_empty() && track_artists.len() == 1 {
+        artists = track_artists;
+    }
+    let mut mismatches = vec![];
+    let mut compare = |field, folder: &str, tags: BTreeSet<String>| {
+        if tags
+            .iter()
+            .any(|t| normalize_key_part(t) != normalize_key_part(folder))
+        {
+            mismatches.push(TagMismatch {
+                album: album.overview(),
+                dir: album.dir_path.clone(),
+                field,
+                folder: folder.to_string(),
+                tags: tags.into_iter().collect(),
+            });
+        }
+    };
+    if !album.is_compilation() {
+        compare("artist", &album.parsed_artist, artists);
+    }
+    compare("title", &album.parsed_title, titles);
+    mismatches
+}
+
+/// lists the album artist tags with their track counts if the tracks of `album` disagree on them
+fn inconsistent_album_artists(album: &Album) -> Option<String> {
+    (album.album_artist_counts.len() > 1).then(|| {
+        album
+            .album_artist_counts
+            .iter()
+            .map(|(artist, n)| {
+                format!(
+                    "{artist:?} ({n} {})",
+                    if *n == 1 { "track" } else { "tracks" }
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    })
+}
+
+/// keeps the albums whose key, artist or title contains `filter` (case-insensitive). If none
+/// matches, the closest albums are printed
+fn filter_albums(albums: Vec<Album>, filter: &Option<String>) -> Vec<Album> {
+    let Some(filter) = filter else {
+        return albums;
+    };
+    let filter = filter.to_lowercase();
+    let matches = |a: &Album| {
+        [&a.key(), &a.parsed_artist, &a.parsed_title]
+            .iter()
+            .any(|s| s.to_lowercase().contains(&filter))
+    };
+    let (matching, other): (Vec<Album>, Vec<Album>) = albums.into_iter().partition(matches);
+    if matching.is_empty() {
+        println!("No album matches {filter:?}.");
+        let mut near_matches: Vec<(usize, String)> = other
+            .iter()
+            .map(|a| {
+                let name = format!("{} - {}", a.parsed_artist, a.parsed_title);
+                (levenshtein(&filter, &name.to_lowercase()), name)
+            })
+            .collect();
+        near_matches.sort();
+        if !near_matches.is_empty() {
+            println!("Did you mean one of these?");
+            near_matches
+                .iter()
+                .take(5)
+                .for_each(|(_, name)| println!("  {name}"));
+        }
+    }
+    matching
+}
+
+/// splits the single track of `album` into the tracks of the cuesheet with ffmpeg and renames
+/// the original file to `<name>.orig`. Lossy files are cut without transcoding them. If a track
+/// fails or the split is cancelled, the tracks written so far are removed again
+fn split_cue(album: &Album, cue_file: &Path, config: &DirConfig, dry_run: bool) -> Result<()> {
+    let sheet = read_cue(cue_file)?;
+    let problems = sheet.problems();
+    if !problems.is_empty() {
+        bail!("the cuesheet {cue_file:?} has problems: {problems:?}");
+    }
+    let src = album.dir_path.join(&album.tracks[0]);
+    let ext = src
+        .extension()
+        .context(format!("{src:?} has no extension"))?
+        .to_string_lossy()
+        .to_string();
+    // re-encoding a lossy file would lose quality, lossless ones are encoded again without loss
+    let copy_codec = FileType::from_extension(&ext).is_some_and(|ft| !ft.is_lossless());
+    let ffmpeg = config
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("ffmpeg"));
+    let mut written: Vec<PathBuf> = vec![];
+    // partial tracks next to the original would look like a complete album on the next scan
+    let discard_output = |written: &[PathBuf]| {
+        let res = written
+            .iter()
+            .filter(|dst| **dst != src && dst.exists())
+            .try_for_each(std::fs::remove_file);
+        if let Err(e) = res {
+            println!("Failed to remove the partially split tracks of {src:?}: {e:?}");
+        }
+    };
+    for (i, track) in sheet.tracks.iter().enumerate() {
+        let title = track.title.clone().unwrap_or_default();
+        let dst = album.dir_path.join(format!(
+            "{:02} {}.{ext}",
+            track.number,
+            sanitize_file_name(&title)
+        ));
+        println!("SPLIT: {src:?} [{:?}] -> {dst:?}", track.start);
+        if dry_run {
+            continue;
+        }
+        let mut args = vec![
+            "-progress".to_string(),
+            "pipe:1".to_string(),
+            "-nostats".to_string(),
+            "-y".to_string(),
+            "-i".to_string(),
+            src.to_string_lossy().to_string(),
+            "-ss".to_string(),
+            format!("{:.3}", track.start.as_secs_f64()),
+        ];
+        let end = sheet.tracks.get(i + 1).map(|next| next.start);
+        if let Some(end) = end {
+            args.extend(["-to".to_string(), format!("{:.3}", end.as_secs_f64())]);
+        }
+        if copy_codec {
+            args.extend(["-c".to_string(), "copy".to_string()]);
+        }
+        let performer = track.performer.as_ref().or(sheet.performer.as_ref());
+        let metadata = [
+            ("title", Some(&title)),
+            ("artist", performer),
+            ("album", sheet.title.as_ref()),
+            ("track", Some(&track.number.to_string())),
+        ];
+        metadata.iter().for_each(|(key, value)| {
+            if let Some(value) = value {
+                args.extend(["-metadata".to_string(), format!("{key}={value}")]);
+            }
+        });
+        args.push(dst.to_string_lossy().to_string());
+        written.push(dst);
+        let duration = end.map(|end| end - track.start);
+        let res = run_ffmpeg(&ffmpeg, &args, duration, |_| {});
+        // ffmpeg gets the Ctrl-C as well and stops in the middle of the track
+        if cancelled() {
+            discard_output(&written);
+            bail!(Cancelled);
+        }
+        let (status, stderr) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                discard_output(&written);
+                return Err(e);
+            }
+        };
+        if !status.success() {
+            discard_output(&written);
+            let lines: Vec<&str> = stderr.lines().collect();
+            bail!(
+                "ffmpeg failed to split track {} ({status}):\n{}",
+                track.number,
+                lines[lines.len().saturating_sub(5)..].join("\n")
+            );
+        }
+    }
+    if !dry_run {
+        let orig = src.with_extension(format!("{ext}.orig"));
+        std::fs::rename(&src, &orig).context(format!("Failed to rename {src:?}"))?;
+        println!(
+            "Split {} into {} tracks",
+            album.overview(),
+            sheet.tracks.len()
+        );
+    }
+    Ok(())
+}
+
+/// the directory the disc albums are merged into. Fails if the grouping is ambiguous
+fn merge_target(discs: &[(u16, &Album)]) -> Result<PathBuf> {
+    let disc_numbers: HashSet<u16> = discs.iter().map(|(disc, _)| *disc).collect();
+    if disc_numbers.len() != discs.len() {
+        bail!("multiple albums have the same disc number");
+    }
+    let file_types: HashSet<Option<FileType>> = discs.iter().map(|(_, a)| a.file_type()).collect();
+    if file_types.len() > 1 {
+        bail!("the discs have different file types");
+    }
+    // the disc number is added in front of the track number, so all tracks need one
+    if let Some(t) = discs.iter().flat_map(|(_, a)| &a.tracks).find(|t| {
+        !t.split_once(' ')
+            .is_some_and(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    }) {
+        bail!("track {t:?} does not start with a track number");
+    }
+    let (_, first) = discs[0];
+    let dir_name = first
+        .dir_path
+        .file_name()
+        .context("album directory has no name")?
+        .to_string_lossy();
+    let Some((base, _)) = disc_suffix(&dir_name) else {
+        bail!("the directory name {dir_name:?} has no disc suffix");
+    };
+    let target = first.dir_path.with_file_name(base);
+    if target.exists() {
+        bail!("{target:?} already exists");
+    }
+    Ok(target)
+}
+
+/// the album directories of `albums` that are stored below a folder named after their parsed
+/// artist whose [`Album::tag_artist`] differs, with their new directory in a folder named after
+/// the tag artist
+fn artist_dir_moves(albums: &[Album]) -> Vec<(PathBuf, PathBuf)> {
+    albums
+        .iter()
+        .filter_map(|a| {
+            let artist = a.tag_artist()?;
+            let artist_dir = a.dir_path.parent()?;
+            if artist_dir.file_name()? != a.parsed_artist.as_str() {
+                return None;
+            }
+            let target = artist_dir
+                .with_file_name(sanitize_file_name(artist))
+                .join(a.dir_path.file_name()?);
+            (target != a.dir_path).then(|| (a.dir_path.clone(), target))
+        })
+        .collect()
+}
+
+/// moves the tracks and cover files of the discs to `target`. The tracks are prefixed with their
+/// disc number and get disc number tags. Empty disc directories are removed
+fn merge_discs(discs: &[(u16, &Album)], target: &Path, dry_run: bool) -> Result<()> {
+    if !dry_run {
+        std::fs::create_dir_all(target).context(format!("Failed to create {target:?}"))?;
+    }
+    for (disc, album) in discs {
+        for t in &album.tracks {
+            let src = album.dir_path.join(t);
+            let dst = target.join(format!("{disc}-{t}"));
+            println!("MOVE: {src:?} -> {dst:?}");
+            if dry_run {
+                continue;
+            }
+            std::fs::rename(&src, &dst).context(format!("Failed to move {src:?} to {dst:?}"))?;
+            let mut tag = get_track_tags(&dst)?;
+            tag.set_disc_number(*disc);
+            tag.write_to_path(
+                dst.to_str()
+                    .context("track path should be a valid string")?,
+            )?;
+        }
+        for cf in &album.cover_files {
+            let dst = target.join(
+                cf.file_name()
+                    .context("cover files must have a file name")?,
+            );
+            if dst.exists() {
+                continue;
+            }
+            println!("MOVE: {cf:?} -> {dst:?}");
+            if !dry_run {
+                std::fs::rename(cf, &dst).context(format!("Failed to move {cf:?} to {dst:?}"))?;
+            }
+        }
+        if !dry_run && std::fs::remove_dir(&album.dir_path).is_err() {
+            println!(
+                "{:?} still contains files, please check it manually",
+                album.dir_path
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Renames the tracks of `album` to `NN Title.ext` or `D-NN Title.ext` (if the album has multiple
+/// discs) using their tags. Tracks without title or track number tags are skipped.
+fn rename_tracks(album: &Album, dry_run: bool) {
+    let tagged_tracks: Vec<(&String, TrackInfo)> = album
+        .tracks
+        .iter()
+        .map(|t| {
+            let tags = get_track_tags(&album.dir_path.join(t));
+            let info = TrackInfo {
+                title: tags
+                    .as_ref()
+                    .ok()
+                    .and_then(|tags| tags.title())
+                    .unwrap_or_default()
+                    .to_string(),
+                disc_number: tags.as_ref().ok().and_then(|tags| tags.disc_number()),
+                track_number: tags.as_ref().ok().and_then(|tags| tags.track_number()),
+            };
+            (t, info)
+        })
+        .collect();
+    let multi_disc = tagged_tracks
+        .iter()
+        .any(|(_, info)| info.disc_number.is_some_and(|d| d > 1));
+    // names that are in use, renamed tracks must not collide with them
+    let mut taken: HashSet<String> = album.tracks.iter().cloned().collect();
+    tagged_tracks.iter().for_each(|(t, info)| {
+        let (title, Some(track)) = (&info.title, info.track_number) else {
+            println!(
+                "Skipping {t:?} in {:?}: track number tag missing",
+                album.dir_path
+            );
+            return;
+        };
+        if title.is_empty() {
+            println!("Skipping {t:?} in {:?}: title tag missing", album.dir_path);
+            return;
+        }
+        let ext = Path::new(t)
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let stem = if multi_disc {
+            format!(
+                "{}-{track:02} {}",
+                info.disc_number.unwrap_or(1),
+                sanitize_file_name(title)
+            )
+        } else {
+            format!("{track:02} {}", sanitize_file_name(title))
+        };
+        let mut new_name = format!("{stem}.{ext}");
+        if new_name == **t {
+            return;
+        }
+        let mut i = 2;
+        while taken.contains(&new_name) || album.dir_path.join(&new_name).exists() {
+            new_name = format!("{stem} ({i}).{ext}");
+            i += 1;
+        }
+        let src = album.dir_path.join(t);
+        let dst = album.dir_path.join(&new_name);
+        if dry_run {
+            println!("[dry-run] Would rename {src:?} -> {dst:?}");
+        } else {
+            println!("Renaming {src:?} -> {dst:?}");
+            if let Err(e) = std::fs::rename(&src, &dst) {
+                println!("Failed to rename {src:?}: {e:?}");
+                return;
+            }
+        }
+        taken.remove(*t);
+        taken.insert(new_name);
+    });
+}
+
+/// runs `tool` with `arg` and returns the first line of its output
+fn tool_version(tool: &Path, arg: &str) -> Result<String> {
+    let output = Command::new(tool)
+        .arg(arg)
+        .output()
+        .context(format!("Failed to run {tool:?}"))?;
+    if !output.status.success() {
+        bail!("{tool:?} exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+fn check_writable(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir).context(format!("Failed to create {dir:?}"))?;
+    let test_file = dir.join(".morg_doctor");
+    std::fs::write(&test_file, "").context(format!("Failed to write to {dir:?}"))?;
+    std::fs::remove_file(&test_file).context(format!("Failed to remove {test_file:?}"))
+}
+
+/// checks the environment and prints a checklist. Returns whether everything required by the
+/// configured destinations is available
+fn doctor() -> bool {
+    let mut ok = true;
+    let config = match DirConfig::read() {
+        Ok(config) if !DirConfig::config_file().exists() => {
+            println!("[WARN] There is no config file yet");
+            println!("       Hint: add sources and destinations with the config subcommands");
+            config
+        }
+        Ok(config) => {
+            println!("[ OK ] Config {:?} can be parsed", DirConfig::config_file());
+            config
+        }
+        Err(e) => {
+            println!(
+                "[FAIL] Config {:?} is invalid: {e:?}",
+                DirConfig::config_file()
+            );
+            println!("       Fix or remove the file and recreate it with the config subcommands");
+            return false;
+        }
+    };
+    let needs_adb = config
+        .destinations
+        .iter()
+        .any(|(d, _, _)| *d == Destination::ADBDest);
+    let needs_ffmpeg = !config.destinations.is_empty();
+
+    let ffmpeg = config
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("ffmpeg"));
+    // ffprobe is shipped with ffmpeg, so it is looked for next to it
+    let ffprobe = ffmpeg.with_file_name("ffprobe");
+    let adb = PathBuf::from("adb");
+    let lame = config
+        .lame_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("lame"));
+    let tools = [
+        (
+            &ffmpeg,
+            "-version",
+            needs_ffmpeg,
+            "install ffmpeg or set its location with `config set-ffmpeg-path`",
+        ),
+        (
+            &ffprobe,
+            "-version",
+            false,
+            "install ffprobe (part of ffmpeg)",
+        ),
+        (
+            &adb,
+            "version",
+            needs_adb,
+            "install the Android platform tools and put adb on your PATH",
+        ),
+        (
+            &lame,
+            "--version",
+            config.gapless_mp3,
+            "install lame, set its location with `config set-lame-path` or disable gapless conversion with `config set-gapless-mp3 false`",
+        ),
+    ];
+    for (tool, arg, required, hint) in tools {
+        match tool_version(tool, arg) {
+            Ok(version) => println!("[ OK ] {tool:?} is available: {version}"),
+            Err(e) if required => {
+                println!("[FAIL] {tool:?} is required but not available: {e}");
+                println!("       Hint: {hint}");
+                ok = false;
+            }
+            Err(e) => {
+                println!("[WARN] {tool:?} is not available: {e}");
+                println!("       Hint: {hint}");
+            }
+        }
+    }
+
+    match Keys::parse() {
+        Ok(_) => println!("[ OK ] Discogs keys file can be parsed"),
+        Err(e) => {
+            println!("[WARN] Discogs keys are not available: {e}");
+            println!(
+                "       Hint: CleanUpTags and FillInCoverFiles need a keys.toml with your discogs key and secret"
+            );
+        }
+    }
+
+    let pd = ProjectDirs::from("TF", "TF", "morg").expect("The project dir should be valid!");
+    for (kind, dir) in [("Config", pd.config_dir()), ("Data", pd.data_local_dir())] {
+        match check_writable(dir) {
+            Ok(()) => println!("[ OK ] {kind} directory {dir:?} is writable"),
+            Err(e) => {
+                println!("[FAIL] {kind} directory {dir:?} is not writable: {e:?}");
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// the albums a sync is restricted to, see `Sync --only`
+#[derive(Clone, Debug, Default)]
+struct AlbumSelection {
+    /// see [`Album::normalized_key`]
+    keys: HashSet<String>,
+    /// directories of the tracks of a playlist
+    dirs: HashSet<PathBuf>,
+}
+
+impl AlbumSelection {
+    /// reads a playlist (`.m3u`/`.m3u8`) or a file with one album key (`artist###title`) per
+    /// line. Empty lines and lines starting with `#` are ignored
+    fn read(path: &Path) -> Result<Self> {
+        let is_playlist = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("m3u") || ext.eq_ignore_ascii_case("m3u8"));
+        if is_playlist {
+            let dirs = playlist_tracks(path)?
+                .iter()
+                .filter_map(|t| t.parent().map(|d| d.to_path_buf()))
+                .collect();
+            return Ok(AlbumSelection {
+                dirs,
+                ..Default::default()
+            });
+        }
+        let content = std::fs::read_to_string(path).context(format!("Failed to read {path:?}"))?;
+        let keys = content
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| {
+                let Some((artist, title)) = l.split_once("###") else {
+                    bail!("Invalid album key {l:?} in {path:?}, expected `artist###title`");
+                };
+                let artist = if is_various_artists(artist) {
+                    "VA"
+                } else {
+                    artist
+                };
+                Ok(format!(
+                    "{}###{}",
+                    normalize_key_part(artist),
+                    normalize_key_part(title)
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(AlbumSelection {
+            keys,
+            ..Default::default()
+        })
+    }
+
+    fn contains(&self, album: &Album) -> bool {
+        self.keys.contains(&album.normalized_key()) || self.dirs.contains(&album.dir_path)
+    }
+}
+
+fn parse_album_selection(s: &str) -> Result<AlbumSelection> {
+    AlbumSelection::read(Path::new(s))
+}
+
+/// Parses a file type of source albums. Unlike the [`ValueEnum`] impl of [`FileType`], this also
+/// accepts the file types that can only be converted from.
+fn parse_source_file_type(s: &str) -> Result<FileType> {
+    FileType::from_extension(s).context(format!(
+        "Unknown file type {s:?}, expected one of {}",
+        FileType::ALL.map(|ft| ft.to_string()).join(", ")
+    ))
+}
+
+/// Parses the `--since` argument of Sync. Accepts a duration relative to now (`30m`, `12h`, `7d`,
+/// `2w`), a date (`YYYY-MM-DD`, midnight UTC) or a unix timestamp in seconds.
+fn parse_since(s: &str) -> Result<SystemTime> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let parts: Vec<&str> = s.split('-').collect();
+    if let [y, m, d] = parts[..]
+        && let (Ok(y), Ok(m), Ok(d)) = (y.parse::<i64>(), m.parse::<u32>(), d.parse::<u32>())
+    {
+        if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+            bail!("Invalid date {s:?}");
+        }
+        let days = days_from_civil(y, m, d);
+        let secs = u64::try_from(days * 86400).context(format!("{s:?} is before 1970"))?;
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let unit_pos = s
+        .find(|c: char| !c.is_ascii_digit())
+        .context(format!("Failed to parse {s:?}"))?;
+    let (num, unit) = s.split_at(unit_pos);
+    let num: u64 = num
+        .parse()
+        .context(format!("Expected a number in front of the unit in {s:?}"))?;
+    let secs = match unit {
+        "s" => num,
+        "m" => num * 60,
+        "h" => num * 60 * 60,
+        "d" => num * 60 * 60 * 24,
+        "w" => num * 60 * 60 * 24 * 7,
+        _ => bail!("Unknown unit {unit:?} in {s:?}. Expected one of s, m, h, d, w"),
+    };
+    SystemTime::now()
+        .checked_sub(Duration::from_secs(secs))
+        .context(format!("{s:?} is too far in the past"))
+}
+
+/// number of days since 1970-01-01 of the given date in the proleptic gregorian calendar
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = i64::from((m + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(d) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Replaces the symlink `link` with a copy of its target. The target is first copied next to the
+/// symlink, so the symlink is only removed once the copy is complete.
+fn replace_symlink_with_copy(link: &Path, dry_run: bool) -> Result<()> {
+    let target = link
+        .read_link()
+        .context(format!("Failed to read symlink {link:?}"))?;
+    // relative targets are relative to the directory containing the symlink
+    let target = match link.parent() {
+        Some(parent) if target.is_relative() => parent.join(target),
+        _ => target,
+    };
+    if !target.exists() {
+        bail!("Target {target:?} of symlink {link:?} does not exist");
+    }
+    if dry_run {
+        println!("[dry-run] Would replace symlink {link:?} with a copy of {target:?}");
+        return Ok(());
+    }
+
+    let mut tmp_name = link
+        .file_name()
+        .context(format!("Symlink {link:?} has no file name"))?
+        .to_os_string();
+    tmp_name.push(".morg-tmp");
+    let tmp = link.with_file_name(tmp_name);
+    if tmp.exists() {
+        bail!("Temporary path {tmp:?} already exists");
+    }
+    println!("Copying {target:?} to {tmp:?}");
+    if target.is_dir() {
+        std::fs::create_dir_all(&tmp).context(format!("Failed to create {tmp:?}"))?;
+        let mut copy_options = CopyOptions::new();
+        copy_options.content_only = true;
+        if let Err(e) = fs_extra::dir::copy(&target, &tmp, &copy_options) {
+            let _ = std::fs::remove_dir_all(&tmp);
+            bail!("Failed to copy {target:?} to {tmp:?}: {e:?}");
+        }
+    } else if let Err(e) = std::fs::copy(&target, &tmp) {
+        let _ = std::fs::remove_file(&tmp);
+        bail!("Failed to copy {target:?} to {tmp:?}: {e:?}");
+    }
+
+    println!("Removing symlink {link:?}");
+    // directory symlinks have to be removed with remove_dir on windows
+    std::fs::remove_file(link)
+        .or_else(|_| std::fs::remove_dir(link))
+        .context(format!("Failed to remove symlink {link:?}"))?;
+    println!("Moving {tmp:?} to {link:?}");
+    std::fs::rename(&tmp, link).context(format!("Failed to move {tmp:?} to {link:?}"))
+}
+
+/// the directory caches and the progress of syncs are stored in
+fn data_dir() -> Result<PathBuf> {
+    let pd = ProjectDirs::from("TF", "TF", "morg").context("Failed to construct data path!")?;
+    Ok(pd.data_local_dir().to_path_buf())
+}
+
+/// the [`ConversionCache`] stored in `state_dir`, or an empty one if it cannot be loaded. Without a
+/// `state_dir`, the cache is only kept in memory
+fn load_conversion_cache(state_dir: Option<&Path>) -> ConversionCache {
+    let Some(state_dir) = state_dir else {
+        return ConversionCache::default();
+    };
+    ConversionCache::load(state_dir).unwrap_or_else(|e| {
+        println!("WARNING: failed to load the conversion cache, converting all albums: {e:?}");
+        ConversionCache::default()
+    })
+}
+
+/// tries to obtain a copy of album with file type `dest_ft`. Conversions of unchanged source
+/// albums are taken from `conversions`, see [`convert_src_album`]
+fn get_ft_src_album(
+    album: &Album,
+    dest_ft: &FileType,
+    album_lookup: &HashMap<(String, FileType), (Album, PathBuf)>,
+    config: &DirConfig,
+    conversions: &mut ConversionCache,
+    summary: &mut SyncSummary,
+) -> Option<Album> {
+    if let Some((src_album, _src)) = album_lookup.get(&(album.normalized_key(), dest_ft.clone())) {
+        return Some(src_album.clone());
+    } else {
+        for ft in config.source_priority() {
+            if let Some((src_album, src)) = album_lookup.get(&(album.normalized_key(), ft.clone()))
+            {
+                let out_dir = config.conversion_dir.as_deref().unwrap_or(src);
+                let out_album_dir = src_album.album_dir_with_ft(
+                    out_dir.to_path_buf(),
+                    &Some(dest_ft.clone()),
+                    config.file_name_rules,
+                );
+                let reused = conversions
+                    .get(src_album, dest_ft, &out_album_dir)
+                    .is_some();
+                if !reused {
+                    println!(
+                        "Found {ft:?} source album {:?}. Converting to {dest_ft:?} (conversion {} of this sync)",
+                        album.overview(),
+                        summary.albums_converted + 1
+                    );
+                }
+                let res = convert_src_album(out_dir, src_album, dest_ft, config, conversions);
+                if let Ok(res) = res {
+                    if !reused {
+                        summary.albums_converted += 1;
+                    }
+                    return Some(res);
+                } else {
+                    println!("Conversion {} -> {dest_ft} failed!", album.overview());
+                    summary.errors += 1;
+                }
+            }
+        }
+    };
+    None
+}
+
+/// determines which album has to be copied to the location to provide `src_album` in the
+/// desired file type. Returns the album and its file type
+fn choose_src_album(
+    src_album: &Album,
+    dest_ft: &FileType,
+    album_lookup: &HashMap<(String, FileType), (Album, PathBuf)>,
+    allow_any: bool,
+    config: &DirConfig,
+    conversions: &mut ConversionCache,
+    summary: &mut SyncSummary,
+) -> Result<(Album, FileType)> {
+    let new_src_album = get_ft_src_album(
+        src_album,
+        dest_ft,
+        album_lookup,
+        config,
+        conversions,
+        summary,
+    );
+    if let Some(src_album) = new_src_album {
+        println!("Found source album {}", src_album.overview());
+        Ok((src_album, dest_ft.clone()))
+    } else if allow_any
+        && let Some((fallback, ft)) =
+            fallback_src_album(src_album, album_lookup, &config.fallback_priority)
+    {
+        println!(
+            "No {dest_ft} source for {}. Falling back to {ft}, the preferred available file type, because allow_any is set for this destination",
+            src_album.overview()
+        );
+        summary
+            .fallback_albums
+            .push(format!("{} ({ft})", fallback.overview()));
+        Ok((fallback, ft))
+    } else {
+        summary.unavailable_albums.push(src_album.overview());
+        bail!(
+            "Failed to find proper source fitting source album for {} [{:?}]. dest_ft is {dest_ft}, allow_any={allow_any}",
+            src_album.overview(),
+            src_album.file_type()
+        )
+    }
+}
+
+/// the source album of `src_album` in the first file type of `priority` it is available in.
+/// Falls back to `src_album` itself
+fn fallback_src_album(
+    src_album: &Album,
+    album_lookup: &HashMap<(String, FileType), (Album, PathBuf)>,
+    priority: &[FileType],
+) -> Option<(Album, FileType)> {
+    priority
+        .iter()
+        .find_map(|ft| {
+            album_lookup
+                .get(&(src_album.normalized_key(), ft.clone()))
+                .map(|(a, _)| (a.clone(), ft.clone()))
+        })
+        .or_else(|| src_album.file_type().map(|ft| (src_album.clone(), ft)))
+}
+
+/// the files in `dir` (not recursive) with one of the `extensions` (case-insensitive)
+fn aux_files(dir: &Path, extensions: &[String]) -> Vec<PathBuf> {
+    let Ok(entries) = read_dir(dir) else {
+        return vec![];
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && p.extension().is_some_and(|ext| {
+                    extensions
+                        .iter()
+                        .any(|e| ext.eq_ignore_ascii_case(e.trim_start_matches('.')))
+                })
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// the files in `dir` (recursively) whose name matches one of the junk `patterns`. Audio and
+/// image files and files with one of the `keep_extensions` are never included. Excluded
+/// directories and symlinks are skipped, so only files inside `dir` are returned
+fn junk_files(
+    dir: &Path,
+    exclude_patterns: &[String],
+    patterns: &[glob::Pattern],
+    keep_extensions: &[String],
+) -> Vec<PathBuf> {
+    let options = glob::MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+    let mut files: Vec<PathBuf> = album::own_files_in_dir(dir, exclude_patterns)
+        .into_iter()
+        .filter(|path| {
+            let keep = path.extension().is_some_and(|ext| {
+                MUSIC_EXTENSIONS
+                    .iter()
+                    .chain(IMAGE_EXTENSIONS.iter())
+                    .map(|e| e.to_string())
+                    .chain(keep_extensions.iter().cloned())
+                    .any(|e| ext.eq_ignore_ascii_case(e.trim_start_matches('.')))
+            });
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            !keep && patterns.iter().any(|p| p.matches_with(&name, options))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// runs ffmpeg with `args`, which have to contain `-progress pipe:1`, and reports the completed
+/// fraction of the track (based on `duration`) to `on_progress`. Returns the exit status and stderr
+fn run_ffmpeg(
+    ffmpeg: &Path,
+    args: &[String],
+    duration: Option<Duration>,
+    on_progress: impl Fn(f64),
+) -> Result<(ExitStatus, String)> {
+    let mut child = Command::new(ffmpeg)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!(
+            "Failed to run {ffmpeg:?}. Is ffmpeg installed? Its path can be set with `config set-ffmpeg-path`"
+        ))?;
+    // stderr is read in parallel so that ffmpeg never blocks on a full pipe
+    let mut stderr = child
+        .stderr
+        .take()
+        .context("ffmpeg stderr should be piped")?;
+    let stderr_reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stderr.read_to_string(&mut output);
+        output
+    });
+    let stdout = child
+        .stdout
+        .take()
+        .context("ffmpeg stdout should be piped")?;
+    BufReader::new(stdout)
+        .lines()
+        .map_while(|l| l.ok())
+        .for_each(|line| {
+            if let Some(duration) = duration
+                && let Some(us) = line.strip_prefix("out_time_us=")
+                && let Ok(us) = us.parse::<u64>()
+            {
+                on_progress((us as f64 / duration.as_micros() as f64).min(1.0));
+            }
+        });
+    let status = child.wait().context("Failed to wait for ffmpeg")?;
+    let stderr = stderr_reader.join().unwrap_or_default();
+    Ok((status, stderr))
+}
+
+/// converts `src_album` to `dest_ft` below `src`. The previous conversion is reused if it is in
+/// `conversions` and its source did not change since, new conversions are added to it
+fn convert_src_album(
+    src: &Path,
+    src_album: &Album,
+    dest_ft: &FileType,
+    config: &DirConfig,
+    conversions: &mut ConversionCache,
+) -> Result<Album> {
+    let Some(src_ft) = src_album.file_type() else {
+        bail!(
+            "Failed to determine filetype of source album {}",
+            src_album.overview()
+        )
+    };
+    if !src_ft.can_convert_to(dest_ft) {
+        bail!(
+            "Converting a lossy music format to a lossless one ({dest_ft}) is prohibited! src_album: {}",
+            src_album.overview()
+        )
+    }
+    let desired_ft = dest_ft.to_possible_value().expect("");
+    let desired_ft = desired_ft.get_name();
+
+    let new_src_album_dir = src_album.album_dir_with_ft(
+        src.to_path_buf(),
+        &Some(dest_ft.clone()),
+        config.file_name_rules,
+    );
+    let converted_album = |new_tracks: Vec<String>| {
+        Album::new(
+            src_album.title.clone(),
+            src_album.artist.clone(),
+            new_tracks,
+            new_src_album_dir.clone(),
+            src_album.cover_files.clone(),
+            src_album.parsed_title.clone(),
+            src_album.parsed_artist.clone(),
+        )
+    };
+    if let Some(converted) = conversions.get(src_album, dest_ft, &new_src_album_dir) {
+        println!(
+            "Reusing previous conversion of {} in {new_src_album_dir:?}",
+            src_album.overview()
+        );
+        return Ok(converted);
+    }
+    let mut remember = |converted: Album| {
+        conversions.insert(src_album, &converted);
+        if let Err(e) = conversions.store() {
+            println!("WARNING: failed to store the conversion cache: {e:?}");
+        }
+        converted
+    };
+
+    let create_album_dir = || {
+        if !new_src_album_dir.exists() {
+            std::fs::create_dir_all(&new_src_album_dir)
+                .context(format!("Failed to create {new_src_album_dir:?}"))?;
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+
+    let copy_cover_files = || {
+        src_album.cover_files.iter().for_each(|cf| {
+            let cf_name = cf.file_name().expect("cover files muts have a file name!");
+            let cf_dest = new_src_album_dir.join(cf_name);
+            println!("COPY: {cf:?} -> {cf_dest:?}");
+            let r = std::fs::copy(cf, &cf_dest);
+            if r.is_err() {
+                println!("Cover copy failed: {r:?}");
+            }
+        });
+    };
+    // logs, cuesheets and booklets are copied as well, they are not tracks of the album
+    let copy_aux_files = || {
+        aux_files(&src_album.dir_path, &config.aux_file_extensions)
+            .iter()
+            .for_each(|f| {
+                let f_dest = new_src_album_dir.join(f.file_name().expect("aux files have a name"));
+                println!("COPY: {f:?} -> {f_dest:?}");
+                if let Err(e) = std::fs::copy(f, &f_dest) {
+                    println!("Copying {f:?} failed: {e:?}");
+                }
+            });
+    };
+    let get_input_args = |full_input_track_path: &PathBuf| {
+        vec![
+            "-i".to_string(),
+            full_input_track_path.to_str().expect("").to_string(),
+        ]
+    };
+    let format_args: Vec<String> = match dest_ft {
+        FileType::MP3 => {
+            let mut args = config.mp3_quality.ffmpeg_args();
+            args.extend(
+                [
+                    "-map_metadata",
+                    "0",
+                    "-id3v2_version",
+                    config.id3_version.ffmpeg_arg(),
+                    "-write_id3v1",
+                    "1",
+                    // the LAME header stores the encoder delay and padding for gapless players
+                    "-write_xing",
+                    "1",
+                ]
+                .iter()
+                .map(|a| a.to_string()),
+            );
+            args
+        }
+        FileType::Flac => vec![],
+        ft => bail!("NOT IMPLEMENTED: conversion to {ft:?}"),
+    };
+    // extra args are appended to the format specific ones, the output file has to come last
+    let get_output_args = |full_output_track_path: &PathBuf| {
+        let mut args = format_args.clone();
+        args.extend(config.ffmpeg_extra_args.iter().cloned());
+        args.push(
+            full_output_track_path
+                .to_str()
+                .context(format!(
+                    "Failed to convert {full_output_track_path:?} to string"
+                ))?
+                .to_string(),
+        );
+        Ok::<Vec<String>, anyhow::Error>(args)
+    };
+    let ffmpeg = config
+        .ffmpeg_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("ffmpeg"));
+    let mut new_tracks = vec![];
+    let mut failed_tracks = vec![];
+    let created_album_dir = !new_src_album_dir.exists();
+    create_album_dir()?;
+    // the output of an interrupted conversion is removed, the scanner would take it for a
+    // finished one
+    let discard_output = || {
+        let res = if created_album_dir {
+            std::fs::remove_dir_all(&new_src_album_dir)
+        } else {
+            src_album
+                .tracks
+                .iter()
+                .map(|t| new_src_album_dir.join(t).with_extension(desired_ft))
+                .filter(|p| p.exists())
+                .try_for_each(std::fs::remove_file)
+        };
+        if let Err(e) = res {
+            println!("Failed to remove the partial conversion in {new_src_album_dir:?}: {e:?}");
+        }
+    };
+    copy_cover_files();
+    copy_aux_files();
+    if *dest_ft == FileType::MP3 && config.gapless_mp3 {
+        let res = convert_album_gapless(&ffmpeg, src_album, &new_src_album_dir, config);
+        if cancelled() {
+            discard_output();
+            bail!(Cancelled);
+        }
+        return Ok(remember(converted_album(res?)));
+    }
+    // every track is split into 1000 steps that are filled from ffmpeg's progress output
+    let pb = ProgressBar::new(src_album.tracks.len() as u64 * 1000).with_style(
+        ProgressStyle::with_template("{prefix} [{bar:30}] {msg} (ETA {eta})")
+            .expect("progress template should be valid"),
+    );
+    pb.set_prefix(src_album.title_without_filetype());
+    for (i, t) in src_album.tracks.iter().enumerate() {
+        let full_path = src_album.dir_path.join(t);
+        // the extension may be upper case, so it is replaced instead of the name
+        let dst_path = new_src_album_dir.join(t).with_extension(desired_ft);
+        pb.set_message(format!("track {} of {}", i + 1, src_album.tracks.len()));
+        pb.println(format!("Track: {full_path:?} --> {dst_path:?}"));
+        // the tracks of grouped singles are in subdirectories
+        if let Some(parent) = dst_path.parent() {
+            std::fs::create_dir_all(parent).context(format!("Failed to create {parent:?}"))?;
+        }
+        let mut args = vec![
+            "-progress".to_string(),
+            "pipe:1".to_string(),
+            "-nostats".to_string(),
+        ];
+        args.append(&mut get_input_args(&full_path));
+        args.append(&mut get_output_args(&dst_path)?);
+        let (status, stderr) = run_ffmpeg(&ffmpeg, &args, get_duration(&full_path), |done| {
+            pb.set_position(i as u64 * 1000 + (done * 1000.0) as u64)
+        })?;
+        // ffmpeg gets the Ctrl-C as well and stops in the middle of the track
+        if cancelled() {
+            pb.finish_and_clear();
+            discard_output();
+            bail!(Cancelled);
+        }
+        pb.set_position((i as u64 + 1) * 1000);
+        if !status.success() {
+            // ffmpeg prints its banner first, the actual error is at the end
+            let lines: Vec<&str> = stderr.lines().collect();
+            let error = lines[lines.len().saturating_sub(5)..].join("\n");
+            pb.println(format!(
+                "ffmpeg failed to convert {full_path:?} ({status}):\n{error}"
+            ));
+            failed_tracks.push(t.clone());
+            continue;
+        }
+        // the track path relative to the album directory is kept
+        let track = Path::new(t)
+            .with_extension(desired_ft)
+            .to_string_lossy()
+            .to_string();
+        new_tracks.push(track);
+    }
+    pb.finish_and_clear();
+    if !failed_tracks.is_empty() {
+        bail!(
+            "Failed to convert {} of {} tracks of {}: {failed_tracks:?}",
+            failed_tracks.len(),
+            src_album.tracks.len(),
+            src_album.overview()
+        );
+    }
+    if new_tracks.len() == src_album.tracks.len() {
+        Ok(remember(converted_album(new_tracks)))
+    } else {
+        bail!("Failed to convert src album: {src_album:?} --> {new_src_album_dir:?} ");
+    }
+}
+
+/// converts all tracks of `src_album` to mp3 in `out_dir` with a single `lame --nogap` run, so that
+/// there is no encoder padding between consecutive tracks. The tracks are decoded to WAV with
+/// ffmpeg first and their tags are copied afterwards. Returns the converted tracks
+fn convert_album_gapless(
+    ffmpeg: &Path,
+    src_album: &Album,
+    out_dir: &Path,
+    config: &DirConfig,
+) -> Result<Vec<String>> {
+    // the WAV files are not written next to the output, a crash would leave them in the library
+    let tmp_dir = std::env::temp_dir().join(format!("morg_gapless_{}", std::process::id()));
+    std::fs::create_dir_all(&tmp_dir).context(format!("Failed to create {tmp_dir:?}"))?;
+    let res = encode_gapless(ffmpeg, src_album, out_dir, &tmp_dir, config);
+    if let Err(e) = std::fs::remove_dir_all(&tmp_dir) {
+        println!("Failed to delete {tmp_dir:?}: {e:?}");
+    }
+    res
+}
+
+/// see [`convert_album_gapless`]. The WAV files are stored in `tmp_dir`
+fn encode_gapless(
+    ffmpeg: &Path,
+    src_album: &Album,
+    out_dir: &Path,
+    tmp_dir: &Path,
+    config: &DirConfig,
+) -> Result<Vec<String>> {
+    let path_arg = |p: &Path| {
+        p.to_str()
+            .map(|p| p.to_string())
+            .context(format!("Failed to convert {p:?} to string"))
+    };
+    // lame writes all tracks to one directory, so the WAV files are numbered instead of keeping
+    // the subdirectories of grouped singles
+    let mut wavs = vec![];
+    for (i, t) in src_album.tracks.iter().enumerate() {
+        if cancelled() {
+            bail!(Cancelled);
+        }
+        let src = src_album.dir_path.join(t);
+        let wav = tmp_dir.join(format!("{i:04}.wav"));
+        println!("Decoding {src:?}");
+        let args = vec![
+            "-progress".to_string(),
+            "pipe:1".to_string(),
+            "-nostats".to_string(),
+            "-y".to_string(),
+            "-i".to_string(),
+            path_arg(&src)?,
+            "-vn".to_string(),
+            "-map_metadata".to_string(),
+            "-1".to_string(),
+            path_arg(&wav)?,
+        ];
+        let (status, stderr) = run_ffmpeg(ffmpeg, &args, None, |_| {})?;
+        if !status.success() {
+            let lines: Vec<&str> = stderr.lines().collect();
+            bail!(
+                "ffmpeg failed to decode {src:?} ({status}):\n{}",
+                lines[lines.len().saturating_sub(5)..].join("\n")
+            );
+        }
+        wavs.push(path_arg(&wav)?);
+    }
+    println!(
+        "Encoding {} tracks of {} with lame --nogap",
+        wavs.len(),
+        src_album.overview()
+    );
+    let lame = config
+        .lame_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("lame"));
+    let output = Command::new(&lame)
+        .args(config.mp3_quality.lame_args())
+        .arg("--nogapout")
+        .arg(tmp_dir)
+        .arg("--nogap")
+        .args(&wavs)
+        .stdin(Stdio::null())
+        .output()
+        .context(format!("Failed to run {lame:?}. Is lame installed? Gapless conversion can be disabled with `config set-gapless-mp3 false`"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines: Vec<&str> = stderr.lines().collect();
+        bail!(
+            "lame failed to convert {} ({}):\n{}",
+            src_album.overview(),
+            output.status,
+            lines[lines.len().saturating_sub(5)..].join("\n")
+        );
+    }
+    src_album
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(i, t)| {
+            let encoded = tmp_dir.join(format!("{i:04}.mp3"));
+            if !encoded.exists() {
+                bail!("lame did not create {encoded:?}");
+            }
+            let track = Path::new(t).with_extension("mp3");
+            let mp3 = out_dir.join(&track);
+            if let Some(parent) = mp3.parent() {
+                std::fs::create_dir_all(parent).context(format!("Failed to create {parent:?}"))?;
+            }
+            move_file(&encoded, &mp3)?;
+            copy_tags_to_mp3(&src_album.dir_path.join(t), &mp3, config.id3_version)?;
+            Ok(track.to_string_lossy().to_string())
+        })
+        .collect()
+}
+
+/// moves `src` to `dst`. Falls back to copying and removing `src` if they are on different
+/// filesystems, where renaming fails
+fn move_file(src: &Path, dst: &Path) -> Result<()> {
+    if std::fs::rename(src, dst).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(src, dst).context(format!("Failed to copy {src:?} to {dst:?}"))?;
+    std::fs::remove_file(src).context(format!("Failed to remove {src:?}"))
+}
+
+/// syncs the sources to all configured destinations and returns the combined summary. With
+/// `strict`, allow_any is ignored for all destinations
+fn sync_all(
+    config: &DirConfig,
+    options: &SyncOptions,
+    since: Option<SystemTime>,
+    strict: bool,
+) -> SyncSummary {
+    let mut destinations = config.destinations.clone();
+    // sync to sources first
+    destinations.sort_by_key(|d| match &d.0 {
+        Destination::PathDest(p) => {
+            if config.source_directories.contains(p) {
+                0
+            } else {
+                1
+            }
+        }
+        Destination::ADBDest => 1,
+    });
+
+    let mut total = SyncSummary::default();
+    destinations.iter().for_each(|(dest, ft, allow_any)| {
+        if cancelled() || (options.fail_fast && total.errors > 0) {
+            return;
+        }
+        match dest {
+            Destination::PathDest(p) => {
+                println!("===== Syncing to dir {p:?} =====");
+                let mut loc = DirLocation::new(
+                    config.destination_dir(p, ft),
+                    config.exclude_patterns.clone(),
+                    config.use_trash,
+                    !options.skip_tag_scan,
+                    config.file_name_rules,
+                )
+                .with_layout_template(config.layout_template.clone())
+                .with_singles_dir(config.singles_dir.clone());
+                let mut summary = sync_to_loc(
+                    &mut loc,
+                    ft,
+                    config,
+                    options,
+                    *allow_any && !strict,
+                    since,
+                    config.max_size(dest),
+                );
+                sync_playlists(&mut loc, config, !options.skip_tag_scan, &mut summary);
+                summary.print(&format!("Summary for {}", loc.to_string()));
+                total.add(&summary);
+            }
+            Destination::ADBDest => {
+                println!("===== Syncing to ADB devce =====");
+                let loc = AdbLocation::new(config.adb_push_retries, config.adb_music_dir.clone())
+                    .map(|loc| {
+                        loc.with_layout_template(config.layout_template.clone())
+                            .with_singles_dir(config.singles_dir.clone())
+                    });
+                if let Ok(mut loc) = loc {
+                    let mut summary = sync_to_loc(
+                        &mut loc,
+                        ft,
+                        config,
+                        options,
+                        *allow_any && !strict,
+                        since,
+                        config.max_size(dest),
+                    );
+                    sync_playlists(&mut loc, config, !options.skip_tag_scan, &mut summary);
+                    summary.print(&format!("Summary for {}", loc.to_string()));
+                    total.add(&summary);
+                } else {
+                    println!("{loc:?}\nSkipping this location.");
+                    total.errors += 1;
+                }
+            }
+        }
+    });
+    total
+}
+
+/// the location of a configured destination
+fn open_location(
+    config: &DirConfig,
+    dest: &Destination,
+    ft: &FileType,
+) -> Result<Box<dyn Location>> {
+    Ok(match dest {
+        Destination::PathDest(p) => Box::new(
+            DirLocation::new(
+                config.destination_dir(p, ft),
+                config.exclude_patterns.clone(),
+                config.use_trash,
+                true,
+                config.file_name_rules,
+            )
+            .with_layout_template(config.layout_template.clone())
+            .with_singles_dir(config.singles_dir.clone()),
+        ),
+        Destination::ADBDest => Box::new(
+            AdbLocation::new(config.adb_push_retries, config.adb_music_dir.clone())?
+                .with_layout_template(config.layout_template.clone())
+                .with_singles_dir(config.singles_dir.clone()),
+        ),
+    })
+}
+
+/// whether the shorter side of a cover with the given dimensions is below `min_size`
+fn cover_below_min_size((width, height): (u32, u32), min_size: u32) -> bool {
+    width.min(height) < min_size
+}
+
+/// fails with one message explaining where the discogs keys file belongs if it cannot be read,
+/// instead of failing for every album that is looked up
+fn require_keys() -> Result<()> {
+    if let Err(e) = Keys::parse() {
+        let keys_file = Keys::keys_file()?;
+        bail!(
+            "This command needs discogs API keys. Create {keys_file:?} with your discogs consumer key and secret:\n\nkey = \"<key>\"\nsecret = \"<secret>\"\n\nCause: {e:#}"
+        );
+    }
+    Ok(())
+}
+
+/// how far a destination is from reflecting the sources
+#[derive(Debug, Default, PartialEq)]
+struct DestinationStatus {
+    /// source albums that are not in the destination
+    missing: Vec<String>,
+    /// albums in the destination that are not in the desired file type
+    wrong_format: Vec<String>,
+    /// albums in the destination that are not in any source
+    extraneous: Vec<String>,
+}
+
+impl DestinationStatus {
+    fn add(&mut self, other: &DestinationStatus) {
+        self.missing.extend(other.missing.iter().cloned());
+        self.wrong_format.extend(other.wrong_format.iter().cloned());
+        self.extraneous.extend(other.extraneous.iter().cloned());
+    }
+
+    fn issues(&self) -> usize {
+        self.missing.len() + self.wrong_format.len() + self.extraneous.len()
+    }
+
+    fn print(&self, title: &str, details: bool) {
+        println!("===== {title} =====");
+        [
+            ("Missing albums", &self.missing),
+            ("Albums in the wrong format", &self.wrong_format),
+            ("Albums not in the sources", &self.extraneous),
+        ]
+        .iter()
+        .for_each(|(name, albums)| {
+            println!("{name}: {}", albums.len());
+            if details {
+                albums.iter().for_each(|a| println!("  {a}"));
+            }
+        });
+    }
+}
+
+/// compares the albums of a destination with the source albums like [`sync_to_loc`] does. Albums
+/// in another file type than `ft` are only accepted with `allow_any` if no source album in `ft`
+/// exists
+fn destination_status(
+    src_albums: &[&Album],
+    dst_albums: &[Album],
+    ft: &FileType,
+    allow_any: bool,
+) -> DestinationStatus {
+    let mut status = DestinationStatus::default();
+    let src_keys: HashSet<String> = src_albums.iter().map(|a| a.normalized_key()).collect();
+    let dst_keys: HashSet<String> = dst_albums.iter().map(|a| a.normalized_key()).collect();
+    let mut missing: BTreeSet<String> = BTreeSet::new();
+    src_albums.iter().for_each(|a| {
+        if !dst_keys.contains(&a.normalized_key()) {
+            missing.insert(a.overview());
+        }
+    });
+    status.missing = missing.into_iter().collect();
+    dst_albums.iter().for_each(|a| {
+        let key = a.normalized_key();
+        if !src_keys.contains(&key) {
+            status.extraneous.push(a.overview());
+            return;
+        }
+        let has_desired_ft =
+            |a2: &&Album| a2.normalized_key() == key && a2.file_type().as_ref() == Some(ft);
+        if a.file_type().as_ref() != Some(ft)
+            && !dst_albums.iter().any(|a2| has_desired_ft(&a2))
+            && (!allow_any || src_albums.iter().any(has_desired_ft))
+        {
+            status.wrong_format.push(a.overview());
+        }
+    });
+    status
+}
+
+/// prints for every configured destination how many source albums are missing, are in the wrong
+/// file type or are not in the sources. Destinations that cannot be opened are skipped. Nothing is
+/// modified
+fn destinations_status(config: &DirConfig, details: bool) -> Result<DestinationStatus> {
+    println!("Loading source albums...");
+    let album_lookup = create_source_album_lookup(
+        &config.source_directories,
+        &config.exclude_patterns,
+        None,
+        true,
+        config.artist_from_tags,
+        &config.source_layout(),
+    );
+    let src_albums: Vec<&Album> = album_lookup.values().map(|(a, _)| a).collect();
+    let mut total = DestinationStatus::default();
+    for (dest, ft, allow_any) in &config.destinations {
+        // a disconnected device should not hide the status of the other destinations
+        let mut loc = match open_location(config, dest, ft) {
+            Ok(loc) => loc,
+            Err(e) => {
+                println!("{dest} ({ft}): not connected, skipping it ({e})");
+                continue;
+            }
+        };
+        let dst_albums = loc
+            .albums()
+            .context(format!("Failed to load albums from {}", loc.to_string()))?;
+        let status = destination_status(&src_albums, &dst_albums, ft, *allow_any);
+        status.print(&format!("{} ({ft})", loc.to_string()), details);
+        total.add(&status);
+    }
+    total.print("Total", false);
+    Ok(total)
+}
+
+/// copies the cover files of the source albums to the matching albums in all configured
+/// destinations and returns the number of errors
+fn sync_covers(config: &DirConfig) -> usize {
+    println!("Loading source albums...");
+    let album_lookup = create_source_album_lookup(
+        &config.source_directories,
+        &config.exclude_patterns,
+        None,
+        true,
+        config.artist_from_tags,
+        &config.source_layout(),
+    );
+    let mut errors = 0;
+    config.destinations.iter().for_each(|(dest, ft, _)| {
+        let mut loc = match open_location(config, dest, ft) {
+            Ok(loc) => loc,
+            Err(e) => {
+                println!("{e:?}\nSkipping this location.");
+                errors += 1;
+                return;
+            }
+        };
+        println!("===== Syncing cover files to {} =====", loc.to_string());
+        let dst_albums = match loc.albums() {
+            Ok(albums) => albums,
+            Err(e) => {
+                println!("Failed to load albums from {}: {e:?}", loc.to_string());
+                errors += 1;
+                return;
+            }
+        };
+        let mut copied = 0;
+        dst_albums.iter().for_each(|dst_album| {
+            // prefer the source album in the same file type, its covers are the ones that were
+            // synced originally
+            let key = dst_album.normalized_key();
+            let src_album = dst_album
+                .file_type()
+                .and_then(|ft| album_lookup.get(&(key.clone(), ft)))
+                .or_else(|| {
+                    album_lookup
+                        .iter()
+                        .find(|((k, _), (a, _))| *k == key && !a.cover_files.is_empty())
+                        .map(|(_, v)| v)
+                });
+            let Some((src_album, _)) = src_album else {
+                return;
+            };
+            if src_album.dir_path == dst_album.dir_path {
+                return;
+            }
+            match loc.copy_missing_covers(src_album, dst_album) {
+                Ok(n) => copied += n,
+                Err(e) => {
+                    println!(
+                        "Failed to copy the covers of {}: {e:?}",
+                        src_album.overview()
+                    );
+                    errors += 1;
+                }
+            }
+        });
+        println!("Copied {copied} cover files to {}", loc.to_string());
+    });
+    errors
+}
+
+/// copies the playlists in the source directories to the root of `location`. Their entries are
+/// rewritten to point to the synced tracks, entries whose tracks were not synced are reported.
+/// Track tags of the source albums are only read if `scan_tags` is set
+fn sync_playlists(
+    location: &mut dyn Location,
+    config: &DirConfig,
+    scan_tags: bool,
+    summary: &mut SyncSummary,
+) {
+    let playlists: Vec<PathBuf> = config
+        .source_directories
+        .iter()
+        .flat_map(|sd| playlists_in_dir(sd, &config.exclude_patterns))
+        .collect();
+    if playlists.is_empty() {
+        return;
+    }
+    let dst_albums: HashMap<String, Album> = match location.albums() {
+        Ok(albums) => albums
+            .into_iter()
+            .map(|a| (a.normalized_key(), a))
+            .collect(),
+        Err(e) => {
+            println!("Failed to load albums from {}: {e:?}", location.to_string());
+            summary.errors += 1;
+            return;
+        }
+    };
+    // source track -> synced track relative to the location root. The file type may differ, so
+    // tracks are matched by their name without extension
+    let root = location.root();
+    let mut track_map = HashMap::new();
+    create_source_album_lookup(
+        &config.source_directories,
+        &config.exclude_patterns,
+        None,
+        scan_tags,
+        config.artist_from_tags,
+        &config.source_layout(),
+    )
+    .values()
+    .for_each(|(src_album, _)| {
+        let Some(dst_album) = dst_albums.get(&src_album.normalized_key()) else {
+            return;
+        };
+        src_album.tracks.iter().for_each(|t| {
+            let stem = Path::new(t).file_stem();
+            if let Some(dst_track) = dst_album
+                .tracks
+                .iter()
+                .find(|dt| Path::new(dt).file_stem() == stem)
+                && let Some(rel) = diff_paths(dst_album.dir_path.join(dst_track), &root)
+            {
+                track_map.insert(
+                    src_album.dir_path.join(t),
+                    rel.to_string_lossy().replace('\\', "/"),
+                );
+            }
+        });
+    });
+    playlists.iter().for_each(|p| {
+        let name = p
+            .file_name()
+            .expect("playlists should have a file name")
+            .to_string_lossy();
+        let res = rewrite_playlist(p, &track_map).and_then(|(content, missing)| {
+            location.write_file(&name, content.as_bytes())?;
+            Ok(missing)
+        });
+        match res {
+            Ok(missing) if missing.is_empty() => println!("Synced playlist {name}"),
+            Ok(missing) => {
                 println!(
-                    "Found {ft:?} source album {:?}. Converting to {dest_ft:?}",
-                    album.overview()
+                    "Synced playlist {name}, {} entries were not synced:",
+                    missing.len()
                 );
-                let res = convert_src_album(src, src_album, dest_ft);
-                if let Ok(res) = res {
-                    return Some(res);
-                } else {
-                    println!("Conversion {} -> {dest_ft} failed!", album.overview());
-                }
+                missing.iter().for_each(|m| println!("  {m}"));
+            }
+            Err(e) => {
+                println!("Failed to sync playlist {p:?}: {e:?}");
+                summary.errors += 1;
             }
         }
-    };
-    None
+    });
 }
 
-/// simply copies the album files to the location in the desired file type
-/// does NOT delete any files in the location
-fn ensure_album_is_in_location(
-    src_album: &Album,
-    dest_ft: &FileType,
-    album_lookup: &HashMap<(String, FileType), (Album, PathBuf)>,
-    location: &mut dyn Location,
-    allow_any: bool,
-) -> Result<FileType> {
-    println!(
-        "Copying source album {} to location {}",
-        src_album.overview(),
-        location.to_string()
-    );
+enum WatchEvent {
+    Fs(notify::Result<notify::Event>),
+    Stop,
+}
 
-    let new_src_album = get_ft_src_album(src_album, dest_ft, album_lookup);
-    if let Some(src_album) = new_src_album {
-        println!("Found source album {}", src_album.overview());
-        location.copy_full_album(&src_album)?;
-        Ok(dest_ft.clone())
-    } else if let Some(ft) = src_album.file_type()
-        && allow_any
+/// whether `path` may have been written by a sync: conversions are stored in the conversion dir
+/// or next to their source in album directories with a ` [<file type>]` suffix
+fn written_by_sync(path: &Path, config: &DirConfig) -> bool {
+    if config
+        .conversion_dir
+        .as_ref()
+        .is_some_and(|dir| path.starts_with(dir))
     {
-        location.copy_full_album(src_album)?;
-        Ok(ft)
-    } else {
-        bail!(
-            "Failed to find proper source fitting source album for {} [{:?}]. dest_ft is {dest_ft}, allow_any={allow_any}",
-            src_album.overview(),
-            src_album.file_type()
-        )
+        return true;
     }
+    let suffixes: Vec<String> = config
+        .destinations
+        .iter()
+        .map(|(_, ft, _)| format!(" [{ft}]"))
+        .collect();
+    path.ancestors().filter_map(|p| p.file_name()).any(|name| {
+        let name = name.to_string_lossy();
+        suffixes
+            .iter()
+            .any(|suffix| name.ends_with(suffix.as_str()))
+    })
 }
 
-fn convert_src_album(src: &Path, src_album: &Album, dest_ft: &FileType) -> Result<Album> {
-    let Some(src_ft) = src_album.file_type() else {
-        bail!(
-            "Failed to determine filetype of source album {}",
-            src_album.overview()
-        )
-    };
-    if dest_ft.is_lossless() && !src_ft.is_lossless() {
-        bail!(
-            "Converting a lossy music format to a lossless one ({dest_ft}) is prohibited! src_album: {}",
-            src_album.overview()
-        )
+/// Watches the source directories and runs an incremental sync once no changes happened for
+/// `debounce`. Stops on Ctrl-C, a running sync stops before its next album.
+fn watch_sources(config: &DirConfig, debounce: Duration) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let stop_tx = tx.clone();
+    ctrlc::set_handler(move || {
+        CANCELLED.store(true, Ordering::SeqCst);
+        let _ = stop_tx.send(WatchEvent::Stop);
+    })
+    .context("Failed to set Ctrl-C handler")?;
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(WatchEvent::Fs(res));
+    })
+    .context("Failed to create file system watcher")?;
+    for dir in &config.source_directories {
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .context(format!("Failed to watch {dir:?}"))?;
+        println!("Watching {dir:?}");
     }
-    let desired_ft = dest_ft.to_possible_value().expect("");
-    let desired_ft = desired_ft.get_name();
-
-    let new_src_album_dir = src_album.album_dir_with_ft(src.to_path_buf(), &Some(dest_ft.clone()));
-
-    let create_album_dir = || {
-        if !new_src_album_dir.exists() {
-            std::fs::create_dir_all(&new_src_album_dir)
-                .context(format!("Failed to create {new_src_album_dir:?}"))?;
-        }
-        Ok::<(), anyhow::Error>(())
-    };
 
-    let copy_cover_files = || {
-        src_album.cover_files.iter().for_each(|cf| {
-            let cf_name = cf.file_name().expect("cover files muts have a file name!");
-            let cf_dest = new_src_album_dir.join(cf_name);
-            println!("COPY: {cf:?} -> {cf_dest:?}");
-            let r = std::fs::copy(cf, &cf_dest);
-            if r.is_err() {
-                println!("Cover copy failed: {r:?}");
+    let mut last_sync = SystemTime::now();
+    let mut pending = false;
+    loop {
+        let event = if pending {
+            rx.recv_timeout(debounce)
+        } else {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        match event {
+            Ok(WatchEvent::Fs(Ok(event))) => {
+                // conversions write to the sources, the events caused by syncs are ignored
+                if (event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove())
+                    && !event.paths.iter().all(|p| written_by_sync(p, config))
+                {
+                    pending = true;
+                }
+            }
+            Ok(WatchEvent::Fs(Err(e))) => println!("Watch error: {e:?}"),
+            Ok(WatchEvent::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                println!("Stopping watcher.");
+                return Ok(());
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                println!("===== Changes detected, syncing =====");
+                let sync_start = SystemTime::now();
+                let options = SyncOptions {
+                    state_dir: Some(data_dir()?),
+                    ..Default::default()
+                };
+                let total = sync_all(config, &options, Some(last_sync), false);
+                total.print("Total");
+                last_sync = sync_start;
+                // changes made during the sync are still queued and start the next one
+                pending = false;
+                println!("Waiting for changes...");
             }
-        });
-    };
-    let get_input_args = |full_input_track_path: &PathBuf| {
-        vec![
-            "-i".to_string(),
-            full_input_track_path.to_str().expect("").to_string(),
-        ]
-    };
-    let get_output_args = |full_output_track_path: &PathBuf| match dest_ft {
-        FileType::MP3 => {
-            let tmp: Vec<String> = [
-                "-ab",
-                "320k",
-                "-map_metadata",
-                "0",
-                "-id3v2_version",
-                "3",
-                "-write_id3v1",
-                "1",
-                full_output_track_path.to_str().expect(""),
-            ]
-            .iter()
-            .map(|a| a.to_string())
-            .collect();
-            Ok(tmp)
         }
-        FileType::Flac => Ok(vec![
-            full_output_track_path
-                .to_str()
-                .context(format!(
-                    "Failed to convert {full_output_track_path:?} to string"
-                ))?
-                .to_string(),
-        ]),
-        ft => bail!("NOT IMPLEMENTED: conversion to {ft:?}"),
-    };
-    let mut new_tracks = vec![];
-    create_album_dir()?;
-    copy_cover_files();
-    let src_ft_str = src_ft
-        .to_possible_value()
-        .expect("src_ft should have a value attached");
-    let src_ft_str = src_ft_str.get_name();
-    src_album.tracks.iter().for_each(|t| {
-        let full_path = src_album.dir_path.join(t);
-        let t_new = t.replace(&format!(".{src_ft_str}"), &format!(".{desired_ft}"));
-        let dst_path = new_src_album_dir.join(&t_new);
-        println!("Track: {full_path:?} --> {dst_path:?}");
-        let mut args = get_input_args(&full_path);
-        if let Ok(mut output_args) = get_output_args(&dst_path) {
-            args.append(&mut output_args);
-        }
-        Command::new("ffmpeg")
-            .args(&args)
-            .output()
-            .expect("failed to convert {full_path:?}");
-        let track = dst_path
-            .file_name()
-            .expect("Destination music file should have a file_name")
-            .to_str()
-            .expect("")
-            .to_string();
-        new_tracks.push(track);
-    });
-    if new_tracks.len() == src_album.tracks.len() {
-        Ok(Album::new(
-            src_album.title.clone(),
-            src_album.artist.clone(),
-            new_tracks,
-            new_src_album_dir,
-            src_album.cover_files.clone(),
-            src_album.parsed_title.clone(),
-            src_album.parsed_artist.clone(),
-        ))
-    } else {
-        bail!("Failed to convert src album: {src_album:?} --> {new_src_album_dir:?} ");
     }
 }
 
-fn sync_to_loc(location: &mut dyn Location, ft: &FileType, config: &DirConfig, allow_any: bool) {
+/// asks the user a yes/no question on stdin. Anything but "y"/"yes" counts as no
+fn confirm(question: &str) -> bool {
+    println!("{question} [y/N]");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// syncs the source albums to `location` and returns what was done
+fn sync_to_loc(
+    location: &mut dyn Location,
+    ft: &FileType,
+    config: &DirConfig,
+    options: &SyncOptions,
+    allow_any: bool,
+    since: Option<SystemTime>,
+    max_size: Option<u64>,
+) -> SyncSummary {
+    let mut summary = SyncSummary::default();
     println!("Loading source albums...");
-    let album_lookup = create_source_album_lookup(&config.source_directories);
+    let album_lookup = create_source_album_lookup(
+        &config.source_directories,
+        &config.exclude_patterns,
+        since,
+        !options.skip_tag_scan,
+        config.artist_from_tags,
+        &config.source_layout(),
+    );
     println!("Loaded source albums.");
-    let albums = location.albums().unwrap();
+    // other editions of the changed albums cannot be looked up by key
+    let albums = if since.is_some() && !options.ignore_editions {
+        // incremental syncs only have to look at the changed albums, which is much cheaper than
+        // listing the whole location on ADB devices
+        let keys: BTreeSet<String> = album_lookup.values().map(|(a, _)| a.key()).collect();
+        location.albums_by_keys(&keys).map(|mut albums| {
+            albums.sort_by(|a1, a2| a1.dir_path.cmp(&a2.dir_path));
+            albums.dedup_by(|a1, a2| a1.dir_path == a2.dir_path);
+            albums
+        })
+    } else {
+        location.albums()
+    };
+    let albums = match albums {
+        Ok(albums) => albums,
+        Err(e) => {
+            println!("Failed to load albums from {}: {e:?}", location.to_string());
+            summary.errors += 1;
+            return summary;
+        }
+    };
     let mut albums_in_loc = HashSet::new();
-    let copy_full_album =
-        |location: &mut dyn Location,
-         album: &Album,
-         albums_in_loc: &mut HashSet<(String, FileType)>| {
-            let res = ensure_album_is_in_location(album, ft, &album_lookup, location, allow_any);
-            if let Ok(ft) = res {
-                albums_in_loc.insert((album.key(), ft.clone()));
-            } else {
-                println!("{res:?}");
+    // editions only count when deciding which source albums are missing. Source albums are
+    // still matched with destination albums by their exact key, so the tracks of one edition are
+    // never copied into another
+    let dest_editions: HashSet<String> = if options.ignore_editions {
+        albums.iter().map(Album::edition_key).collect()
+    } else {
+        HashSet::new()
+    };
+    // the quota covers the files already on the destination, not only the ones copied now
+    let mut used_bytes = if max_size.is_some() {
+        match location.used_bytes() {
+            Ok(used) => used,
+            Err(e) => {
+                println!(
+                    "Failed to determine the size of {}, which has a quota: {e:?}",
+                    location.to_string()
+                );
+                summary.errors += 1;
+                return summary;
             }
-        };
+        }
+    } else {
+        0
+    };
+    let mut conversions = load_conversion_cache(options.state_dir.as_deref());
+    let mut checkpoint = match &options.state_dir {
+        Some(state_dir) => {
+            SyncCheckpoint::load(state_dir, &location.to_string()).unwrap_or_else(|e| {
+                println!("WARNING: failed to load the progress of the previous sync: {e:?}");
+                SyncCheckpoint::default()
+            })
+        }
+        None => SyncCheckpoint::default(),
+    };
+    if checkpoint.len() > 0 {
+        println!(
+            "Resuming the interrupted sync to {}, {} albums were already synced",
+            location.to_string(),
+            checkpoint.len()
+        );
+    }
+    // the checkpoint is checked against the source albums of the lookup, so these are recorded
+    // instead of conversions
+    let mark_done = |checkpoint: &mut SyncCheckpoint, album: &Album| {
+        album_lookup
+            .values()
+            .filter(|(src_album, _)| src_album.normalized_key() == album.normalized_key())
+            .for_each(|(src_album, _)| {
+                if let Err(e) = checkpoint.mark_done(src_album) {
+                    println!("WARNING: failed to store the sync progress: {e:?}");
+                }
+            });
+    };
+    // simply copies the album files to the location in the desired file type
+    // does NOT delete any files in the location
+    let mut copy_full_album = |location: &mut dyn Location,
+                               album: &Album,
+                               albums_in_loc: &mut HashSet<(String, FileType)>,
+                               conversions: &mut ConversionCache,
+                               checkpoint: &mut SyncCheckpoint,
+                               summary: &mut SyncSummary| {
+        println!(
+            "Copying source album {} to location {}",
+            album.overview(),
+            location.to_string()
+        );
+        let res = choose_src_album(
+            album,
+            ft,
+            &album_lookup,
+            allow_any,
+            config,
+            conversions,
+            summary,
+        )
+        .and_then(|(src_album, ft)| {
+            let size = src_album.size_bytes();
+            if let Some(max_size) = max_size
+                && used_bytes + size > max_size
+            {
+                println!(
+                    "Destination quota reached: skipping {} ({size} bytes)",
+                    album.overview()
+                );
+                summary.quota_skipped_albums.push(album.overview());
+                return Ok(None);
+            }
+            location.copy_full_album(&src_album)?;
+            used_bytes += size;
+            Ok(Some(ft))
+        });
+        match res {
+            Ok(Some(ft)) => {
+                albums_in_loc.insert((album.normalized_key(), ft.clone()));
+                summary.albums_copied += 1;
+                mark_done(checkpoint, album);
+                Some(ft)
+            }
+            // the album counts as handled so that its other source file types are not tried
+            Ok(None) => {
+                albums_in_loc.insert((album.normalized_key(), ft.clone()));
+                None
+            }
+            Err(e) => {
+                println!("{e:?}");
+                if e.is::<SizeMismatch>() {
+                    summary.truncated_albums.push(album.overview());
+                }
+                summary.errors += 1;
+                None
+            }
+        }
+    };
 
     // try to replace albums with proper filetypes
     albums.iter().for_each(|a| {
+        if cancelled() || (options.fail_fast && summary.errors > 0) {
+            return;
+        }
         if let Some(aft) = a.file_type() {
+            // albums that were converted before do not have to be converted again unless their
+            // source changed
+            if aft == *ft
+                && !album_lookup.contains_key(&(a.normalized_key(), ft.clone()))
+                && album_lookup.iter().any(|((key, _), (src_album, _))| {
+                    *key == a.normalized_key() && conversions.is_current(src_album, a)
+                })
+            {
+                println!("{} is up to date, skipping conversion", a.overview());
+                albums_in_loc.insert((a.normalized_key(), aft));
+                return;
+            }
+            if aft == *ft
+                && album_lookup.values().any(|(src_album, _)| {
+                    src_album.normalized_key() == a.normalized_key()
+                        && checkpoint.is_done(src_album)
+                })
+            {
+                println!("{} was synced by the interrupted sync", a.overview());
+                albums_in_loc.insert((a.normalized_key(), aft));
+                return;
+            }
             // create proper source album
-            let src_album = get_ft_src_album(a, ft, &album_lookup);
+            let src_album =
+                get_ft_src_album(a, ft, &album_lookup, config, &mut conversions, &mut summary);
 
             // copy files
             if let Some(src_album) = src_album {
                 if aft != *ft {
-                    if !albums
-                        .iter()
-                        .any(|a2| a2.key() == a.key() && a2.file_type() == Some(ft.clone()))
-                    {
+                    if !albums.iter().any(|a2| {
+                        a2.normalized_key() == a.normalized_key()
+                            && a2.file_type() == Some(ft.clone())
+                    }) {
                         println!(
                             "Found {} with wrong filetype (is {aft:?}, but should be {ft:?})",
                             a.overview()
                         );
-                        println!(
-                            "Will attempt to delete album in destination {:?}",
-                            a.dir_path
+                        // the replacement is copied first so that the album is not lost if the
+                        // conversion fails
+                        let copied_ft = copy_full_album(
+                            location,
+                            &src_album,
+                            &mut albums_in_loc,
+                            &mut conversions,
+                            &mut checkpoint,
+                            &mut summary,
                         );
-                        let _ = location.del_album(a);
-                        copy_full_album(location, &src_album, &mut albums_in_loc);
+                        if copied_ft.as_ref() != Some(ft) {
+                            println!(
+                                "Keeping {:?} as no {ft:?} replacement could be copied",
+                                a.dir_path
+                            );
+                        } else if location
+                            .album_dir(&src_album)
+                            .is_ok_and(|dir| dir == a.dir_path)
+                        {
+                            // the replacement was copied into the same directory, deleting it
+                            // would delete the replacement as well
+                            let stale: Vec<String> = a
+                                .tracks
+                                .iter()
+                                .filter(|t| !src_album.tracks.contains(t))
+                                .cloned()
+                                .collect();
+                            let question = format!(
+                                "Delete the {} {aft:?} tracks of {:?}?",
+                                stale.len(),
+                                a.dir_path
+                            );
+                            if options.confirm_deletes && !confirm(&question) {
+                                println!("Keeping the {aft:?} tracks of {:?}", a.dir_path);
+                            } else if let Err(e) = location.del_tracks(a, &stale) {
+                                println!("{e:?}");
+                                summary.errors += 1;
+                            }
+                        } else if options.confirm_deletes
+                            && !confirm(&format!("Delete {:?} from the destination?", a.dir_path))
+                        {
+                            println!("Keeping {:?}", a.dir_path);
+                        } else {
+                            println!(
+                                "Will attempt to delete album in destination {:?}",
+                                a.dir_path
+                            );
+                            if location.del_album(a).is_ok() {
+                                summary.albums_deleted += 1;
+                            } else {
+                                summary.errors += 1;
+                            }
+                        }
                     }
                 } else {
-                    albums_in_loc.insert((a.key(), aft.clone()));
-                    location.copy_missing_files(&src_album, a);
+                    albums_in_loc.insert((a.normalized_key(), aft.clone()));
+                    summary.files_skipped += location.copy_missing_files(&src_album, a);
+                    mark_done(&mut checkpoint, &src_album);
                 }
             } else {
                 println!("Did not find {ft:?} source album for {}", a.overview());
-                albums_in_loc.insert((a.key(), aft.clone()));
+                albums_in_loc.insert((a.normalized_key(), aft.clone()));
             }
         } else {
             println!("ERROR: Failed to determine file type of {}", a.overview());
+            summary.errors += 1;
         }
     });
     // copy over missing albums
-    let album_lookup = create_source_album_lookup(&config.source_directories);
-    album_lookup.values().for_each(|(album, _)| {
-        if !albums_in_loc.iter().any(|(ak, _)| *ak == album.key()) {
-            copy_full_album(location, album, &mut albums_in_loc);
+    let album_lookup = create_source_album_lookup(
+        &config.source_directories,
+        &config.exclude_patterns,
+        since,
+        !options.skip_tag_scan,
+        config.artist_from_tags,
+        &config.source_layout(),
+    );
+    let mut missing_albums: Vec<&Album> = album_lookup
+        .values()
+        .map(|(album, _)| album)
+        .filter(|album| {
+            options
+                .only
+                .as_ref()
+                .is_none_or(|only| only.contains(album))
+        })
+        .collect();
+    if max_size.is_some() {
+        // the most wanted albums are copied first so that they fit
+        match config.quota_order {
+            QuotaOrder::RecentlyAdded => missing_albums.sort_by_key(|a| {
+                std::cmp::Reverse(
+                    std::fs::metadata(&a.dir_path)
+                        .and_then(|m| m.modified())
+                        .ok(),
+                )
+            }),
+            QuotaOrder::Alphabetical => missing_albums.sort_by_key(|a| a.normalized_key()),
+        }
+    }
+    missing_albums.into_iter().for_each(|album| {
+        if cancelled() || (options.fail_fast && summary.errors > 0) {
+            return;
+        }
+        if albums_in_loc
+            .iter()
+            .any(|(ak, _)| *ak == album.normalized_key())
+        {
+            return;
+        }
+        if dest_editions.contains(&album.edition_key()) {
+            println!(
+                "Skipping {} as the destination has another edition of it",
+                album.overview()
+            );
+        } else {
+            copy_full_album(
+                location,
+                album,
+                &mut albums_in_loc,
+                &mut conversions,
+                &mut checkpoint,
+                &mut summary,
+            );
         }
     });
+    // an interrupted or failed sync is resumed next time
+    if summary.errors == 0
+        && !cancelled()
+        && let Err(e) = checkpoint.finish()
+    {
+        println!("WARNING: failed to remove the sync progress: {e:?}");
+    }
+    summary
+}
+
+#[test]
+fn test_set_source_priority_accepts_all_file_types() {
+    let cli = Cli::try_parse_from([
+        "morg",
+        "config",
+        "set-source-priority",
+        "aiff",
+        "WMA",
+        "aif",
+    ])
+    .unwrap();
+    let Commands::Config {
+        subcommand: ConfigCommands::SetSourcePriority { file_types },
+    } = cli.command
+    else {
+        panic!("expected set-source-priority");
+    };
+    assert_eq!(
+        file_types,
+        vec![FileType::Aiff, FileType::Wma, FileType::Aiff]
+    );
+    assert!(Cli::try_parse_from(["morg", "config", "set-source-priority", "mp4"]).is_err());
+}
+
+#[test]
+fn test_written_by_sync() {
+    let mut config: DirConfig = toml::from_str(
+        "source_directories = [\"/music\"]\ndestinations = []\nconversion_dir = \"/music/.conv\"",
+    )
+    .unwrap();
+    config.destinations.push((
+        Destination::PathDest(PathBuf::from("/phone")),
+        FileType::MP3,
+        false,
+    ));
+    let written = |p: &str| written_by_sync(Path::new(p), &config);
+    assert!(written("/music/.conv/Poppy/Choke/01 Choke.mp3"));
+    assert!(written("/music/Poppy/Choke [mp3]/01 Choke.mp3"));
+    assert!(written("/music/Poppy/Choke [mp3]"));
+    // albums added while a sync runs have to be synced as well
+    assert!(!written("/music/Poppy/Choke/01 Choke.flac"));
+    assert!(!written("/music/Poppy/Choke [opus]/01 Choke.opus"));
+}
+
+#[test]
+fn test_parse_since() {
+    assert_eq!(
+        parse_since("1700000000").unwrap(),
+        UNIX_EPOCH + Duration::from_secs(1700000000)
+    );
+    assert_eq!(parse_since("1970-01-01").unwrap(), UNIX_EPOCH);
+    assert_eq!(
+        parse_since("2024-03-01").unwrap(),
+        UNIX_EPOCH + Duration::from_secs(1709251200)
+    );
+    let week_ago = parse_since("1w").unwrap();
+    let elapsed = SystemTime::now().duration_since(week_ago).unwrap();
+    assert!(elapsed >= Duration::from_secs(7 * 24 * 60 * 60));
+    assert!(elapsed < Duration::from_secs(7 * 24 * 60 * 60 + 60));
+    assert!(parse_since("3x").is_err());
+    assert!(parse_since("2024-13-01").is_err());
+    assert!(parse_since("yesterday").is_err());
+}
+
+#[test]
+fn test_source_priority() {
+    let config: DirConfig = toml::from_str(
+        "source_directories = []\ndestinations = []\nconversion_source_priority = [\"MP3\", \"aac\", \"flac\"]",
+    )
+    .unwrap();
+    use FileType::*;
+    assert_eq!(
+        config.source_priority(),
+        vec![MP3, Flac, Wav, Aiff, M4A, Ogg, Opus, Wma]
+    );
+    let config: DirConfig = toml::from_str("source_directories = []\ndestinations = []").unwrap();
+    assert_eq!(
+        config.source_priority(),
+        default_conversion_source_priority()
+    );
+}
+
+#[test]
+fn test_id3_version_config() {
+    let config: DirConfig = toml::from_str("source_directories = []\ndestinations = []").unwrap();
+    assert_eq!(config.id3_version.ffmpeg_arg(), "3");
+    let config: DirConfig =
+        toml::from_str("source_directories = []\ndestinations = []\nid3_version = \"V24\"")
+            .unwrap();
+    assert_eq!(config.id3_version.ffmpeg_arg(), "4");
+}
+
+#[test]
+fn test_file_type_subdirs() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("ft_subdirs_test");
+    let config: DirConfig = toml::from_str(&format!(
+        "source_directories = []\ndestinations = []\nfile_type_subdir_destinations = [{:?}]",
+        root.as_path()
+    ))
+    .unwrap();
+    assert_eq!(
+        config.destination_dir(&root, &FileType::Flac),
+        root.join("FLAC")
+    );
+    let other = root.join("other");
+    assert_eq!(config.destination_dir(&other, &FileType::Flac), other);
+
+    for (dir, track) in [
+        ("FLAC/Poppy/Choke", "01.flac"),
+        ("MP3/Poppy/I Disagree", "01.mp3"),
+    ] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+        std::fs::write(root.join(dir).join(track), "").unwrap();
+    }
+    let mut keys: Vec<String> = config
+        .destination_albums(&root)
+        .iter()
+        .map(|a| a.key())
+        .collect();
+    keys.sort();
+    assert_eq!(keys, vec!["Poppy###Choke", "Poppy###I Disagree"]);
+}
+
+#[test]
+fn test_aux_files() {
+    use crate::test_util::TestDir;
+    let dir = TestDir::new("aux_test");
+    [
+        "01 Choke.flac",
+        "cover.jpg",
+        "Choke.CUE",
+        "rip.log",
+        "booklet.pdf",
+    ]
+    .iter()
+    .for_each(|f| std::fs::write(dir.join(f), "").unwrap());
+    let names: Vec<String> = aux_files(&dir, &default_aux_file_extensions())
+        .iter()
+        .map(|f| f.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names, vec!["Choke.CUE", "booklet.pdf", "rip.log"]);
+    assert!(aux_files(&dir, &[]).is_empty());
+}
+
+#[test]
+fn test_album_selection() {
+    use crate::test_util::TestDir;
+    let dir = TestDir::new("selection_test");
+    let album =
+        |artist: &str, title: &str, dir_path: PathBuf| Album::test(artist, title, dir_path, &[]);
+    let list = dir.join("favorites.txt");
+    std::fs::write(
+        &list,
+        "# favorites\npoppy###CHOKE\n\nVarious Artists###Hits\n",
+    )
+    .unwrap();
+    let selection = AlbumSelection::read(&list).unwrap();
+    assert!(selection.contains(&album("Poppy", "Choke", dir.join("a"))));
+    assert!(selection.contains(&album("VA", "Hits", dir.join("b"))));
+    assert!(!selection.contains(&album("Poppy", "I Disagree", dir.join("c"))));
+
+    let playlist = dir.join("favorites.m3u");
+    std::fs::write(&playlist, "#EXTM3U\nPoppy/Choke/01 Choke.mp3\n").unwrap();
+    let selection = AlbumSelection::read(&playlist).unwrap();
+    assert!(selection.contains(&album("Poppy", "Other", dir.join("Poppy/Choke"))));
+    assert!(!selection.contains(&album("Poppy", "Choke", dir.join("Poppy/I Disagree"))));
+
+    std::fs::write(&list, "Poppy - Choke\n").unwrap();
+    assert!(AlbumSelection::read(&list).is_err());
+}
+
+#[test]
+fn test_junk_files() {
+    use crate::test_util::TestDir;
+    let dir = TestDir::new("junk_test");
+    let album_dir = dir.join("Poppy").join("Choke");
+    std::fs::create_dir_all(&album_dir).unwrap();
+    [
+        "Thumbs.db",
+        "01 Choke.mp3",
+        "cover.jpg",
+        "rip.log",
+        "notes.TXT",
+        "desktop.ini",
+    ]
+    .iter()
+    .for_each(|f| std::fs::write(album_dir.join(f), "").unwrap());
+    let patterns: Vec<glob::Pattern> = default_junk_patterns()
+        .iter()
+        .chain(["*.log".to_string(), "*.mp3".to_string()].iter())
+        .map(|p| glob::Pattern::new(p).unwrap())
+        .collect();
+    let names: Vec<String> = junk_files(&dir, &[], &patterns, &default_aux_file_extensions())
+        .iter()
+        .map(|f| f.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(names, vec!["Thumbs.db", "desktop.ini", "notes.TXT"]);
+
+    // excluded directories and symlinked directories are not scanned
+    let outside = TestDir::new("junk_test_outside");
+    std::fs::write(outside.join("Thumbs.db"), "").unwrap();
+    #[cfg(windows)]
+    let linked = std::os::windows::fs::symlink_dir(&*outside, dir.join("Linked"));
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(&*outside, dir.join("Linked"));
+    let bootlegs = dir.join("Bootlegs");
+    std::fs::create_dir_all(&bootlegs).unwrap();
+    std::fs::write(bootlegs.join("Thumbs.db"), "").unwrap();
+    let files = junk_files(
+        &dir,
+        &["Bootlegs".to_string()],
+        &patterns,
+        &default_aux_file_extensions(),
+    );
+    assert_eq!(files.len(), 3);
+    assert!(files.iter().all(|f| f.starts_with(&album_dir)));
+    // creating symlinks may need extra privileges
+    if linked.is_ok() {
+        assert!(dir.join("Linked").join("Thumbs.db").exists());
+    }
+}
+
+#[test]
+fn test_destination_status() {
+    let album = |title: &str, track: &str| Album::test("Poppy", title, title, &[track]);
+    let src = [
+        album("Choke", "01 Choke.flac"),
+        album("Choke", "01 Choke.mp3"),
+        album("I Disagree", "01 Concrete.flac"),
+        album("Flux", "01 Flux.flac"),
+    ];
+    let src: Vec<&Album> = src.iter().collect();
+    let dst = [
+        album("Choke", "01 Choke.flac"),
+        album("I Disagree", "01 Concrete.flac"),
+        album("Poppy.Computer", "01 Computer Boy.mp3"),
+    ];
+    let status = destination_status(&src, &dst, &FileType::MP3, false);
+    assert_eq!(status.missing, vec![src[3].overview()]);
+    assert_eq!(
+        status.wrong_format,
+        vec![dst[0].overview(), dst[1].overview()]
+    );
+    assert_eq!(status.extraneous, vec![dst[2].overview()]);
+    // I Disagree is not available as MP3
+    let status = destination_status(&src, &dst, &FileType::MP3, true);
+    assert_eq!(status.wrong_format, vec![dst[0].overview()]);
+}
+
+#[test]
+fn test_artist_dir_moves() {
+    let album = |artist: &str, parsed_artist: &str, dir: &str| {
+        let mut album = Album::test(
+            parsed_artist,
+            "Lemonade",
+            dir,
+            &["01 Pray You Catch Me.flac"],
+        );
+        album.artist = artist.to_string();
+        album
+    };
+    let albums = [
+        album("Beyoncé", "Beyonce", "music/Beyonce/Lemonade"),
+        album("Beyoncé", "Beyoncé", "music/Beyoncé/Lemonade"),
+        album("Various Artists", "Beyonce", "music/Beyonce/Lemonade"),
+        // not stored in an artist folder
+        album("Beyoncé", "Beyonce", "music/Beyonce - Lemonade"),
+    ];
+    assert_eq!(
+        artist_dir_moves(&albums),
+        vec![(
+            PathBuf::from("music/Beyonce/Lemonade"),
+            PathBuf::from("music/Beyoncé/Lemonade")
+        )]
+    );
+    let mut a = albums[0].clone();
+    a.use_tag_artist();
+    assert_eq!(a.normalized_key(), albums[1].normalized_key());
+    assert_eq!(a.key(), "Beyoncé###Lemonade");
+}
+
+#[test]
+fn test_lossy_only_albums() {
+    let album = |title: &str, track: &str| Album::test("Poppy", title, title, &[track]);
+    let albums = [
+        album("Choke", "01 Choke.mp3"),
+        album("Choke", "01 Choke.m4a"),
+        album("I Disagree", "01 Concrete.flac"),
+        album("Flux", "01 Flux.opus"),
+    ];
+    let lossy_only = lossy_only_albums(&albums);
+    assert_eq!(lossy_only.len(), 2);
+    assert_eq!(lossy_only[0].0.parsed_title, "Choke");
+    assert_eq!(lossy_only[0].1.len(), 2);
+    assert_eq!(lossy_only[1].0.parsed_title, "Flux");
+}
+
+#[test]
+fn test_clean_title() {
+    let config: DirConfig = toml::from_str("source_directories = []\ndestinations = []").unwrap();
+    let title_rules = config.title_rules().unwrap();
+    let album = path_to_details(
+        PathBuf::from("/music/Poppy/Negative Spaces/04 yesterday.mp3"),
+        PathBuf::from("/music"),
+    )
+    .unwrap();
+    let album_info = AlbumInfo {
+        artist: "Poppy".to_string(),
+        title: "Negative Spaces".to_string(),
+        year: None,
+    };
+    let clean = |t: &str| music_tags::clean_title(t, &album, &album_info, &title_rules);
+    assert_eq!(clean("yesterday (Official Audio)"), "yesterday");
+    assert_eq!(clean("yesterday [Official Music Video]"), "yesterday");
+    assert_eq!(clean("yesterday [HD]"), "yesterday");
+    assert_eq!(clean(". yesterday"), "yesterday");
+    assert_eq!(clean("Poppy - yesterday (hq)"), "yesterday");
+    assert_eq!(clean("yesterday (Live)"), "yesterday (Live)");
+    assert_eq!(clean("[HD]"), "[HD]");
+    assert_eq!(clean("...Baby One More Time"), "...Baby One More Time");
+    assert_eq!(clean("_Underscore"), "_Underscore");
+    let track_info = parse_track_info("04 . yesterday [HD].mp3", &album, &album_info, &title_rules);
+    assert_eq!(track_info.title, "yesterday");
+    assert_eq!(track_info.track_number, Some(4));
+    let track_info = parse_track_info(
+        "01 ...Baby One More Time.mp3",
+        &album,
+        &album_info,
+        &title_rules,
+    );
+    assert_eq!(track_info.title, "...Baby One More Time");
+}
+
+#[test]
+fn test_inconsistent_album_artists() {
+    let mut album = path_to_details(
+        PathBuf::from("/music/Poppy/Choke/01 Choke.mp3"),
+        PathBuf::from("/music"),
+    )
+    .unwrap();
+    assert_eq!(inconsistent_album_artists(&album), None);
+    album.album_artist_counts = vec![("Poppy".to_string(), 5)];
+    assert_eq!(inconsistent_album_artists(&album), None);
+    album.album_artist_counts = vec![("Poppy".to_string(), 5), ("poppy".to_string(), 1)];
+    assert_eq!(
+        inconsistent_album_artists(&album).unwrap(),
+        "\"Poppy\" (5 tracks), \"poppy\" (1 track)"
+    );
+}
+
+#[test]
+fn test_mp3_quality_lame_args() {
+    assert_eq!(Mp3Quality::Cbr(320).lame_args(), ["--cbr", "-b", "320"]);
+    assert_eq!(Mp3Quality::Vbr(2).lame_args(), ["-V", "2"]);
+}
+
+#[test]
+fn test_cover_below_min_size() {
+    assert!(cover_below_min_size((300, 300), 500));
+    assert!(cover_below_min_size((1200, 450), 500));
+    assert!(!cover_below_min_size((500, 500), 500));
+    assert!(!cover_below_min_size((1400, 1400), 1000));
+}
+
+#[test]
+fn test_fallback_src_album() {
+    let album = |tracks: &[&str]| Album::test("Poppy", "Choke", "/music/Poppy/Choke", tracks);
+    let wav = album(&["01 Choke.wav"]);
+    let mp3 = album(&["01 Choke.mp3"]);
+    let mut lookup = HashMap::new();
+    lookup.insert(
+        (wav.normalized_key(), FileType::Wav),
+        (wav.clone(), PathBuf::from("/music")),
+    );
+    lookup.insert(
+        (mp3.normalized_key(), FileType::MP3),
+        (mp3.clone(), PathBuf::from("/music")),
+    );
+    let config: DirConfig =
+        toml::from_str("source_directories = []\ndestinations = []\nfallback_priority = [\"mp3\"]")
+            .unwrap();
+    let (chosen, ft) = fallback_src_album(&wav, &lookup, &config.fallback_priority).unwrap();
+    assert_eq!(ft, FileType::MP3);
+    assert_eq!(chosen.tracks, mp3.tracks);
+    // without a fallback priority, the album is kept instead of preferring a lossless file type
+    let config: DirConfig = toml::from_str("source_directories = []\ndestinations = []").unwrap();
+    let (chosen, ft) = fallback_src_album(&mp3, &lookup, &config.fallback_priority).unwrap();
+    assert_eq!(ft, FileType::MP3);
+    assert_eq!(chosen.tracks, mp3.tracks);
+    let (_, ft) = fallback_src_album(&wav, &HashMap::new(), &config.fallback_priority).unwrap();
+    assert_eq!(ft, FileType::Wav);
+}
+
+#[test]
+fn test_sync_replaces_wrong_format_in_same_dir() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("sync_same_dir");
+    let src = root.join("src");
+    let dst = root.join("dst");
+    std::fs::create_dir_all(src.join("Poppy/Choke")).unwrap();
+    std::fs::create_dir_all(dst.join("Poppy/Choke")).unwrap();
+    std::fs::write(src.join("Poppy/Choke/01 Choke.mp3"), "mp3").unwrap();
+    std::fs::write(dst.join("Poppy/Choke/01 Choke.flac"), "flac").unwrap();
+    let config: DirConfig = toml::from_str(&format!(
+        "source_directories = [{src:?}]\ndestinations = []\nconversion_dir = {:?}",
+        root.join("conv")
+    ))
+    .unwrap();
+    let mut loc = DirLocation::new(dst.clone(), vec![], false, false, FileNameRules::Fat32);
+    let options = SyncOptions {
+        state_dir: Some(root.join("state")),
+        ..Default::default()
+    };
+    let summary = sync_to_loc(
+        &mut loc,
+        &FileType::MP3,
+        &config,
+        &options,
+        false,
+        None,
+        None,
+    );
+    assert_eq!(summary.errors, 0);
+    // the replacement lands in the directory of the flac album and must survive
+    assert!(dst.join("Poppy/Choke/01 Choke.mp3").exists());
+    assert!(!dst.join("Poppy/Choke/01 Choke.flac").exists());
+}
+
+#[test]
+fn test_sync_ignore_editions() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("sync_editions");
+    let src = root.join("src");
+    let dst = root.join("dst");
+    let deluxe = "Poppy/Choke (Deluxe Edition)";
+    std::fs::create_dir_all(src.join("Poppy/Choke")).unwrap();
+    std::fs::create_dir_all(src.join(deluxe)).unwrap();
+    std::fs::create_dir_all(dst.join("Poppy/Choke")).unwrap();
+    std::fs::write(src.join("Poppy/Choke/01 Choke.mp3"), "mp3").unwrap();
+    std::fs::write(src.join(deluxe).join("01 Choke.mp3"), "mp3").unwrap();
+    std::fs::write(src.join(deluxe).join("02 Bonus.mp3"), "mp3").unwrap();
+    std::fs::write(dst.join("Poppy/Choke/01 Choke.mp3"), "mp3").unwrap();
+    let config: DirConfig = toml::from_str(&format!(
+        "source_directories = [{src:?}]\ndestinations = []"
+    ))
+    .unwrap();
+    let mut loc = DirLocation::new(dst.clone(), vec![], false, false, FileNameRules::Fat32);
+    let options = SyncOptions {
+        ignore_editions: true,
+        state_dir: Some(root.join("state")),
+        ..Default::default()
+    };
+    let summary = sync_to_loc(
+        &mut loc,
+        &FileType::MP3,
+        &config,
+        &options,
+        false,
+        None,
+        None,
+    );
+    assert_eq!(summary.errors, 0);
+    assert_eq!(summary.albums_copied, 0);
+    // the deluxe edition is neither copied nor merged into the plain album
+    assert!(!dst.join(deluxe).exists());
+    assert!(!dst.join("Poppy/Choke/02 Bonus.mp3").exists());
+}
+
+#[test]
+fn test_sync_quota_counts_existing_files() {
+    use crate::test_util::TestDir;
+    let root = TestDir::new("sync_quota");
+    let src = root.join("src");
+    let dst = root.join("dst");
+    std::fs::create_dir_all(src.join("Poppy/Choke")).unwrap();
+    std::fs::create_dir_all(dst.join("Poppy/I Disagree")).unwrap();
+    std::fs::write(src.join("Poppy/Choke/01 Choke.mp3"), "12345").unwrap();
+    std::fs::write(dst.join("Poppy/I Disagree/01 Concrete.mp3"), "1234567890").unwrap();
+    let config: DirConfig = toml::from_str(&format!(
+        "source_directories = [{src:?}]\ndestinations = []"
+    ))
+    .unwrap();
+    let mut loc = DirLocation::new(dst.clone(), vec![], false, false, FileNameRules::Fat32);
+    assert_eq!(loc.used_bytes().unwrap(), 10);
+    let options = SyncOptions {
+        state_dir: Some(root.join("state")),
+        ..Default::default()
+    };
+    // the album alone fits, but not together with the files already on the destination
+    let summary = sync_to_loc(
+        &mut loc,
+        &FileType::MP3,
+        &config,
+        &options,
+        false,
+        None,
+        Some(12),
+    );
+    assert_eq!(summary.quota_skipped_albums.len(), 1);
+    assert!(!dst.join("Poppy/Choke").exists());
 }
@@ -1,14 +1,23 @@
-use core::time;
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Result, bail};
 use distance::levenshtein;
 use json::JsonValue;
-use reqwest::header::USER_AGENT;
+use pathdiff::diff_paths;
+use reqwest::header::{CONTENT_TYPE, HeaderMap, RETRY_AFTER, USER_AGENT};
 use serde::{Deserialize, Serialize};
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use crate::Album;
+use crate::{
+    Album,
+    album::{VARIOUS_ARTISTS, is_various_artists},
+    location::Location,
+    music_tags::{release_ids_from_tags, year_from_tags},
+};
 
 #[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
 pub struct Keys {
@@ -17,7 +26,7 @@ pub struct Keys {
 }
 
 impl Keys {
-    fn keys_file() -> Result<PathBuf> {
+    pub fn keys_file() -> Result<PathBuf> {
         let dirs = directories::ProjectDirs::from("TF", "TF", "morg")
             .context("Failed to construct config path!")?;
         let keys_file = dirs.config_local_dir().join("keys.toml");
@@ -30,7 +39,7 @@ impl Keys {
                 "Could not read {keys_file:?}. Does the file exist?"
             ))?
             .replace("\r\n", "\n");
-        toml::from_str(&text).context("Could not parse keys from {keys_file:?}")
+        toml::from_str(&text).context(format!("Could not parse keys from {keys_file:?}"))
     }
 }
 
@@ -41,19 +50,118 @@ pub struct AlbumInfo {
     pub year: Option<i32>,
 }
 
+/// discogs search filters. They are only applied if the unfiltered search returns too many
+/// results, so that EPs, singles and box sets can still be matched
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct DiscogsFilters {
+    pub format: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Default for DiscogsFilters {
+    fn default() -> Self {
+        DiscogsFilters {
+            format: Some("album".to_string()),
+            description: Some("Official Release".to_string()),
+        }
+    }
+}
+
+impl DiscogsFilters {
+    fn params(&self) -> Vec<(&'static str, String)> {
+        [("format", &self.format), ("description", &self.description)]
+            .into_iter()
+            .filter_map(|(k, v)| v.clone().map(|v| (k, v)))
+            .collect()
+    }
+}
+
+/// unfiltered searches with at least this many results are narrowed with the [`DiscogsFilters`]
+const NARROW_THRESHOLD: usize = 50;
+
+#[derive(Deserialize, Serialize)]
+struct CacheEntry {
+    #[serde(flatten)]
+    info: AlbumInfo,
+    /// unix timestamp (seconds) of when the info was fetched
+    #[serde(default)]
+    fetched_at: Option<u64>,
+    /// cover image of the matched release
+    #[serde(default)]
+    cover_url: Option<String>,
+    /// whether `cover_url` was looked up. Entries cached before cover urls were stored have no
+    /// `cover_url` even if their release has a cover
+    #[serde(default)]
+    cover_checked: bool,
+}
+
+impl CacheEntry {
+    /// entries without a timestamp are considered stale once a ttl is set
+    fn is_stale(&self, ttl: Option<Duration>) -> bool {
+        let Some(ttl) = ttl else {
+            return false;
+        };
+        match self.fetched_at {
+            Some(fetched_at) => now_secs().saturating_sub(fetched_at) > ttl.as_secs(),
+            None => true,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct MusicInfoCache {
-    cache: HashMap<String, AlbumInfo>,
+    cache: HashMap<String, CacheEntry>,
     refresh: bool,
+    /// entries older than this are fetched again
+    #[serde(skip)]
+    ttl: Option<Duration>,
+    /// proxy for discogs and cover requests. `HTTP_PROXY`/`HTTPS_PROXY` are used if not set
+    #[serde(skip)]
+    proxy: Option<String>,
+    #[serde(skip)]
+    filters: DiscogsFilters,
+    #[serde(skip)]
+    rate_limiter: RateLimiter,
+    /// only serve cached entries and never access discogs
+    #[serde(skip)]
+    offline: bool,
 }
 impl MusicInfoCache {
     pub fn new() -> Self {
         MusicInfoCache {
             cache: HashMap::new(),
             refresh: true,
+            ttl: None,
+            proxy: None,
+            filters: DiscogsFilters::default(),
+            rate_limiter: RateLimiter::default(),
+            offline: false,
         }
     }
-    pub fn load(refresh: bool) -> Result<Self> {
+
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: Option<String>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn with_filters(mut self, filters: DiscogsFilters) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    pub fn load(refresh: bool, ttl: Option<Duration>) -> Result<Self> {
         let dirs = directories::ProjectDirs::from("TF", "TF", "morg")
             .context("Failed to construct data path!")?;
         if !dirs.data_local_dir().exists() {
@@ -69,9 +177,12 @@ impl MusicInfoCache {
             let mut res: MusicInfoCache =
                 toml::from_str(&text).context("Could not parse music info from {info_file:?}")?;
             res.refresh = refresh;
+            res.ttl = ttl;
             Ok(res)
         } else {
-            Ok(MusicInfoCache::new())
+            let mut res = MusicInfoCache::new();
+            res.ttl = ttl;
+            Ok(res)
         }
     }
 
@@ -84,109 +195,609 @@ impl MusicInfoCache {
         Ok(())
     }
 
-    pub fn get_album_info(&mut self, album: &Album) -> Result<AlbumInfo> {
+    /// removes the entry for `key` (see [`Album::key`]). Returns whether there was one
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.cache.remove(key).is_some()
+    }
+
+    /// removes all entries and returns how many there were
+    pub fn clear(&mut self) -> usize {
+        let n = self.cache.len();
+        self.cache.clear();
+        n
+    }
+
+    /// returns the cache entry of the album, fetching it from discogs if it is missing, stale or
+    /// if `force` is set. In offline mode, cached entries are returned as they are
+    fn entry(&mut self, album: &Album, force: bool) -> Result<&CacheEntry> {
         let key = album.key();
-        if self.refresh || !self.cache.contains_key(&key) {
-            let (album_info, limit) = get_album_info_discogs(album)?;
-            self.cache.insert(key, album_info.clone());
+        if self.offline {
+            return self.cache.get(&key).context(format!(
+                "{} is not cached and discogs cannot be accessed in offline mode",
+                album.overview()
+            ));
+        }
+        let stale = self.cache.get(&key).is_none_or(|e| e.is_stale(self.ttl));
+        if self.refresh || stale || force {
+            let (album_info, cover_url) = lookup_album_info(
+                album,
+                self.proxy.as_deref(),
+                &self.filters,
+                &mut self.rate_limiter,
+            )?;
+            self.cache.insert(
+                key.clone(),
+                CacheEntry {
+                    info: album_info,
+                    fetched_at: Some(now_secs()),
+                    cover_url,
+                    cover_checked: true,
+                },
+            );
             self.store().context("Failed to store cache")?;
-            if limit <= 1 {
-                println!("Waiting 60s to avoid rate limit...");
+        }
+        self.cache.get(&key).context("not found in cache")
+    }
 
-                std::thread::sleep(time::Duration::from_secs(60));
-            }
-            Ok(album_info)
-        } else {
-            self.cache.get(&key).context("not found in cache").cloned()
+    /// the album info from discogs. Albums with an override skip the lookup
+    pub fn get_album_info(&mut self, album: &Album) -> Result<AlbumInfo> {
+        if let Some(album_override) = &album.album_override {
+            return Ok(AlbumInfo {
+                artist: album_override
+                    .artist
+                    .clone()
+                    .unwrap_or_else(|| album.artist.clone()),
+                title: album_override
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| album.title_without_filetype()),
+                year: album_override.year.or_else(|| year_from_tags(album)),
+            });
         }
+        self.entry(album, false).map(|e| e.info.clone())
+    }
+
+    /// the cover url of the album's discogs release. Entries cached before cover urls were stored
+    /// are fetched again
+    pub fn get_cover_url(&mut self, album: &Album) -> Result<Option<String>> {
+        let entry = self.entry(album, false)?;
+        if entry.cover_checked {
+            return Ok(entry.cover_url.clone());
+        }
+        Ok(self.entry(album, true)?.cover_url.clone())
     }
 }
 
-fn get_album_json(album: &Album) -> Result<(JsonValue, i32)> {
-    let keys = Keys::parse()?;
-    let runtime = tokio::runtime::Runtime::new().unwrap();
-    let client = reqwest::Client::new();
+/// searches discogs with `params` (the keys and user agent are added) and returns the results.
+/// `rate_limiter` delays the request if necessary. Rate limited requests are retried once
+fn search_discogs(
+    runtime: &tokio::runtime::Runtime,
+    client: &reqwest::Client,
+    keys: &Keys,
+    params: &[(&str, String)],
+    rate_limiter: &mut RateLimiter,
+) -> Result<JsonValue> {
     let url = "https://api.discogs.com/database/search";
-    let params = [
-        ("artist", album.artist.to_string()),
-        ("album", album.title.to_string()),
-        ("format", "album".to_string()),
-        //("per_page", "30"),
-        ("page", "5".to_string()),
-        ("description", "Official Release".to_string()),
+    let mut params = params.to_vec();
+    params.extend([
         ("key", keys.key.to_string()),
         ("secret", keys.secret.to_string()),
-        (
-            "user-agent",
-            "morg: Music organizer, yamakantor@mnet-online.de".to_string(),
-        ),
-    ];
-    let res = client
-        .get(url)
-        .header(
-            USER_AGENT,
-            "morg: Music organizer, yamakantor@mnet-online.de",
-        )
-        .query(&params)
-        .send();
-    let res = runtime.block_on(res);
-    let res = res.unwrap();
-    let headers = res.headers();
-    let mut limit = 0;
-    if let Some(rl) = headers.get("X-Discogs-Ratelimit-Remaining")
-        && let Ok(rl) = rl.to_str()
-    {
-        limit = rl.parse().expect("rate limit should be a valid i32");
+        ("user-agent", MORG_USER_AGENT.to_string()),
+    ]);
+    let mut retried = false;
+    loop {
+        rate_limiter.wait();
+        let res = client
+            .get(url)
+            .header(USER_AGENT, MORG_USER_AGENT)
+            .query(&params)
+            .send();
+        let res = runtime
+            .block_on(res)
+            .context(format!("Failed to reach discogs. {PROXY_HINT}"))?;
+        rate_limiter.update(res.headers());
+        if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS && !retried {
+            rate_limiter.throttled();
+            retried = true;
+            continue;
+        }
+        check_discogs_auth(res.status())?;
+        let content = runtime.block_on(res.error_for_status()?.text())?;
+        let parsed = json::parse(&content)?;
+        return Ok(parsed["results"].clone());
+    }
+}
+
+/// user agent of all requests, discogs and MusicBrainz require one that identifies the application
+const MORG_USER_AGENT: &str = "morg: Music organizer, yamakantor@mnet-online.de";
+
+/// appended to the errors of failed requests
+const PROXY_HINT: &str =
+    "If you are behind a proxy, check the proxy config and HTTP_PROXY/HTTPS_PROXY";
+
+/// a client that sends [`MORG_USER_AGENT`] and uses `proxy` if it is set. Otherwise reqwest uses
+/// HTTP_PROXY/HTTPS_PROXY from the environment
+fn http_client(proxy: Option<&str>) -> Result<reqwest::blocking::Client> {
+    let mut builder = reqwest::blocking::Client::builder().user_agent(MORG_USER_AGENT);
+    if let Some(proxy) = proxy {
+        builder =
+            builder.proxy(reqwest::Proxy::all(proxy).context(format!("Invalid proxy {proxy:?}"))?);
     }
-    let content = runtime.block_on(res.text())?;
-    let parsed = json::parse(&content)?;
+    builder.build().context("Failed to create the http client")
+}
 
-    let search_title = format!("{} - {}", album.artist, album.title);
-    parsed["results"]
-        .clone()
+/// discogs counts the requests of a moving one minute window
+const DISCOGS_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// the header with the number of discogs requests remaining in the current window
+const DISCOGS_RATELIMIT_REMAINING: &str = "X-Discogs-Ratelimit-Remaining";
+
+/// discogs rejected the keys
+#[derive(Debug)]
+struct DiscogsAuthError;
+
+impl std::fmt::Display for DiscogsAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match Keys::keys_file() {
+            Ok(keys_file) => write!(
+                f,
+                "Discogs rejected the credentials, check your key/secret in {keys_file:?}"
+            ),
+            Err(_) => f.write_str("Discogs rejected the credentials, check your key/secret"),
+        }
+    }
+}
+
+impl std::error::Error for DiscogsAuthError {}
+
+/// fails with a [`DiscogsAuthError`] if discogs rejected the keys
+fn check_discogs_auth(status: reqwest::StatusCode) -> Result<()> {
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        bail!(DiscogsAuthError);
+    }
+    Ok(())
+}
+
+/// makes a single authenticated discogs request to check the keys. Returns the number of
+/// requests remaining in the current rate limit window, if discogs reported it
+pub fn test_discogs(proxy: Option<&str>) -> Result<Option<u32>> {
+    let keys = Keys::parse()?;
+    let res = http_client(proxy)?
+        .get("https://api.discogs.com/database/search")
+        .query(&[
+            ("q", "morg"),
+            ("per_page", "1"),
+            ("key", &keys.key),
+            ("secret", &keys.secret),
+        ])
+        .send()
+        .context(format!("Failed to reach discogs. {PROXY_HINT}"))?;
+    check_discogs_auth(res.status())?;
+    let remaining = res
+        .headers()
+        .get(DISCOGS_RATELIMIT_REMAINING)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|r| r.parse().ok());
+    res.error_for_status()?;
+    Ok(remaining)
+}
+
+/// spaces discogs requests according to the `X-Discogs-Ratelimit-Remaining` and `Retry-After`
+/// headers. Once no requests remain, it only waits until the oldest request of the current window
+/// expires. The sleep function can be replaced, e.g. in tests
+pub struct RateLimiter {
+    window: Duration,
+    /// when the requests of the current window were sent
+    requests: VecDeque<Instant>,
+    remaining: Option<u32>,
+    retry_after: Option<Duration>,
+    sleep: Box<dyn FnMut(Duration) + Send>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(Box::new(std::thread::sleep))
+    }
+}
+
+impl RateLimiter {
+    pub fn new(sleep: Box<dyn FnMut(Duration) + Send>) -> Self {
+        RateLimiter {
+            window: DISCOGS_RATE_LIMIT_WINDOW,
+            requests: VecDeque::new(),
+            remaining: None,
+            retry_after: None,
+            sleep,
+        }
+    }
+
+    /// waits until the next request may be sent and records it
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        while self
+            .requests
+            .front()
+            .is_some_and(|r| now.duration_since(*r) >= self.window)
+        {
+            self.requests.pop_front();
+        }
+        let wait = match self.retry_after.take() {
+            Some(retry_after) => retry_after,
+            None if self.remaining.is_some_and(|r| r <= 1) => self
+                .requests
+                .front()
+                .map(|oldest| self.window.saturating_sub(now.duration_since(*oldest)))
+                .unwrap_or_default(),
+            None => Duration::ZERO,
+        };
+        if !wait.is_zero() {
+            println!(
+                "Waiting {}s to avoid rate limit...",
+                wait.as_secs_f32().ceil()
+            );
+            (self.sleep)(wait);
+            self.remaining = None;
+        }
+        self.requests.push_back(Instant::now());
+    }
+
+    /// makes the next [`RateLimiter::wait`] wait for the `Retry-After` of a rejected request, or
+    /// a whole window if discogs did not send one
+    pub fn throttled(&mut self) {
+        self.retry_after.get_or_insert(self.window);
+    }
+
+    /// reads the rate limit headers of a discogs response
+    pub fn update(&mut self, headers: &HeaderMap) {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        self.remaining = header(DISCOGS_RATELIMIT_REMAINING).and_then(|r| r.parse().ok());
+        self.retry_after = header(RETRY_AFTER.as_str())
+            .and_then(|r| r.parse().ok())
+            .map(Duration::from_secs);
+    }
+}
+
+/// only the digits of a barcode, discogs lists them with varying separators
+fn normalize_barcode(barcode: &str) -> String {
+    barcode.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+/// picks the best search result. A result with the given barcode is preferred, otherwise the
+/// result whose title is closest to `search_title` (`<artist> - <title>`) is used
+fn best_match(results: &JsonValue, search_title: &str, barcode: Option<&str>) -> Option<JsonValue> {
+    if let Some(barcode) = barcode.map(normalize_barcode)
+        && let Some(r) = results.members().find(|r| {
+            r["barcode"]
+                .members()
+                .filter_map(|b| b.as_str())
+                .any(|b| normalize_barcode(b) == barcode)
+        })
+    {
+        return Some(r.clone());
+    }
+    results
         .members()
         .filter_map(|r| {
             if r.has_key("title") {
                 let title = &r["title"].to_string();
-                let score = levenshtein(&search_title, title);
+                let score = levenshtein(search_title, title);
                 Some((r.clone(), score))
             } else {
                 None
             }
         })
         .min_by_key(|(_, s)| *s)
-        .map(|(r, _)| (r, limit))
-        .context("")
-}
-
-pub fn download_cover_file(album: &mut Album) -> Result<i32> {
-    let result = get_album_json(album);
-
-    if let Ok((result, limit)) = result {
-        if result.has_key("cover_image") {
-            let cover_url = result["cover_image"]
-                .as_str()
-                .context("cover_image should be a valid str!")?;
-            let ext = cover_url
-                .rsplit_once(".")
-                .context("Failed to determine cover file extension for {cover_url:?}")?;
-            let cover_path = album.dir_path.join(format!("cover.{}", ext.1));
-            println!("Downloading {cover_url} to {cover_path:?}");
-            let mut file = std::fs::File::create(cover_path)?;
-            reqwest::blocking::get(cover_url)?.copy_to(&mut file)?;
-        }
-        Ok(limit)
+        .map(|(r, _)| r)
+}
+
+fn get_album_json(
+    album: &Album,
+    proxy: Option<&str>,
+    filters: &DiscogsFilters,
+    rate_limiter: &mut RateLimiter,
+) -> Result<JsonValue> {
+    let keys = Keys::parse()?;
+    let runtime = tokio::runtime::Runtime::new().context("Failed to start the async runtime")?;
+    // reqwest uses HTTP_PROXY/HTTPS_PROXY from the environment unless a proxy is set explicitly
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        builder =
+            builder.proxy(reqwest::Proxy::all(proxy).context(format!("Invalid proxy {proxy:?}"))?);
+    }
+    let client = builder
+        .build()
+        .context("Failed to create the http client")?;
+    let search_title = format!("{} - {}", album.artist, album.title);
+
+    // barcodes and catalog numbers identify the release, so they are tried first
+    let ids = release_ids_from_tags(album);
+    let id_params = if let Some(barcode) = &ids.barcode {
+        Some(vec![("barcode", barcode.to_string())])
     } else {
-        bail!(
-            "Failed to find matching discogs result for {}",
+        ids.catalog_number.as_ref().map(|catno| {
+            vec![
+                ("catno", catno.to_string()),
+                ("artist", album.artist.to_string()),
+            ]
+        })
+    };
+    if let Some(id_params) = id_params {
+        let results = search_discogs(&runtime, &client, &keys, &id_params, rate_limiter)?;
+        if let Some(r) = best_match(&results, &search_title, ids.barcode.as_deref()) {
+            return Ok(r);
+        }
+        println!(
+            "No discogs result for {id_params:?}, searching by title for {}",
             album.overview()
         );
     }
+
+    let results = search_by_title(album, filters, |params| {
+        search_discogs(&runtime, &client, &keys, params, rate_limiter)
+    })?;
+    best_match(&results, &search_title, None).context("")
 }
 
-fn get_album_info_discogs(album: &Album) -> Result<(AlbumInfo, i32)> {
-    let result = get_album_json(album);
-    if let Ok((result, limit)) = result {
+/// searches for the artist and title of the album with `search`. The search is only repeated with
+/// the `filters` if there are at least [`NARROW_THRESHOLD`] results, the unfiltered results are
+/// kept if the filtered search finds nothing
+fn search_by_title(
+    album: &Album,
+    filters: &DiscogsFilters,
+    mut search: impl FnMut(&[(&str, String)]) -> Result<JsonValue>,
+) -> Result<JsonValue> {
+    let mut params = vec![
+        ("artist", album.artist.to_string()),
+        ("album", album.title.to_string()),
+        //("per_page", "30"),
+        ("page", "5".to_string()),
+    ];
+    let results = search(&params)?;
+    if results.len() >= NARROW_THRESHOLD && !filters.params().is_empty() {
+        params.extend(filters.params());
+        let narrowed = search(&params)?;
+        if !narrowed.is_empty() {
+            return Ok(narrowed);
+        }
+    }
+    Ok(results)
+}
+
+/// downloads the cover of the album's release. The release is looked up in `cache` first.
+/// Returns the file name to store the cover as (`cover.<ext>`) and the image data
+pub fn fetch_cover_bytes(album: &Album, cache: &mut MusicInfoCache) -> Result<(String, Vec<u8>)> {
+    if cache.offline {
+        bail!("Covers cannot be downloaded in offline mode");
+    }
+    let Some(cover_url) = cache.get_cover_url(album)? else {
+        bail!("The release of {} has no cover", album.overview());
+    };
+    println!("Downloading {cover_url}");
+    let res = http_client(cache.proxy.as_deref())?
+        .get(&cover_url)
+        .send()
+        .context(format!("Failed to download {cover_url}. {PROXY_HINT}"))?;
+    let res = res.error_for_status()?;
+    // cover art archive urls have no extension, the content type is used for them
+    let ext = cover_url
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext.to_string())
+        .or_else(|| {
+            res.headers()
+                .get(CONTENT_TYPE)
+                .and_then(|ct| ct.to_str().ok())
+                .and_then(|ct| ct.strip_prefix("image/"))
+                .map(|ext| ext.replace("jpeg", "jpg"))
+        })
+        .context(format!(
+            "Failed to determine cover file extension for {cover_url:?}"
+        ))?;
+    let bytes = res.bytes()?;
+    Ok((format!("cover.{ext}"), bytes.to_vec()))
+}
+
+/// downloads the cover of the album's discogs release and writes it to the album directory, once
+/// for each of the `cover_names` (file names without extension). `album` has to be an album of
+/// `location`
+pub fn download_cover_file(
+    location: &mut dyn Location,
+    album: &Album,
+    cache: &mut MusicInfoCache,
+    cover_names: &[String],
+) -> Result<()> {
+    let (name, bytes) = fetch_cover_bytes(album, cache)?;
+    let ext = Path::new(&name)
+        .extension()
+        .context(format!("{name:?} has no extension"))?
+        .to_string_lossy();
+    write_cover(location, album, cover_names, &ext, &bytes)
+}
+
+/// writes the cover image to `<name>.<ext>` in the album directory for each of the `cover_names`
+fn write_cover(
+    location: &mut dyn Location,
+    album: &Album,
+    cover_names: &[String],
+    ext: &str,
+    bytes: &[u8],
+) -> Result<()> {
+    let rel_dir = diff_paths(&album.dir_path, location.root()).context(format!(
+        "{:?} is not in {}",
+        album.dir_path,
+        location.to_string()
+    ))?;
+    cover_names.iter().try_for_each(|name| {
+        let rel_path = rel_dir
+            .join(format!("{name}.{ext}"))
+            .to_string_lossy()
+            .replace('\\', "/");
+        println!("Writing {rel_path} to {}", location.to_string());
+        location.write_file(&rel_path, bytes)
+    })
+}
+
+/// minimum time between two lrclib requests
+const LYRICS_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// looks up lyrics on lrclib.net. Requests are spaced by [`LYRICS_REQUEST_INTERVAL`]
+pub struct LyricsClient {
+    client: reqwest::blocking::Client,
+    last_request: Option<Instant>,
+}
+
+impl LyricsClient {
+    pub fn new(proxy: Option<&str>) -> Result<Self> {
+        Ok(LyricsClient {
+            client: http_client(proxy)?,
+            last_request: None,
+        })
+    }
+
+    /// the synced lyrics of the track if lrclib has them, otherwise the plain ones. Returns `None`
+    /// if lrclib does not know the track
+    pub fn fetch(
+        &mut self,
+        artist: &str,
+        title: &str,
+        album: Option<&str>,
+        duration: Option<Duration>,
+    ) -> Result<Option<String>> {
+        let mut params = vec![
+            ("artist_name", artist.to_string()),
+            ("track_name", title.to_string()),
+        ];
+        if let Some(album) = album {
+            params.push(("album_name", album.to_string()));
+        }
+        if let Some(duration) = duration {
+            params.push(("duration", duration.as_secs().to_string()));
+        }
+        let mut retried = false;
+        loop {
+            if let Some(last) = self.last_request {
+                std::thread::sleep(LYRICS_REQUEST_INTERVAL.saturating_sub(last.elapsed()));
+            }
+            self.last_request = Some(Instant::now());
+            let res = self
+                .client
+                .get("https://lrclib.net/api/get")
+                .query(&params)
+                .send()
+                .context(format!("Failed to reach lrclib. {PROXY_HINT}"))?;
+            match res.status() {
+                reqwest::StatusCode::NOT_FOUND => return Ok(None),
+                reqwest::StatusCode::TOO_MANY_REQUESTS if !retried => {
+                    println!("Waiting 60s to avoid rate limit...");
+                    std::thread::sleep(Duration::from_secs(60));
+                    retried = true;
+                }
+                _ => {
+                    let content = res.error_for_status()?.text()?;
+                    return Ok(lyrics_from_json(&json::parse(&content)?));
+                }
+            }
+        }
+    }
+}
+
+/// the synced lyrics of an lrclib response, or the plain ones if there are none
+fn lyrics_from_json(response: &JsonValue) -> Option<String> {
+    ["syncedLyrics", "plainLyrics"]
+        .iter()
+        .filter_map(|key| response[*key].as_str())
+        .find(|lyrics| !lyrics.trim().is_empty())
+        .map(|lyrics| lyrics.to_string())
+}
+
+/// MusicBrainz allows one request per second
+const MUSICBRAINZ_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the album info of a MusicBrainz release (requested with `inc=artist-credits`)
+fn album_info_from_musicbrainz(release: &JsonValue) -> Option<AlbumInfo> {
+    let title = release["title"].as_str()?.to_string();
+    let mut artist = release["artist-credit"]
+        .members()
+        .map(|credit| {
+            format!(
+                "{}{}",
+                credit["name"].as_str().unwrap_or_default(),
+                credit["joinphrase"].as_str().unwrap_or_default()
+            )
+        })
+        .collect::<String>();
+    if artist.is_empty() {
+        return None;
+    }
+    if is_various_artists(&artist) {
+        artist = VARIOUS_ARTISTS.to_string();
+    }
+    let year = release["date"]
+        .as_str()
+        .and_then(|date| date.get(..4))
+        .and_then(|year| year.parse().ok());
+    Some(AlbumInfo {
+        artist,
+        title,
+        year,
+    })
+}
+
+/// returns the album info and cover url of the MusicBrainz release `mbid`
+fn get_album_info_musicbrainz(
+    mbid: &str,
+    proxy: Option<&str>,
+) -> Result<(AlbumInfo, Option<String>)> {
+    let res = http_client(proxy)?
+        .get(format!("https://musicbrainz.org/ws/2/release/{mbid}"))
+        .query(&[("inc", "artist-credits"), ("fmt", "json")])
+        .send()
+        .context(format!("Failed to reach MusicBrainz. {PROXY_HINT}"));
+    std::thread::sleep(MUSICBRAINZ_REQUEST_INTERVAL);
+    let release = json::parse(&res?.error_for_status()?.text()?)?;
+    let info = album_info_from_musicbrainz(&release)
+        .context(format!("MusicBrainz release {mbid} has no title or artist"))?;
+    let cover_url = (release["cover-art-archive"]["front"].as_bool() == Some(true))
+        .then(|| format!("https://coverartarchive.org/release/{mbid}/front"));
+    Ok((info, cover_url))
+}
+
+/// returns the album info and cover url of the album. Albums with a MusicBrainz release id in
+/// their tags are looked up by it directly, all others are searched on discogs
+fn lookup_album_info(
+    album: &Album,
+    proxy: Option<&str>,
+    filters: &DiscogsFilters,
+    rate_limiter: &mut RateLimiter,
+) -> Result<(AlbumInfo, Option<String>)> {
+    if let Some(mbid) = release_ids_from_tags(album).musicbrainz_release_id {
+        match get_album_info_musicbrainz(&mbid, proxy) {
+            Ok(res) => return Ok(res),
+            Err(e) => println!(
+                "Failed to look up MusicBrainz release {mbid} of {}, searching discogs: {e:?}",
+                album.overview()
+            ),
+        }
+    }
+    get_album_info_discogs(album, proxy, filters, rate_limiter)
+}
+
+/// returns the album info and cover url of the best matching discogs release
+fn get_album_info_discogs(
+    album: &Album,
+    proxy: Option<&str>,
+    filters: &DiscogsFilters,
+    rate_limiter: &mut RateLimiter,
+) -> Result<(AlbumInfo, Option<String>)> {
+    let result = get_album_json(album, proxy, filters, rate_limiter);
+    // invalid keys affect every album, so they are not reported as a missing match
+    if let Err(e) = &result
+        && e.is::<DiscogsAuthError>()
+    {
+        bail!(DiscogsAuthError);
+    }
+    if let Ok(result) = result {
         let mut artist = None;
         let mut album_title = None;
         let title = result["title"].to_string();
@@ -195,6 +806,9 @@ fn get_album_info_discogs(album: &Album) -> Result<(AlbumInfo, i32)> {
             (2..100).for_each(|i| {
                 aartist = aartist.trim_end_matches(&format!(" ({i})"));
             });
+            if is_various_artists(aartist) {
+                aartist = VARIOUS_ARTISTS;
+            }
 
             artist = Some(aartist);
             album_title = Some(atitle);
@@ -213,13 +827,15 @@ fn get_album_info_discogs(album: &Album) -> Result<(AlbumInfo, i32)> {
             album.overview()
         );
 
+        let cover_url = result["cover_image"].as_str().map(|c| c.to_string());
+
         Ok((
             AlbumInfo {
                 artist: artist.context("no artist")?.to_string(),
                 title: album_title.context("no album_title")?.to_string(),
                 year,
             },
-            limit,
+            cover_url,
         ))
     } else {
         bail!(
@@ -228,3 +844,204 @@ fn get_album_info_discogs(album: &Album) -> Result<(AlbumInfo, i32)> {
         );
     }
 }
+
+#[test]
+fn test_best_match_prefers_barcode() {
+    let results = json::array![
+        json::object! { "title": "Poppy - Choke", "barcode": ["111"] },
+        json::object! { "title": "Poppy - Choke (Deluxe)", "barcode": ["0 602577 123456"] },
+    ];
+    let best =
+        |barcode| best_match(&results, "Poppy - Choke", barcode).unwrap()["title"].to_string();
+    assert_eq!(best(None), "Poppy - Choke");
+    assert_eq!(best(Some("0602577123456")), "Poppy - Choke (Deluxe)");
+    // unknown barcodes fall back to title matching
+    assert_eq!(best(Some("999")), "Poppy - Choke");
+}
+
+#[test]
+fn test_search_by_title_filters() {
+    let album = Album::test("Poppy", "Choke", "/music/Poppy/Choke", &["01 Choke.mp3"]);
+    let ep = json::object! { "title": "Poppy - Choke", "format": ["CD", "EP"] };
+    let many = json::JsonValue::Array(vec![ep.clone(); NARROW_THRESHOLD]);
+    // returns `unfiltered` or `filtered` depending on the params and records whether the search
+    // was filtered
+    let search = |filters: &DiscogsFilters, unfiltered: &JsonValue, filtered: &JsonValue| {
+        let mut searches = vec![];
+        let results = search_by_title(&album, filters, |params| {
+            let is_filtered = params.iter().any(|(k, _)| *k == "format");
+            searches.push(is_filtered);
+            Ok(if is_filtered { filtered } else { unfiltered }.clone())
+        })
+        .unwrap();
+        (results.len(), searches)
+    };
+    let filters = DiscogsFilters::default();
+    // few results are not narrowed, so the album format filter does not hide the EP
+    let few = json::array![ep.clone()];
+    assert_eq!(search(&filters, &few, &json::array![]), (1, vec![false]));
+    assert_eq!(
+        best_match(&few, "Poppy - Choke", None).unwrap()["title"].to_string(),
+        "Poppy - Choke"
+    );
+    // too many results are narrowed with the filters
+    let narrowed = json::array![ep.clone(), ep.clone()];
+    assert_eq!(search(&filters, &many, &narrowed), (2, vec![false, true]));
+    // the unfiltered results are kept if the filters remove everything
+    assert_eq!(
+        search(&filters, &many, &json::array![]),
+        (NARROW_THRESHOLD, vec![false, true])
+    );
+    // without filters there is nothing to narrow
+    let no_filters = DiscogsFilters {
+        format: None,
+        description: None,
+    };
+    assert_eq!(
+        search(&no_filters, &many, &narrowed),
+        (NARROW_THRESHOLD, vec![false])
+    );
+}
+
+#[test]
+fn test_write_cover_uses_configured_names() {
+    use crate::{album::FileNameRules, location::DirLocation, test_util::TestDir};
+    let root = TestDir::new("write_cover_test");
+    let dir = root.join("Poppy").join("Choke");
+    std::fs::create_dir_all(&dir).unwrap();
+    let album = Album::test("Poppy", "Choke", dir.clone(), &[]);
+    let mut location = DirLocation::new(root.clone(), vec![], false, true, FileNameRules::Fat32);
+    let names = ["folder".to_string(), "AlbumArt".to_string()];
+    write_cover(&mut location, &album, &names, "jpg", b"image").unwrap();
+    assert_eq!(std::fs::read(dir.join("folder.jpg")).unwrap(), b"image");
+    assert_eq!(std::fs::read(dir.join("AlbumArt.jpg")).unwrap(), b"image");
+    assert!(!dir.join("cover.jpg").exists());
+}
+
+#[test]
+fn test_offline_cache() {
+    let mut cache = MusicInfoCache::new().with_offline(true);
+    let album = Album::test("Poppy", "Choke", "", &[]);
+    assert!(cache.get_album_info(&album).is_err());
+    cache.cache.insert(
+        album.key(),
+        CacheEntry {
+            info: AlbumInfo {
+                artist: "Poppy".to_string(),
+                title: "Choke".to_string(),
+                year: Some(2019),
+            },
+            fetched_at: None,
+            cover_url: None,
+            cover_checked: false,
+        },
+    );
+    // the entry is stale because it has no timestamp, but it is not fetched again
+    assert_eq!(cache.get_album_info(&album).unwrap().year, Some(2019));
+    assert!(cache.get_cover_url(&album).unwrap().is_none());
+}
+
+#[test]
+fn test_checked_cover_not_fetched_again() {
+    let mut cache = MusicInfoCache::new();
+    cache.refresh = false;
+    let album = Album::test("Poppy", "Choke", "", &[]);
+    cache.cache.insert(
+        album.key(),
+        CacheEntry {
+            info: AlbumInfo {
+                artist: "Poppy".to_string(),
+                title: "Choke".to_string(),
+                year: Some(2019),
+            },
+            fetched_at: Some(now_secs()),
+            cover_url: None,
+            cover_checked: true,
+        },
+    );
+    // the release has no cover, so discogs is not accessed again
+    assert!(cache.get_cover_url(&album).unwrap().is_none());
+}
+
+#[test]
+fn test_album_info_from_musicbrainz() {
+    let release = json::object! {
+        "title": "Lanterns on the Lake",
+        "date": "2011-09-19",
+        "artist-credit": [
+            { "name": "Poppy", "joinphrase": " & " },
+            { "name": "Someone", "joinphrase": "" },
+        ],
+    };
+    let info = album_info_from_musicbrainz(&release).unwrap();
+    assert_eq!(info.artist, "Poppy & Someone");
+    assert_eq!(info.title, "Lanterns on the Lake");
+    assert_eq!(info.year, Some(2011));
+    let various = json::object! {
+        "title": "Hits",
+        "artist-credit": [{ "name": "Various Artists" }],
+    };
+    let info = album_info_from_musicbrainz(&various).unwrap();
+    assert_eq!(info.artist, VARIOUS_ARTISTS);
+    assert_eq!(info.year, None);
+    assert!(album_info_from_musicbrainz(&json::object! { "title": "Hits" }).is_none());
+}
+
+#[test]
+fn test_check_discogs_auth() {
+    assert!(check_discogs_auth(reqwest::StatusCode::OK).is_ok());
+    let err = check_discogs_auth(reqwest::StatusCode::UNAUTHORIZED).unwrap_err();
+    assert!(err.to_string().contains("check your key/secret"));
+}
+
+#[test]
+fn test_lyrics_from_json() {
+    let response = json::object! {
+        "plainLyrics": "I'm not a robot",
+        "syncedLyrics": "[00:12.34] I'm not a robot",
+    };
+    assert_eq!(
+        lyrics_from_json(&response).as_deref(),
+        Some("[00:12.34] I'm not a robot")
+    );
+    let response = json::object! { "plainLyrics": "I'm not a robot", "syncedLyrics": null };
+    assert_eq!(
+        lyrics_from_json(&response).as_deref(),
+        Some("I'm not a robot")
+    );
+    let response = json::object! { "instrumental": true, "plainLyrics": null, "syncedLyrics": "" };
+    assert_eq!(lyrics_from_json(&response), None);
+}
+
+#[test]
+fn test_rate_limiter() {
+    use std::sync::{Arc, Mutex};
+    let slept = Arc::new(Mutex::new(vec![]));
+    let sleeps = slept.clone();
+    let mut limiter = RateLimiter::new(Box::new(move |d| sleeps.lock().unwrap().push(d)));
+    let headers = |remaining: &str, retry_after: Option<&str>| {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Discogs-Ratelimit-Remaining", remaining.parse().unwrap());
+        if let Some(retry_after) = retry_after {
+            headers.insert(RETRY_AFTER, retry_after.parse().unwrap());
+        }
+        headers
+    };
+    limiter.wait();
+    limiter.update(&headers("5", None));
+    limiter.wait();
+    assert!(slept.lock().unwrap().is_empty());
+    // no requests left: wait until the first request leaves the window
+    limiter.update(&headers("1", None));
+    limiter.wait();
+    let waited = slept.lock().unwrap()[0];
+    assert!(waited > Duration::from_secs(59) && waited <= DISCOGS_RATE_LIMIT_WINDOW);
+    limiter.update(&headers("0", Some("7")));
+    limiter.wait();
+    assert_eq!(slept.lock().unwrap()[1], Duration::from_secs(7));
+    // rejected requests without Retry-After wait for a whole window
+    limiter.update(&headers("0", None));
+    limiter.throttled();
+    limiter.wait();
+    assert_eq!(slept.lock().unwrap()[2], DISCOGS_RATE_LIMIT_WINDOW);
+}
@@ -1,19 +1,443 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, Cursor},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::{Context, Result, bail};
-use audiotags::{AudioTag, FlacTag, Id3v2Tag, Tag};
+use audiotags::{AudioTag, FlacTag, Id3v2Tag, MimeType, Mp4Tag, Picture, Tag};
+use clap::ValueEnum;
+use lofty::{
+    config::WriteOptions,
+    file::{AudioFile, TaggedFileExt},
+    picture::PictureType,
+    probe::Probe,
+    tag::{ItemKey, TagExt, TagType},
+};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-use crate::{Album, FileType, music_info::AlbumInfo};
+use crate::{Album, FileType, album::AlbumOverride, music_info::AlbumInfo, vorbis_tag::VorbisTag};
 
-pub fn set_missing_tags(album: &Album, album_info: &AlbumInfo) -> Result<()> {
+/// The tag operations used by morg. This abstracts over audiotags and the lofty based
+/// [`VorbisTag`] for the containers audiotags does not support.
+pub trait TrackTag {
+    fn title(&self) -> Option<&str>;
+    fn set_title(&mut self, title: &str);
+    fn artist(&self) -> Option<&str>;
+    fn set_artist(&mut self, artist: &str);
+    fn album_title(&self) -> Option<&str>;
+    fn set_album_title(&mut self, title: &str);
+    fn album_artist(&self) -> Option<&str>;
+    fn set_album_artist(&mut self, artist: &str);
+    fn year(&self) -> Option<i32>;
+    fn set_year(&mut self, year: i32);
+    fn track_number(&self) -> Option<u16>;
+    fn set_track_number(&mut self, track_number: u16);
+    fn disc_number(&self) -> Option<u16>;
+    fn set_disc_number(&mut self, disc_number: u16);
+    fn has_album_cover(&self) -> bool;
+    fn set_album_cover(&mut self, data: &[u8], mime_type: MimeType);
+    fn set_artist_sort(&mut self, artist_sort: &str);
+    fn set_album_artist_sort(&mut self, album_artist_sort: &str);
+    fn write_to_path(&mut self, path: &str) -> Result<()>;
+}
+
+/// the tag values compared by the CleanUpTags preview
+#[derive(Debug, Default, PartialEq)]
+pub struct TagValues {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track: Option<u16>,
+    pub disc: Option<u16>,
+    pub year: Option<i32>,
+}
+
+impl TagValues {
+    fn of(tag: &(impl TrackTag + ?Sized)) -> Self {
+        TagValues {
+            title: tag.title().map(|v| v.to_string()),
+            artist: tag.artist().map(|v| v.to_string()),
+            album: tag.album_title().map(|v| v.to_string()),
+            album_artist: tag.album_artist().map(|v| v.to_string()),
+            track: tag.track_number(),
+            disc: tag.disc_number(),
+            year: tag.year(),
+        }
+    }
+
+    /// one `field: old -> new` line per value that differs in `new`
+    pub fn diff(&self, new: &TagValues) -> Vec<String> {
+        fn show<T: std::fmt::Debug>(v: &Option<T>) -> String {
+            v.as_ref()
+                .map(|v| format!("{v:?}"))
+                .unwrap_or_else(|| "<none>".to_string())
+        }
+        let mut lines = vec![];
+        let mut compare = |field: &str, old: String, new: String| {
+            if old != new {
+                lines.push(format!("{field}: {old} -> {new}"));
+            }
+        };
+        compare("title", show(&self.title), show(&new.title));
+        compare("artist", show(&self.artist), show(&new.artist));
+        compare("album", show(&self.album), show(&new.album));
+        compare(
+            "albumartist",
+            show(&self.album_artist),
+            show(&new.album_artist),
+        );
+        compare("track", show(&self.track), show(&new.track));
+        compare("disc", show(&self.disc), show(&new.disc));
+        compare("year", show(&self.year), show(&new.year));
+        lines
+    }
+}
+
+/// prints the tag values of the track at `path` that changed
+fn print_tag_diff(path: &Path, before: &TagValues, after: &TagValues) {
+    let lines = before.diff(after);
+    if lines.is_empty() {
+        return;
+    }
+    println!("{path:?}:");
+    lines.iter().for_each(|l| println!("  {l}"));
+}
+
+/// [`TrackTag`] implementation for all tags supported by audiotags
+struct AudiotagsTag {
+    tag: Box<dyn AudioTag + Send + Sync>,
+    /// audiotags does not support sort tags, they are written with lofty instead
+    artist_sort: Option<String>,
+    album_artist_sort: Option<String>,
+}
+
+impl AudiotagsTag {
+    fn new(tag: Box<dyn AudioTag + Send + Sync>) -> Self {
+        AudiotagsTag {
+            tag,
+            artist_sort: None,
+            album_artist_sort: None,
+        }
+    }
+}
+
+impl TrackTag for AudiotagsTag {
+    fn title(&self) -> Option<&str> {
+        self.tag.title()
+    }
+    fn set_title(&mut self, title: &str) {
+        self.tag.set_title(title);
+    }
+    fn artist(&self) -> Option<&str> {
+        self.tag.artist()
+    }
+    fn set_artist(&mut self, artist: &str) {
+        self.tag.set_artist(artist);
+    }
+    fn album_title(&self) -> Option<&str> {
+        self.tag.album_title()
+    }
+    fn set_album_title(&mut self, title: &str) {
+        self.tag.set_album_title(title);
+    }
+    fn album_artist(&self) -> Option<&str> {
+        self.tag.album_artist()
+    }
+    fn set_album_artist(&mut self, artist: &str) {
+        self.tag.set_album_artist(artist);
+    }
+    fn year(&self) -> Option<i32> {
+        self.tag.year()
+    }
+    fn set_year(&mut self, year: i32) {
+        self.tag.set_year(year);
+    }
+    fn track_number(&self) -> Option<u16> {
+        self.tag.track_number()
+    }
+    fn set_track_number(&mut self, track_number: u16) {
+        self.tag.set_track_number(track_number);
+    }
+    fn disc_number(&self) -> Option<u16> {
+        self.tag.disc_number()
+    }
+    fn set_disc_number(&mut self, disc_number: u16) {
+        self.tag.set_disc_number(disc_number);
+    }
+    fn has_album_cover(&self) -> bool {
+        self.tag.album_cover().is_some()
+    }
+    fn set_album_cover(&mut self, data: &[u8], mime_type: MimeType) {
+        self.tag.set_album_cover(Picture { data, mime_type });
+    }
+    fn set_artist_sort(&mut self, artist_sort: &str) {
+        self.artist_sort = Some(artist_sort.to_string());
+    }
+    fn set_album_artist_sort(&mut self, album_artist_sort: &str) {
+        self.album_artist_sort = Some(album_artist_sort.to_string());
+    }
+    fn write_to_path(&mut self, path: &str) -> Result<()> {
+        self.tag.write_to_path(path)?;
+        if self.artist_sort.is_some() || self.album_artist_sort.is_some() {
+            write_sort_tags(
+                path,
+                self.artist_sort.as_deref(),
+                self.album_artist_sort.as_deref(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// writes the sort tags (e.g. TSOP/TSO2 for ID3v2, ARTISTSORT/ALBUMARTISTSORT for FLAC) with lofty
+fn write_sort_tags(
+    path: &str,
+    artist_sort: Option<&str>,
+    album_artist_sort: Option<&str>,
+) -> Result<()> {
+    let mut tagged_file =
+        lofty::read_from_path(path).context(format!("Failed to read tags from {path:?}"))?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(lofty::tag::Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .context(format!("Failed to create tag for {path:?}"))?;
+    if let Some(artist_sort) = artist_sort {
+        tag.insert_text(ItemKey::TrackArtistSortOrder, artist_sort.to_string());
+    }
+    if let Some(album_artist_sort) = album_artist_sort {
+        tag.insert_text(ItemKey::AlbumArtistSortOrder, album_artist_sort.to_string());
+    }
+    tag.save_to_path(path, WriteOptions::default())
+        .context(format!("Failed to write sort tags to {path:?}"))
+}
+
+/// the ID3v2 version of MP3 tags written during conversions and tagging
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, ValueEnum)]
+pub enum Id3Version {
+    /// ID3v2.3: supported by almost all players and by Windows Explorer, but text is stored as
+    /// UTF-16 and multiple values are joined with `/`
+    #[default]
+    V23,
+    /// ID3v2.4: UTF-8 text and real multi-valued frames, but some older players and Windows
+    /// Explorer do not read it
+    V24,
+}
+
+impl Id3Version {
+    /// the value of ffmpeg's `-id3v2_version` option
+    pub fn ffmpeg_arg(&self) -> &'static str {
+        match self {
+            Id3Version::V23 => "3",
+            Id3Version::V24 => "4",
+        }
+    }
+}
+
+/// rewrites the ID3v2 tag of the MP3 file at `path` in `version`. audiotags always writes
+/// ID3v2.4, so only ID3v2.3 requires a rewrite. Other files are left as they are
+fn apply_id3_version(path: &Path, version: Id3Version) -> Result<()> {
+    let is_mp3 = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mp3"));
+    if !is_mp3 || version == Id3Version::V24 {
+        return Ok(());
+    }
+    let tagged_file =
+        lofty::read_from_path(path).context(format!("Failed to read tags from {path:?}"))?;
+    let Some(tag) = tagged_file.primary_tag() else {
+        return Ok(());
+    };
+    tag.save_to_path(path, WriteOptions::default().use_id3v23(true))
+        .context(format!("Failed to write an ID3v2.3 tag to {path:?}"))
+}
+
+/// copies the tags of the track at `src` to the MP3 file at `dst`, e.g. after encoding it with a
+/// tool that does not carry over the metadata. The audio frames of `dst` are not touched
+pub fn copy_tags_to_mp3(src: &Path, dst: &Path, id3_version: Id3Version) -> Result<()> {
+    let tagged_file =
+        lofty::read_from_path(src).context(format!("Failed to read tags from {src:?}"))?;
+    let Some(tag) = tagged_file.primary_tag() else {
+        return Ok(());
+    };
+    let mut tag = tag.clone();
+    tag.re_map(TagType::Id3v2);
+    tag.save_to_path(
+        dst,
+        WriteOptions::default().use_id3v23(id3_version == Id3Version::V23),
+    )
+    .context(format!("Failed to write tags to {dst:?}"))
+}
+
+/// the width and height of the cover embedded in the track at `path`. The front cover is
+/// preferred over other pictures
+pub fn embedded_cover_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let tagged_file = lofty::read_from_path(path).ok()?;
+    let pictures = tagged_file.primary_tag()?.pictures();
+    let picture = pictures
+        .iter()
+        .find(|p| p.pic_type() == PictureType::CoverFront)
+        .or(pictures.first())?;
+    image_dimensions(picture.data())
+}
+
+/// the width and height of the png or jpeg image `data`
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// the lyrics (USLT for ID3v2, LYRICS for Vorbis comments) of the track at `path`
+pub fn read_lyrics(path: &Path) -> Option<String> {
+    let tagged_file = lofty::read_from_path(path).ok()?;
+    tagged_file
+        .primary_tag()?
+        .get_string(&ItemKey::Lyrics)
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.to_string())
+}
+
+/// writes `lyrics` to the tag of the track at `path` with lofty
+pub fn write_lyrics(path: &Path, lyrics: &str) -> Result<()> {
+    let mut tagged_file =
+        lofty::read_from_path(path).context(format!("Failed to read tags from {path:?}"))?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(lofty::tag::Tag::new(tag_type));
+    }
+    let tag = tagged_file
+        .primary_tag_mut()
+        .context(format!("Failed to create tag for {path:?}"))?;
+    tag.insert_text(ItemKey::Lyrics, lyrics.to_string());
+    tag.save_to_path(path, WriteOptions::default())
+        .context(format!("Failed to write lyrics to {path:?}"))
+}
+
+/// Moves a leading article to the end, e.g. "The Beatles" -> "Beatles, The". Returns None if
+/// `name` does not start with an article.
+pub fn sort_name(name: &str) -> Option<String> {
+    ["The ", "A ", "An "].iter().find_map(|article| {
+        let rest = name.strip_prefix(article)?.trim();
+        if rest.is_empty() {
+            None
+        } else {
+            Some(format!("{rest}, {}", article.trim()))
+        }
+    })
+}
+
+/// sets the sort tags for the artist and album artist of `tag` if they start with an article
+fn set_sort_tags(tag: &mut Box<dyn TrackTag + Send + Sync>) {
+    if let Some(artist_sort) = tag.artist().and_then(sort_name) {
+        tag.set_artist_sort(&artist_sort);
+    }
+    if let Some(album_artist_sort) = tag.album_artist().and_then(sort_name) {
+        tag.set_album_artist_sort(&album_artist_sort);
+    }
+}
+
+/// determines the mime type of a cover file from its extension
+fn cover_mime_type(cover_file: &Path) -> Option<MimeType> {
+    let ext = cover_file.extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "png" => Some(MimeType::Png),
+        "jpg" | "jpeg" => Some(MimeType::Jpeg),
+        _ => None,
+    }
+}
+
+fn is_vorbis_file(track_path: &Path) -> bool {
+    track_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ogg") || ext.eq_ignore_ascii_case("opus"))
+}
+
+/// reads the primary cover file of the album (see [`Album::primary_cover`]) if it has a supported
+/// image type
+fn read_cover(album: &Album, preferred_names: &[String]) -> Option<(Vec<u8>, MimeType)> {
+    album.primary_cover(preferred_names).and_then(|cf| {
+        let mime_type = cover_mime_type(cf)?;
+        let data = std::fs::read(cf).ok()?;
+        Some((data, mime_type))
+    })
+}
+
+/// embeds the primary cover file of the album (preferring `preferred_names`) into its tracks.
+/// Tracks that already have an embedded cover are skipped unless `overwrite` is set. Returns the
+/// number of updated tracks
+pub fn embed_cover(album: &Album, preferred_names: &[String], overwrite: bool) -> Result<usize> {
+    let Some((data, mime_type)) = read_cover(album, preferred_names) else {
+        bail!("No usable cover file for {}", album.overview());
+    };
+    embed_cover_data(album, &data, mime_type, overwrite)
+}
+
+/// embeds the image `name` (only used for its extension) with the data `data` into the tracks of
+/// the album, replacing their covers. Returns the number of updated tracks
+pub fn embed_cover_bytes(album: &Album, name: &str, data: &[u8]) -> Result<usize> {
+    let Some(mime_type) = cover_mime_type(Path::new(name)) else {
+        bail!("{name:?} is not a png or jpeg image");
+    };
+    embed_cover_data(album, data, mime_type, true)
+}
+
+fn embed_cover_data(
+    album: &Album,
+    data: &[u8],
+    mime_type: MimeType,
+    overwrite: bool,
+) -> Result<usize> {
+    let mut embedded = 0;
+    album.tracks.iter().try_for_each(|t| {
+        let track_path = album.dir_path.join(t);
+        let mut tag = get_tag(&track_path, album)?;
+        if overwrite || !tag.has_album_cover() {
+            tag.set_album_cover(data, mime_type);
+            tag.write_to_path(
+                track_path
+                    .to_str()
+                    .context("track path should be a valid string")?,
+            )?;
+            embedded += 1;
+        }
+        Ok::<(), anyhow::Error>(())
+    })?;
+    Ok(embedded)
+}
+
+/// sets the tags that are missing in the tracks of the album. Covers are not embedded, that is left
+/// to [`embed_cover`]. With `preview`, the changes are only printed
+pub fn set_missing_tags(
+    album: &Album,
+    album_info: &AlbumInfo,
+    sort_tags: bool,
+    id3_version: Id3Version,
+    title_rules: &[Regex],
+    preview: bool,
+) -> Result<()> {
     album.tracks.iter().try_for_each(|t| {
         let track_path = album.dir_path.join(t);
         let mut tag = get_tag(&track_path, album)?;
+        let before = TagValues::of(tag.as_ref());
 
         if tag.album_title().is_none() {
             tag.set_album_title(&album_info.title);
         }
+        if tag.year().is_none()
+            && let Some(year) = album_info.year
+        {
+            tag.set_year(year);
+        }
         if let Some(aa) = tag.album_artist()
             && aa.is_empty()
         {
@@ -21,12 +445,20 @@ pub fn set_missing_tags(album: &Album, album_info: &AlbumInfo) -> Result<()> {
         } else if tag.album_artist().is_none() {
             tag.set_album_artist(&album_info.artist);
         }
+        let track_info = parse_track_info(t, album, album_info, title_rules);
+        let (track_artist, title) = split_track_title(album, &track_info.title);
         if tag.artist().is_none() {
-            tag.set_artist(&album_info.artist)
+            if album.is_compilation() {
+                // never force "Various Artists" onto the tracks of a compilation
+                if let Some(track_artist) = track_artist {
+                    tag.set_artist(track_artist);
+                }
+            } else {
+                tag.set_artist(&album_info.artist)
+            }
         }
-        let track_info = parse_track_info(t, album, album_info);
         if tag.title().is_none() {
-            tag.set_title(&track_info.title);
+            tag.set_title(title);
         }
         if let Some(dn) = track_info.disc_number
             && tag.disc_number().is_none()
@@ -38,23 +470,123 @@ pub fn set_missing_tags(album: &Album, album_info: &AlbumInfo) -> Result<()> {
         {
             tag.set_track_number(tn);
         }
+        if preview {
+            print_tag_diff(&track_path, &before, &TagValues::of(tag.as_ref()));
+            return Ok(());
+        }
+        if sort_tags {
+            set_sort_tags(&mut tag);
+        }
         tag.write_to_path(
             track_path
                 .to_str()
                 .context("track path should be a valid string")?,
         )?;
-
-        Ok(())
+        apply_id3_version(&track_path, id3_version)
     })
 }
 
+/// writes the title, track and disc number parsed from the track file names (see
+/// [`parse_track_info`]) and `album_info` to the tags of the album's tracks, replacing existing
+/// values. Tracks whose file name has no track number or title are not modified, they are returned
+pub fn repair_tags(
+    album: &Album,
+    album_info: &AlbumInfo,
+    id3_version: Id3Version,
+    title_rules: &[Regex],
+) -> Result<Vec<String>> {
+    let mut unparsed = vec![];
+    album.tracks.iter().try_for_each(|t| {
+        let track_info = parse_track_info(t, album, album_info, title_rules);
+        let Some(track_number) = track_info
+            .track_number
+            .filter(|_| !track_info.title.is_empty())
+        else {
+            unparsed.push(t.clone());
+            return Ok(());
+        };
+        let track_path = album.dir_path.join(t);
+        let mut tag = get_tag(&track_path, album)?;
+        let (track_artist, title) = split_track_title(album, &track_info.title);
+        tag.set_title(title);
+        tag.set_track_number(track_number);
+        if let Some(dn) = track_info.disc_number {
+            tag.set_disc_number(dn);
+        }
+        tag.set_album_title(&album_info.title);
+        tag.set_album_artist(&album_info.artist);
+        if let Some(track_artist) = track_artist {
+            tag.set_artist(track_artist);
+        } else if !album.is_compilation() {
+            tag.set_artist(&album_info.artist);
+        }
+        if let Some(year) = album_info.year {
+            tag.set_year(year);
+        }
+        tag.write_to_path(
+            track_path
+                .to_str()
+                .context("track path should be a valid string")?,
+        )?;
+        apply_id3_version(&track_path, id3_version)
+    })?;
+    Ok(unparsed)
+}
+
+/// Tracks of compilations are usually named `<track artist> - <title>`. Returns the track artist
+/// (for compilations only) and the remaining title.
+fn split_track_title<'a>(album: &Album, title: &'a str) -> (Option<&'a str>, &'a str) {
+    if album.is_compilation()
+        && let Some((track_artist, title)) = title.split_once(" - ")
+    {
+        (Some(track_artist.trim()), title.trim())
+    } else {
+        (None, title)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct TrackInfo {
     pub title: String,
     pub disc_number: Option<u16>,
     pub track_number: Option<u16>,
 }
 
-pub fn parse_track_info(rel_track_path: &str, album: &Album, album_info: &AlbumInfo) -> TrackInfo {
+/// removes the artist and album prefixes from a title parsed from a track file name and then
+/// everything matched by one of the `title_rules`, e.g. "(Official Audio)". The prefix-free title
+/// is kept if the rules would remove all of it
+pub fn clean_title(
+    title: &str,
+    album: &Album,
+    album_info: &AlbumInfo,
+    title_rules: &[Regex],
+) -> String {
+    let title = title
+        .trim_start_matches("- ")
+        .replace(&format!("{} - ", album_info.artist), "")
+        .replace(&format!("{} - ", album.artist), "")
+        .replace(&format!("{} - ", album_info.title), "")
+        .replace(&format!("{} - ", album.parsed_artist), "")
+        .replace(&format!("{} - ", album.parsed_title), "");
+    let title = title.trim();
+    let cleaned = title_rules.iter().fold(title.to_string(), |t, re| {
+        re.replace_all(&t, "").to_string()
+    });
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        title.to_string()
+    } else {
+        cleaned.to_string()
+    }
+}
+
+/// parses the disc and track number and the title (see [`clean_title`]) from the track file name
+pub fn parse_track_info(
+    rel_track_path: &str,
+    album: &Album,
+    album_info: &AlbumInfo,
+    title_rules: &[Regex],
+) -> TrackInfo {
     let mut res = TrackInfo {
         title: "".to_string(),
         disc_number: None,
@@ -75,41 +607,96 @@ pub fn parse_track_info(rel_track_path: &str, album: &Album, album_info: &AlbumI
             }
         }
         if let Some((name, _)) = parts.1.rsplit_once('.') {
-            let title = name.trim_start_matches("- ");
-            let title = title
-                .replace(&format!("{} - ", album_info.artist), "")
-                .replace(&format!("{} - ", album.artist), "")
-                .replace(&format!("{} - ", album_info.title), "")
-                .replace(&format!("{} - ", album.parsed_artist), "")
-                .replace(&format!("{} - ", album.parsed_title), "");
-            let title = title.trim();
-            res.title = title.to_string();
+            res.title = clean_title(name, album, album_info, title_rules);
         }
     }
     res
 }
 
-fn get_tag(track_path: &PathBuf, album: &Album) -> Result<Box<dyn AudioTag + Send + Sync>> {
+/// strips the (optionally zero padded) track number and the following separator from the start
+/// of `title`. Returns `None` if the title does not start with the track number
+pub fn strip_track_number(title: &str, track_number: u16) -> Option<String> {
+    let tn = track_number.to_string();
+    let rest = title
+        .strip_prefix(&format!("0{tn}"))
+        .or_else(|| title.strip_prefix(&tn))?;
+    // e.g. "1979" for track 1
+    if rest.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let rest = rest.trim_start_matches(|c: char| c.is_whitespace() || ".-_)".contains(c));
+    (!rest.is_empty()).then(|| rest.to_string())
+}
+
+/// removes the track number from the start of the title tags of the album's tracks
+pub fn fix_track_number_titles(album: &Album, dry_run: bool) -> Result<()> {
+    // only used to parse the track number from the file name
+    let album_info = AlbumInfo {
+        artist: album.artist.clone(),
+        title: album.title.clone(),
+        year: None,
+    };
+    album.tracks.iter().try_for_each(|t| {
+        let track_path = album.dir_path.join(t);
+        let mut tag = get_track_tags(&track_path)?;
+        let Some(title) = tag.title() else {
+            return Ok(());
+        };
+        let track_number = tag
+            .track_number()
+            .or_else(|| parse_track_info(t, album, &album_info, &[]).track_number);
+        if let Some(tn) = track_number
+            && let Some(fixed) = strip_track_number(title, tn)
+        {
+            println!("{track_path:?}: {title:?} -> {fixed:?}");
+            if !dry_run {
+                tag.set_title(&fixed);
+                tag.write_to_path(
+                    track_path
+                        .to_str()
+                        .context("track path should be a valid string")?,
+                )?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn get_tag(track_path: &PathBuf, album: &Album) -> Result<Box<dyn TrackTag + Send + Sync>> {
+    if is_vorbis_file(track_path) {
+        return Ok(Box::new(VorbisTag::read_from_path(track_path)?));
+    }
     let tag = match Tag::new().read_from_path(track_path) {
         Ok(tag) => tag,
         Err(_) => {
             let tag: Box<dyn AudioTag + Send + Sync> = match album.file_type() {
                 Some(FileType::MP3) => Box::new(Id3v2Tag::new()),
                 Some(FileType::Flac) => Box::new(FlacTag::new()),
+                Some(FileType::M4A) => Box::new(Mp4Tag::new()),
                 Some(ft) => bail!("Could not create tag object for file type {ft}."),
                 None => bail!("Failed to create tag: file type of album {album:?} is not known."),
             };
             tag
         }
     };
-    Ok(tag)
+    Ok(Box::new(AudiotagsTag::new(tag)))
 }
 
-pub fn set_tags(album: &Album, album_info: &AlbumInfo) -> Result<()> {
+/// sets the tags of the album's tracks from `album_info` and their file names. With `preview`, the
+/// changes are only printed
+pub fn set_tags(
+    album: &Album,
+    album_info: &AlbumInfo,
+    sort_tags: bool,
+    id3_version: Id3Version,
+    title_rules: &[Regex],
+    preview: bool,
+) -> Result<()> {
     let mut first = true;
     album.tracks.iter().try_for_each(|t| {
         let track_path = album.dir_path.join(t);
         let mut tag = get_tag(&track_path, album)?;
+        let before = TagValues::of(tag.as_ref());
 
         tag.set_album_title(&album_info.title);
         if first {
@@ -143,31 +730,269 @@ pub fn set_tags(album: &Album, album_info: &AlbumInfo) -> Result<()> {
                 }
             }
             if let Some((name, _)) = parts.1.rsplit_once('.') {
-                let title = name.trim_start_matches("- ");
-                let title = title
-                    .replace(&format!("{} - ", album_info.artist), "")
-                    .replace(&format!("{} - ", album.artist), "")
-                    .replace(&format!("{} - ", album_info.title), "");
-                let title = title.trim();
+                let title = clean_title(name, album, album_info, title_rules);
+                let (track_artist, title) = split_track_title(album, &title);
+                if let Some(track_artist) = track_artist
+                    && tag.artist().is_none()
+                {
+                    tag.set_artist(track_artist);
+                }
                 tag.set_title(title);
             }
         }
+        if preview {
+            print_tag_diff(&track_path, &before, &TagValues::of(tag.as_ref()));
+            return Ok(());
+        }
+        if sort_tags {
+            set_sort_tags(&mut tag);
+        }
         tag.write_to_path(
             track_path
                 .to_str()
                 .context("track path should be a valid string")?,
         )?;
-
-        Ok(())
+        apply_id3_version(&track_path, id3_version)
     })
 }
 
-pub fn get_track_tags(
-    abs_track_path: &PathBuf,
-) -> Result<Box<dyn audiotags::AudioTag + 'static + Send + Sync>> {
-    Tag::new()
+pub fn get_track_tags(abs_track_path: &PathBuf) -> Result<Box<dyn TrackTag + Send + Sync>> {
+    if is_vorbis_file(abs_track_path) {
+        return Ok(Box::new(VorbisTag::read_from_path(abs_track_path)?));
+    }
+    let tag = Tag::new()
         .read_from_path(abs_track_path)
-        .context(format!("Failed to read tags from {abs_track_path:?}"))
+        .context(format!("Failed to read tags from {abs_track_path:?}"))?;
+    Ok(Box::new(AudiotagsTag::new(tag)))
+}
+
+/// identifiers in the track tags that allow an exact release lookup
+#[derive(Debug, Default)]
+pub struct ReleaseIds {
+    pub barcode: Option<String>,
+    pub catalog_number: Option<String>,
+    /// `MUSICBRAINZ_ALBUMID`, e.g. written by Picard
+    pub musicbrainz_release_id: Option<String>,
+}
+
+/// reads the barcode (UPC/EAN), catalog number and MusicBrainz release id from the tags of the
+/// album's tracks. The first track that has one is used
+pub fn release_ids_from_tags(album: &Album) -> ReleaseIds {
+    let mut ids = ReleaseIds::default();
+    for t in &album.tracks {
+        let Ok(tagged_file) = lofty::read_from_path(album.dir_path.join(t)) else {
+            continue;
+        };
+        let Some(tag) = tagged_file.primary_tag() else {
+            continue;
+        };
+        let get = |key: &ItemKey| {
+            tag.get_string(key)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        ids.barcode = ids.barcode.or_else(|| get(&ItemKey::Barcode));
+        ids.catalog_number = ids.catalog_number.or_else(|| get(&ItemKey::CatalogNumber));
+        ids.musicbrainz_release_id = ids
+            .musicbrainz_release_id
+            .or_else(|| get(&ItemKey::MusicBrainzReleaseId));
+        if ids.barcode.is_some()
+            && ids.catalog_number.is_some()
+            && ids.musicbrainz_release_id.is_some()
+        {
+            break;
+        }
+    }
+    ids
+}
+
+/// name of the sidecar file in the album directory, see [`write_sidecar`]
+pub const SIDECAR_FILE: &str = "morg.toml";
+
+/// album metadata that is stored in a sidecar file instead of the track tags
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Sidecar {
+    /// metadata pinned by the user, it is kept when the sidecar is written
+    #[serde(default, rename = "override", skip_serializing_if = "Option::is_none")]
+    pub album_override: Option<AlbumOverride>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<AlbumInfo>,
+    /// track info by track file (relative to the album directory)
+    #[serde(default)]
+    pub tracks: BTreeMap<String, TrackInfo>,
+}
+
+/// writes `album_info` and the track info parsed from the track file names to the sidecar file of
+/// the album. The tracks themselves are not modified
+pub fn write_sidecar(album: &Album, album_info: &AlbumInfo, title_rules: &[Regex]) -> Result<()> {
+    let album_override = read_sidecar(album)?.and_then(|s| s.album_override);
+    let sidecar = Sidecar {
+        album_override,
+        album: Some(album_info.clone()),
+        tracks: album
+            .tracks
+            .iter()
+            .map(|t| {
+                (
+                    t.clone(),
+                    parse_track_info(t, album, album_info, title_rules),
+                )
+            })
+            .collect(),
+    };
+    let path = album.dir_path.join(SIDECAR_FILE);
+    let text = toml::to_string(&sidecar).context("Failed to serialize sidecar")?;
+    std::fs::write(&path, text).context(format!("Failed to write {path:?}"))
+}
+
+/// reads the sidecar file of the album. Returns `None` if there is none
+pub fn read_sidecar(album: &Album) -> Result<Option<Sidecar>> {
+    let path = album.dir_path.join(SIDECAR_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = std::fs::read_to_string(&path)
+        .context(format!("Could not read {path:?}"))?
+        .replace("\r\n", "\n");
+    let sidecar = toml::from_str(&text).context(format!("Could not parse sidecar {path:?}"))?;
+    Ok(Some(sidecar))
+}
+
+/// the most common year in the existing tags of the album's tracks. The year in the sidecar file
+/// is preferred if there is one
+pub fn year_from_tags(album: &Album) -> Option<i32> {
+    if let Ok(Some(Sidecar {
+        album: Some(album), ..
+    })) = read_sidecar(album)
+    {
+        return album.year;
+    }
+    let mut counts: HashMap<i32, usize> = HashMap::new();
+    album
+        .tracks
+        .iter()
+        .filter_map(|t| get_track_tags(&album.dir_path.join(t)).ok())
+        .filter_map(|tag| tag.year())
+        .for_each(|year| *counts.entry(year).or_default() += 1);
+    // ties are resolved in favor of the earlier year
+    counts
+        .into_iter()
+        .max_by_key(|(year, count)| (*count, std::cmp::Reverse(*year)))
+        .map(|(year, _)| year)
+}
+
+/// audio properties that should be the same for all tracks of an album
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AudioProperties {
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+}
+
+pub fn get_audio_properties(abs_track_path: &Path) -> Result<AudioProperties> {
+    let tagged_file = lofty::read_from_path(abs_track_path).context(format!(
+        "Failed to read audio properties from {abs_track_path:?}"
+    ))?;
+    let properties = tagged_file.properties();
+    Ok(AudioProperties {
+        sample_rate: properties.sample_rate(),
+        bit_depth: properties.bit_depth(),
+    })
+}
+
+/// tag fields that can be kept by [`strip_tags`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TagField {
+    Title,
+    Artist,
+    Album,
+    Albumartist,
+    Track,
+    Disc,
+    Year,
+    Genre,
+}
+
+impl TagField {
+    fn item_keys(&self) -> Vec<ItemKey> {
+        use ItemKey::*;
+        match self {
+            TagField::Title => vec![TrackTitle],
+            TagField::Artist => vec![TrackArtist],
+            TagField::Album => vec![AlbumTitle],
+            TagField::Albumartist => vec![AlbumArtist],
+            TagField::Track => vec![TrackNumber, TrackTotal],
+            TagField::Disc => vec![DiscNumber, DiscTotal],
+            TagField::Year => vec![Year, RecordingDate],
+            TagField::Genre => vec![Genre],
+        }
+    }
+}
+
+/// Prints the tag items of the track and removes all of them except the `keep` fields. Embedded
+/// pictures are removed as well if `strip_cover` is set. Nothing is written if `dry_run` is set.
+/// Returns the number of bytes saved
+pub fn strip_tags(
+    abs_track_path: &Path,
+    keep: &[TagField],
+    strip_cover: bool,
+    dry_run: bool,
+) -> Result<u64> {
+    let keep: Vec<ItemKey> = keep.iter().flat_map(|f| f.item_keys()).collect();
+    let tagged_file = lofty::read_from_path(abs_track_path)
+        .context(format!("Failed to read tags from {abs_track_path:?}"))?;
+    let size_before = std::fs::metadata(abs_track_path)?.len();
+    for tag in tagged_file.tags() {
+        let items: Vec<String> = tag.items().map(|i| format!("{:?}", i.key())).collect();
+        println!(
+            "{abs_track_path:?} [{:?}]: {} ({} pictures)",
+            tag.tag_type(),
+            items.join(", "),
+            tag.pictures().len()
+        );
+        if dry_run {
+            continue;
+        }
+        let mut tag = tag.clone();
+        tag.retain(|i| keep.contains(i.key()));
+        if strip_cover {
+            let picture_types: Vec<PictureType> =
+                tag.pictures().iter().map(|p| p.pic_type()).collect();
+            picture_types
+                .into_iter()
+                .for_each(|t| tag.remove_picture_type(t));
+        }
+        tag.save_to_path(abs_track_path, WriteOptions::default())
+            .context(format!("Failed to write tags to {abs_track_path:?}"))?;
+    }
+    let size_after = std::fs::metadata(abs_track_path)?.len();
+    Ok(size_before.saturating_sub(size_after))
+}
+
+/// detects the file type of the track from its header, ignoring the extension. Returns `None` if
+/// the container is not recognized or not supported by morg
+pub fn detect_file_type(abs_track_path: &Path) -> Result<Option<FileType>> {
+    let file = File::open(abs_track_path).context(format!("Failed to open {abs_track_path:?}"))?;
+    let probe = Probe::new(BufReader::new(file))
+        .guess_file_type()
+        .context(format!("Failed to read the header of {abs_track_path:?}"))?;
+    use lofty::file::FileType as Container;
+    Ok(probe.file_type().and_then(|ft| match ft {
+        Container::Mp4 => Some(FileType::M4A),
+        Container::Mpeg => Some(FileType::MP3),
+        Container::Wav => Some(FileType::Wav),
+        Container::Flac => Some(FileType::Flac),
+        Container::Vorbis => Some(FileType::Ogg),
+        Container::Opus => Some(FileType::Opus),
+        Container::Aiff => Some(FileType::Aiff),
+        _ => None,
+    }))
+}
+
+/// the playing time of the track, if it can be determined
+pub fn get_duration(abs_track_path: &Path) -> Option<Duration> {
+    let tagged_file = lofty::read_from_path(abs_track_path).ok()?;
+    let duration = tagged_file.properties().duration();
+    (!duration.is_zero()).then_some(duration)
 }
 
 #[test]
@@ -182,3 +1007,159 @@ fn test_parse_track_info() {
     assert_eq!(album.parsed_artist, "Poppy".to_string());
     assert_eq!(album.parsed_title, "Negative Spaces".to_string());
 }
+
+#[test]
+fn test_split_track_title() {
+    use crate::album::path_to_details;
+    use std::str::FromStr;
+    let album = path_to_details(
+        PathBuf::from_str("/music/Various Artists/Hits/03 Poppy - Concrete.mp3").unwrap(),
+        PathBuf::from_str("/music").unwrap(),
+    )
+    .unwrap();
+    assert!(album.is_compilation());
+    assert_eq!(
+        split_track_title(&album, "Poppy - Concrete"),
+        (Some("Poppy"), "Concrete")
+    );
+    let album = path_to_details(
+        PathBuf::from_str("/music/Poppy/Negative Spaces/04 yesterday.mp3").unwrap(),
+        PathBuf::from_str("/music").unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        split_track_title(&album, "yesterday - live"),
+        (None, "yesterday - live")
+    );
+}
+
+#[test]
+fn test_strip_track_number() {
+    assert_eq!(
+        strip_track_number("01 - Choke", 1),
+        Some("Choke".to_string())
+    );
+    assert_eq!(strip_track_number("1. Choke", 1), Some("Choke".to_string()));
+    assert_eq!(strip_track_number("12 Burn", 12), Some("Burn".to_string()));
+    assert_eq!(strip_track_number("1979", 1), None);
+    assert_eq!(strip_track_number("Choke", 1), None);
+    assert_eq!(strip_track_number("01", 1), None);
+}
+
+#[test]
+fn test_sidecar() {
+    use crate::test_util::TestDir;
+    let dir = TestDir::new("sidecar_test");
+    let album = Album::test(
+        "Poppy",
+        "Choke",
+        dir.clone(),
+        &["05 The Holy Mountain.flac"],
+    );
+    let album_info = AlbumInfo {
+        artist: "Poppy".to_string(),
+        title: "Choke".to_string(),
+        year: Some(2019),
+    };
+    assert!(read_sidecar(&album).unwrap().is_none());
+    std::fs::write(dir.join(SIDECAR_FILE), "[override]\nyear = 2020\n").unwrap();
+    write_sidecar(&album, &album_info, &[]).unwrap();
+    let sidecar = read_sidecar(&album).unwrap().unwrap();
+    // the pinned metadata is kept
+    assert_eq!(sidecar.album_override.unwrap().year, Some(2020));
+    assert_eq!(sidecar.album.unwrap().year, Some(2019));
+    assert_eq!(
+        sidecar.tracks["05 The Holy Mountain.flac"],
+        TrackInfo {
+            title: "The Holy Mountain".to_string(),
+            disc_number: None,
+            track_number: Some(5),
+        }
+    );
+    assert_eq!(year_from_tags(&album), Some(2019));
+}
+
+#[test]
+fn test_sort_name() {
+    assert_eq!(sort_name("The Beatles"), Some("Beatles, The".to_string()));
+    assert_eq!(
+        sort_name("A Perfect Circle"),
+        Some("Perfect Circle, A".to_string())
+    );
+    assert_eq!(sort_name("An Horse"), Some("Horse, An".to_string()));
+    assert_eq!(sort_name("Theory of a Deadman"), None);
+    assert_eq!(sort_name("Poppy"), None);
+    assert_eq!(sort_name("The "), None);
+}
+
+#[test]
+fn test_tag_values_diff() {
+    let before = TagValues {
+        title: Some("01 Choke".to_string()),
+        album: Some("Choke".to_string()),
+        track: Some(1),
+        ..Default::default()
+    };
+    let after = TagValues {
+        title: Some("Choke".to_string()),
+        album: Some("Choke".to_string()),
+        track: Some(1),
+        year: Some(2019),
+        ..Default::default()
+    };
+    assert_eq!(
+        before.diff(&after),
+        vec![
+            "title: \"01 Choke\" -> \"Choke\"".to_string(),
+            "year: <none> -> 2019".to_string()
+        ]
+    );
+    assert!(after.diff(&after).is_empty());
+}
+
+#[test]
+fn test_tag_untagged_m4a() {
+    use crate::test_util::TestDir;
+    // a minimal m4a file without any metadata, it only consists of the file type and an empty
+    // movie
+    let m4a = {
+        let atom = |name: &[u8], content: &[u8]| {
+            let mut atom = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+            atom.extend_from_slice(name);
+            atom.extend_from_slice(content);
+            atom
+        };
+        let ftyp = [b"M4A ".as_slice(), &[0; 4], b"M4A mp42isom"].concat();
+        // version and flags, creation and modification time, time scale, duration, rate, volume,
+        // reserved, unity matrix, pre-defined and next track id
+        let mut mvhd = vec![0; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[20..24].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        mvhd[24..26].copy_from_slice(&0x0100u16.to_be_bytes());
+        [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000]
+            .iter()
+            .enumerate()
+            .for_each(|(i, v)| mvhd[36 + 4 * i..40 + 4 * i].copy_from_slice(&v.to_be_bytes()));
+        mvhd[96..100].copy_from_slice(&1u32.to_be_bytes());
+        [
+            atom(b"ftyp", &ftyp),
+            atom(b"moov", &atom(b"mvhd", &mvhd)),
+            atom(b"mdat", &[]),
+        ]
+        .concat()
+    };
+    let dir = TestDir::new("m4a_test");
+    let track_path = dir.join("05 The Holy Mountain.m4a");
+    std::fs::write(&track_path, m4a).unwrap();
+    let album = Album::test("Poppy", "Choke", dir.clone(), &["05 The Holy Mountain.m4a"]);
+    let mut tag = get_tag(&track_path, &album).unwrap();
+    tag.set_title("The Holy Mountain");
+    tag.set_artist("Poppy");
+    tag.set_track_number(5);
+    tag.write_to_path(track_path.to_str().unwrap()).unwrap();
+
+    let tag = get_track_tags(&track_path).unwrap();
+    assert_eq!(tag.title(), Some("The Holy Mountain"));
+    assert_eq!(tag.artist(), Some("Poppy"));
+    assert_eq!(tag.track_number(), Some(5));
+}
@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// resolves `.` and `..` without touching the file system
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    path.components().for_each(|c| match c {
+        Component::CurDir => {}
+        Component::ParentDir => {
+            res.pop();
+        }
+        c => res.push(c),
+    });
+    res
+}
+
+/// the tracks of the playlist. Relative entries are resolved against the playlist's directory
+pub fn playlist_tracks(playlist: &Path) -> Result<Vec<PathBuf>> {
+    let content =
+        std::fs::read_to_string(playlist).context(format!("Failed to read {playlist:?}"))?;
+    let playlist_dir = playlist.parent().unwrap_or(Path::new(""));
+    Ok(content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+        .map(|entry| normalize_path(&playlist_dir.join(entry.replace('\\', "/"))))
+        .collect())
+}
+
+/// Rewrites the entries of the playlist so that they point to the synced tracks. `track_map` maps
+/// absolute source track paths to their paths relative to the destination root. Entries that are
+/// not in `track_map` are dropped together with their `#EXTINF` line. Returns the new content and
+/// the dropped entries
+pub fn rewrite_playlist(
+    playlist: &Path,
+    track_map: &HashMap<PathBuf, String>,
+) -> Result<(String, Vec<String>)> {
+    let content =
+        std::fs::read_to_string(playlist).context(format!("Failed to read {playlist:?}"))?;
+    let playlist_dir = playlist.parent().unwrap_or(Path::new(""));
+    let mut lines: Vec<String> = vec![];
+    let mut missing = vec![];
+    content.lines().for_each(|line| {
+        let entry = line.trim();
+        if entry.is_empty() || entry.starts_with('#') {
+            lines.push(line.to_string());
+            return;
+        }
+        let track = normalize_path(&playlist_dir.join(entry.replace('\\', "/")));
+        if let Some(dst) = track_map.get(&track) {
+            lines.push(dst.clone());
+        } else {
+            if lines.last().is_some_and(|l| l.starts_with("#EXTINF")) {
+                lines.pop();
+            }
+            missing.push(entry.to_string());
+        }
+    });
+    Ok((lines.join("\n") + "\n", missing))
+}
+
+#[test]
+fn test_rewrite_playlist() {
+    use crate::test_util::TestDir;
+    let dir = TestDir::new("playlist_test");
+    std::fs::create_dir_all(dir.join("Playlists")).unwrap();
+    let playlist = dir.join("Playlists").join("mix.m3u");
+    std::fs::write(
+        &playlist,
+        "#EXTM3U\n#EXTINF:180,Poppy - Choke\n../Poppy/Choke/01 Choke.flac\n#EXTINF:200,Missing\n../Missing/01 Song.flac\n",
+    )
+    .unwrap();
+    let track_map = HashMap::from([(
+        dir.join("Poppy").join("Choke").join("01 Choke.flac"),
+        "Poppy/Choke [mp3]/01 Choke.mp3".to_string(),
+    )]);
+    let (content, missing) = rewrite_playlist(&playlist, &track_map).unwrap();
+    assert_eq!(
+        content,
+        "#EXTM3U\n#EXTINF:180,Poppy - Choke\nPoppy/Choke [mp3]/01 Choke.mp3\n"
+    );
+    assert_eq!(missing, vec!["../Missing/01 Song.flac".to_string()]);
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{Album, album::sanitize_file_name, conversion_cache::source_fingerprint};
+
+/// checkpoints of syncs that started longer ago are discarded, the destination may have changed
+/// in the meantime
+const CHECKPOINT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// the albums that were synced to a destination by an unfinished sync, so that an interrupted
+/// sync can skip them. Stored in the state dir until the sync finishes without errors or becomes
+/// older than [`CHECKPOINT_MAX_AGE`]
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SyncCheckpoint {
+    /// when the unfinished sync started, in seconds since the unix epoch
+    #[serde(default)]
+    started: u64,
+    /// source fingerprint (see [`source_fingerprint`]) by album key
+    done: HashMap<String, String>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// whether the file at `path` contains the checkpoint, so that new albums can be appended
+    #[serde(skip)]
+    written: bool,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn checkpoint_file(state_dir: &Path, destination: &str) -> PathBuf {
+    state_dir
+        .join("sync_progress")
+        .join(format!("{}.toml", sanitize_file_name(destination)))
+}
+
+fn checkpoint_key(album: &Album) -> String {
+    let ft = album
+        .file_type()
+        .map(|ft| ft.to_string())
+        .unwrap_or_default();
+    format!("{}###{ft}", album.normalized_key())
+}
+
+impl SyncCheckpoint {
+    /// loads the checkpoint of the previous, unfinished sync to `destination` from `state_dir`.
+    /// Checkpoints older than [`CHECKPOINT_MAX_AGE`] are discarded and a new one is started
+    pub fn load(state_dir: &Path, destination: &str) -> Result<Self> {
+        let path = checkpoint_file(state_dir, destination);
+        let checkpoint = if path.exists() {
+            let text = std::fs::read_to_string(&path)
+                .context(format!("Could not read {path:?}"))?
+                .replace("\r\n", "\n");
+            toml::from_str(&text).context(format!("Could not parse {path:?}"))?
+        } else {
+            SyncCheckpoint::default()
+        };
+        Ok(checkpoint.or_new(SystemTime::now()).with_path(path))
+    }
+
+    /// the checkpoint itself if it was started within [`CHECKPOINT_MAX_AGE`] before `now`,
+    /// otherwise a new one started at `now`
+    fn or_new(self, now: SystemTime) -> Self {
+        let now = unix_secs(now);
+        if !self.done.is_empty() && now.saturating_sub(self.started) <= CHECKPOINT_MAX_AGE.as_secs()
+        {
+            return self;
+        }
+        if !self.done.is_empty() {
+            println!("Discarding the progress of a sync that started more than a week ago");
+        }
+        SyncCheckpoint {
+            started: now,
+            ..Default::default()
+        }
+    }
+
+    fn with_path(mut self, path: PathBuf) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    /// whether `src_album` was synced by the unfinished sync and did not change since
+    pub fn is_done(&self, src_album: &Album) -> bool {
+        self.done
+            .get(&checkpoint_key(src_album))
+            .is_some_and(|fp| *fp == source_fingerprint(src_album))
+    }
+
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    /// records that `src_album` was synced and stores the checkpoint. New albums are appended to
+    /// the stored checkpoint, so that syncs of large libraries do not rewrite it for every album
+    pub fn mark_done(&mut self, src_album: &Album) -> Result<()> {
+        let key = checkpoint_key(src_album);
+        let fingerprint = source_fingerprint(src_album);
+        let previous = self.done.insert(key.clone(), fingerprint.clone());
+        if previous.as_ref() == Some(&fingerprint) {
+            return Ok(());
+        }
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.written && previous.is_none() {
+            // `done` is the last table of the file, so the entry can be appended to it
+            let entry = toml::to_string(&HashMap::from([(key, fingerprint)]))?;
+            return std::fs::OpenOptions::new()
+                .append(true)
+                .open(path)
+                .and_then(|mut f| f.write_all(entry.as_bytes()))
+                .context(format!("Failed to write {path:?}"));
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context(format!("Failed to create {dir:?}"))?;
+        }
+        let text = toml::to_string(&self)?;
+        std::fs::write(path, text).context(format!("Failed to write {path:?}"))?;
+        self.written = true;
+        Ok(())
+    }
+
+    /// removes the stored checkpoint once the sync finished
+    pub fn finish(self) -> Result<()> {
+        if let Some(path) = &self.path
+            && path.exists()
+        {
+            std::fs::remove_file(path).context(format!("Failed to delete {path:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sync_checkpoint() {
+    use crate::test_util::TestDir;
+    let dir = TestDir::new("test_sync_checkpoint");
+    let album_dir = dir.join("Poppy/Choke");
+    std::fs::create_dir_all(&album_dir).unwrap();
+    std::fs::write(album_dir.join("01 Choke.flac"), "flac").unwrap();
+    let album = Album::test("Poppy", "Choke", album_dir.clone(), &["01 Choke.flac"]);
+    let mut checkpoint = SyncCheckpoint::default()
+        .or_new(SystemTime::now())
+        .with_path(dir.join("progress.toml"));
+    assert!(!checkpoint.is_done(&album));
+    checkpoint.mark_done(&album).unwrap();
+    assert!(checkpoint.is_done(&album));
+    // later albums are appended to the stored checkpoint
+    let other_dir = dir.join("Poppy/I Disagree");
+    std::fs::create_dir_all(&other_dir).unwrap();
+    std::fs::write(other_dir.join("01 Concrete.flac"), "flac").unwrap();
+    let other = Album::test("Poppy", "I Disagree", other_dir, &["01 Concrete.flac"]);
+    checkpoint.mark_done(&other).unwrap();
+    checkpoint.mark_done(&other).unwrap();
+    let text = std::fs::read_to_string(dir.join("progress.toml")).unwrap();
+    let stored: SyncCheckpoint = toml::from_str(&text).unwrap();
+    assert!(stored.is_done(&album));
+    assert!(stored.is_done(&other));
+    // stale checkpoints are discarded
+    let started = UNIX_EPOCH + Duration::from_secs(stored.started);
+    let stored = stored.or_new(started + CHECKPOINT_MAX_AGE);
+    assert!(stored.is_done(&album));
+    let stored = stored.or_new(started + CHECKPOINT_MAX_AGE + Duration::from_secs(1));
+    assert!(!stored.is_done(&album));
+    // a changed source has to be synced again
+    std::fs::write(album_dir.join("01 Choke.flac"), "remastered flac").unwrap();
+    assert!(!checkpoint.is_done(&album));
+    checkpoint.finish().unwrap();
+    assert!(!dir.join("progress.toml").exists());
+}
@@ -0,0 +1,45 @@
+use std::{ops::Deref, path::PathBuf};
+
+use crate::Album;
+
+impl Album {
+    /// an album with `tracks` in `dir` whose parsed artist and title are `artist` and `title`
+    pub fn test(artist: &str, title: &str, dir: impl Into<PathBuf>, tracks: &[&str]) -> Album {
+        Album::new(
+            title.to_string(),
+            artist.to_string(),
+            tracks.iter().map(|t| t.to_string()).collect(),
+            dir.into(),
+            vec![],
+            title.to_string(),
+            artist.to_string(),
+        )
+    }
+}
+
+/// the directory `morg_<name>_<process id>` in the temp dir. It is created empty and removed when
+/// it is dropped, so that failing tests do not leave it behind
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("morg_{name}_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
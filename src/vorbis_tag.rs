@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use audiotags::MimeType;
+use lofty::{
+    config::WriteOptions,
+    file::TaggedFileExt,
+    picture::{Picture, PictureType},
+    tag::{Accessor, ItemKey, Tag, TagExt},
+};
+
+use crate::music_tags::TrackTag;
+
+/// Vorbis comments of an OGG/Opus file. audiotags does not support these containers, so lofty is
+/// used instead.
+pub struct VorbisTag {
+    tag: Tag,
+}
+
+impl VorbisTag {
+    pub fn read_from_path(path: &Path) -> Result<Self> {
+        let tagged_file =
+            lofty::read_from_path(path).context(format!("Failed to read tags from {path:?}"))?;
+        let tag = tagged_file
+            .primary_tag()
+            .cloned()
+            .unwrap_or_else(|| Tag::new(tagged_file.primary_tag_type()));
+        Ok(VorbisTag { tag })
+    }
+}
+
+fn to_lofty_mime_type(mime_type: MimeType) -> lofty::picture::MimeType {
+    use lofty::picture::MimeType as LMT;
+    match mime_type {
+        MimeType::Png => LMT::Png,
+        MimeType::Jpeg => LMT::Jpeg,
+        MimeType::Tiff => LMT::Tiff,
+        MimeType::Bmp => LMT::Bmp,
+        MimeType::Gif => LMT::Gif,
+    }
+}
+
+impl TrackTag for VorbisTag {
+    fn title(&self) -> Option<&str> {
+        self.tag.get_string(&ItemKey::TrackTitle)
+    }
+    fn set_title(&mut self, title: &str) {
+        self.tag.set_title(title.to_string());
+    }
+    fn artist(&self) -> Option<&str> {
+        self.tag.get_string(&ItemKey::TrackArtist)
+    }
+    fn set_artist(&mut self, artist: &str) {
+        self.tag.set_artist(artist.to_string());
+    }
+    fn album_title(&self) -> Option<&str> {
+        self.tag.get_string(&ItemKey::AlbumTitle)
+    }
+    fn set_album_title(&mut self, title: &str) {
+        self.tag.set_album(title.to_string());
+    }
+    fn album_artist(&self) -> Option<&str> {
+        self.tag.get_string(&ItemKey::AlbumArtist)
+    }
+    fn set_album_artist(&mut self, artist: &str) {
+        self.tag
+            .insert_text(ItemKey::AlbumArtist, artist.to_string());
+    }
+    fn year(&self) -> Option<i32> {
+        self.tag.year().and_then(|y| i32::try_from(y).ok())
+    }
+    fn set_year(&mut self, year: i32) {
+        if let Ok(year) = u32::try_from(year) {
+            self.tag.set_year(year);
+        }
+    }
+    fn track_number(&self) -> Option<u16> {
+        self.tag.track().and_then(|n| u16::try_from(n).ok())
+    }
+    fn set_track_number(&mut self, track_number: u16) {
+        self.tag.set_track(track_number.into());
+    }
+    fn disc_number(&self) -> Option<u16> {
+        self.tag.disk().and_then(|n| u16::try_from(n).ok())
+    }
+    fn set_disc_number(&mut self, disc_number: u16) {
+        self.tag.set_disk(disc_number.into());
+    }
+    fn has_album_cover(&self) -> bool {
+        self.tag
+            .pictures()
+            .iter()
+            .any(|p| p.pic_type() == PictureType::CoverFront)
+    }
+    fn set_album_cover(&mut self, data: &[u8], mime_type: MimeType) {
+        self.tag.remove_picture_type(PictureType::CoverFront);
+        self.tag.push_picture(Picture::new_unchecked(
+            PictureType::CoverFront,
+            Some(to_lofty_mime_type(mime_type)),
+            None,
+            data.to_vec(),
+        ));
+    }
+    fn set_artist_sort(&mut self, artist_sort: &str) {
+        self.tag
+            .insert_text(ItemKey::TrackArtistSortOrder, artist_sort.to_string());
+    }
+    fn set_album_artist_sort(&mut self, album_artist_sort: &str) {
+        self.tag
+            .insert_text(ItemKey::AlbumArtistSortOrder, album_artist_sort.to_string());
+    }
+    fn write_to_path(&mut self, path: &str) -> Result<()> {
+        self.tag
+            .save_to_path(path, WriteOptions::default())
+            .context(format!("Failed to write tags to {path:?}"))
+    }
+}